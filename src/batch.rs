@@ -0,0 +1,158 @@
+use crate::command::{Command, ParseError, COMMANDS};
+use qi::board::{Board, Move, MoveResult};
+use qi::record::{parse_iccs, Outcome};
+use std::fmt;
+use std::io::{self, BufRead};
+
+/// Exit codes of `qi batch`, one for each way a script can end. Any other
+/// failure, such as an unreadable file, exits with 1.
+pub const EXIT_UNFINISHED: i32 = 0;
+pub const EXIT_RED_WIN: i32 = 2;
+pub const EXIT_GREEN_WIN: i32 = 3;
+pub const EXIT_ILLEGAL: i32 = 4;
+
+/// Where a move script left the game.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    pub fen: String,
+    pub outcome: Outcome,
+    /// The 1-based index of the first move that could not be played, and why.
+    pub illegal: Option<(usize, String)>,
+}
+
+impl Report {
+    pub fn exit_code(&self) -> i32 {
+        match (&self.illegal, self.outcome) {
+            (Some(..), _) => EXIT_ILLEGAL,
+            (None, Outcome::RedWin) => EXIT_RED_WIN,
+            (None, Outcome::GreenWin) => EXIT_GREEN_WIN,
+            (None, _) => EXIT_UNFINISHED,
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "fen {}", self.fen)?;
+        writeln!(f, "result {}", self.outcome.as_str())?;
+        if let Some((index, reason)) = &self.illegal {
+            writeln!(f, "illegal {} {}", index, reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// The moves on one line of a script: a REPL `move` command, or any number
+/// of ICCS moves such as `h2e2 h9g7`. Returns the moves up to the first
+/// token that is not one, and why that token was rejected.
+fn moves(line: &str) -> (Vec<Move>, Option<String>) {
+    let first = line.split_whitespace().next().unwrap_or("");
+    if COMMANDS.iter().any(|&(name, _, _)| name == first) {
+        return match Command::parse(line) {
+            Ok(Command::Move { from, to }) => (vec![Move::new(from, to)], None),
+            Ok(..) => (
+                vec![],
+                Some("only moves are allowed in batch mode".to_string()),
+            ),
+            Err(e) => (vec![], Some(e.to_string())),
+        };
+    }
+    let mut moves = vec![];
+    for token in line.split_whitespace() {
+        match parse_iccs(token) {
+            Some(mv) => moves.push(mv),
+            None => {
+                return (
+                    moves,
+                    Some(ParseError::InvalidMove(token.to_string()).to_string()),
+                )
+            }
+        }
+    }
+    (moves, None)
+}
+
+/// Plays every move in `input` from the initial position, stopping at the
+/// first one that is malformed or illegal. Blank lines and lines starting
+/// with `#` are skipped.
+pub fn replay<R: BufRead>(input: R) -> io::Result<Report> {
+    let mut board = Board::new();
+    let mut outcome = Outcome::Unfinished;
+    let mut illegal = None;
+    let mut index = 0;
+    'lines: for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (moves, malformed) = moves(line);
+        for mv in moves {
+            index += 1;
+            match board.move_piece(board.turn, mv.from(), mv.to()) {
+                Ok(MoveResult::Valid) => {}
+                Ok(MoveResult::RedWin) => outcome = Outcome::RedWin,
+                Ok(MoveResult::GreenWin) => outcome = Outcome::GreenWin,
+                Err(e) => {
+                    illegal = Some((index, e.to_string()));
+                    break 'lines;
+                }
+            }
+        }
+        if let Some(reason) = malformed {
+            illegal = Some((index + 1, reason));
+            break;
+        }
+    }
+    Ok(Report {
+        fen: board.fen(),
+        outcome,
+        illegal,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_scripts() {
+        let report = replay("# central cannon\nmove 8 8 8 5\n\nh9g7 h0g2\n".as_bytes()).unwrap();
+        assert_eq!(report.illegal, None);
+        assert_eq!(report.outcome, Outcome::Unfinished);
+        assert_eq!(report.exit_code(), EXIT_UNFINISHED);
+        assert_eq!(
+            report.fen,
+            "rnbakab1r/9/1c4nc1/p1p1p1p1p/9/9/P1P1P1P1P/1C2C1N2/9/RNBAKAB1R b - - 0 2"
+        );
+
+        let report = replay("h2e2\nh2e2\nh9g7\n".as_bytes()).unwrap();
+        assert_eq!(
+            report.illegal,
+            Some((2, "There's no piece on (8, 8)".to_string()))
+        );
+        assert_eq!(report.exit_code(), EXIT_ILLEGAL);
+
+        let report = replay("h2e2\nmove 0 1 1 1\n".as_bytes()).unwrap();
+        assert_eq!(
+            report.illegal,
+            Some((2, "Row 0 is not between 1 and 10".to_string()))
+        );
+
+        // the moves before a malformed token are played
+        let report = replay("h2e2 h9g7 zz99 h0g2\n".as_bytes()).unwrap();
+        assert_eq!(
+            report.illegal,
+            Some((3, "Not a move: zz99 (expected e.g. h2e2)".to_string()))
+        );
+        assert_eq!(
+            report.fen,
+            "rnbakab1r/9/1c4nc1/p1p1p1p1p/9/9/P1P1P1P1P/1C2C4/9/RNBAKABNR w - - 0 2"
+        );
+        let report = replay("h2e2\ngo\n".as_bytes()).unwrap();
+        assert_eq!(
+            report.illegal,
+            Some((2, "only moves are allowed in batch mode".to_string()))
+        );
+    }
+}
@@ -0,0 +1,513 @@
+//! The board as 90-square bitboards: bit `row * 9 + col` of a `u128`, one
+//! per side and piece type. `Board` keeps a `Bitboards` in lockstep with its
+//! array and generates moves and detects checks with it.
+
+use crate::board::{Move, Piece, PieceType, Player};
+use crate::moves::MoveList;
+
+pub type Bitboard = u128;
+
+/// The piece types in the order `Bitboards` indexes them.
+pub const KINDS: [PieceType; 7] = [
+    PieceType::Shuai,
+    PieceType::Shi,
+    PieceType::Xiang,
+    PieceType::Ma,
+    PieceType::Ju,
+    PieceType::Pao,
+    PieceType::Bing,
+];
+
+const NONE: u8 = 0xff;
+
+pub const fn bit(square: usize) -> Bitboard {
+    1 << square
+}
+
+/// The squares set in `bb`, lowest first.
+pub fn squares(mut bb: Bitboard) -> impl Iterator<Item = usize> {
+    std::iter::from_fn(move || {
+        if bb == 0 {
+            return None;
+        }
+        let square = bb.trailing_zeros() as usize;
+        bb &= bb - 1;
+        Some(square)
+    })
+}
+
+fn side(player: Player) -> Option<usize> {
+    match player {
+        Player::Red => Some(0),
+        Player::Green => Some(1),
+        _ => None,
+    }
+}
+
+pub(crate) fn kind(piecetype: PieceType) -> Option<usize> {
+    KINDS.iter().position(|&k| k == piecetype)
+}
+
+/// The square `(dr, dc)` away from `square`, or `NONE` off the board.
+const fn offset(square: usize, dr: isize, dc: isize) -> u8 {
+    let row = (square / 9) as isize + dr;
+    let col = (square % 9) as isize + dc;
+    if row >= 0 && row < 10 && col >= 0 && col < 9 {
+        (row * 9 + col) as u8
+    } else {
+        NONE
+    }
+}
+
+/// Whether `square` is in the palace of side 0 (Red) or 1 (Green).
+const fn in_palace(square: u8, side: usize) -> bool {
+    if square == NONE {
+        return false;
+    }
+    let (row, col) = ((square / 9) as usize, (square % 9) as usize);
+    let rows = if side == 0 { row >= 7 } else { row <= 2 };
+    rows && col >= 3 && col <= 5
+}
+
+const ORTHOGONAL: [[isize; 2]; 4] = [[-1, 0], [1, 0], [0, -1], [0, 1]];
+const DIAGONAL: [[isize; 2]; 4] = [[-1, -1], [-1, 1], [1, -1], [1, 1]];
+
+/// One step inside the palace, orthogonally for the Shuai or diagonally, to
+/// or from the centre, for the Shi.
+const fn palace_steps(steps: [[isize; 2]; 4]) -> [[Bitboard; 90]; 2] {
+    let mut table = [[0; 90]; 2];
+    let mut s = 0;
+    while s < 2 {
+        let mut square = 0;
+        while square < 90 {
+            let mut i = 0;
+            while i < 4 {
+                let to = offset(square, steps[i][0], steps[i][1]);
+                let center = if s == 0 { 8 * 9 + 4 } else { 9 + 4 };
+                let diagonal = steps[i][0] != 0 && steps[i][1] != 0;
+                let via_center = square == center || to as usize == center;
+                if in_palace(square as u8, s) && in_palace(to, s) && (!diagonal || via_center) {
+                    table[s][square] |= bit(to as usize);
+                }
+                i += 1;
+            }
+            square += 1;
+        }
+        s += 1;
+    }
+    table
+}
+
+/// Xiang destinations with the eye that must be empty, staying on the same
+/// side of the river.
+const fn xiang_steps() -> [[(u8, u8); 4]; 90] {
+    let mut table = [[(NONE, NONE); 4]; 90];
+    let mut square = 0;
+    while square < 90 {
+        let mut i = 0;
+        while i < 4 {
+            let [dr, dc] = DIAGONAL[i];
+            let to = offset(square, 2 * dr, 2 * dc);
+            if to != NONE && (to as usize / 9 <= 4) == (square / 9 <= 4) {
+                table[square][i] = (to, offset(square, dr, dc));
+            }
+            i += 1;
+        }
+        square += 1;
+    }
+    table
+}
+
+/// Ma destinations with the leg that must be empty: the square orthogonally
+/// next to the Ma, or with `reverse` the square diagonally next to the
+/// destination, which is the leg of a Ma on the destination attacking
+/// `square`.
+const fn ma_steps(reverse: bool) -> [[(u8, u8); 8]; 90] {
+    const JUMPS: [[isize; 2]; 8] = [
+        [-2, -1],
+        [-2, 1],
+        [2, -1],
+        [2, 1],
+        [-1, -2],
+        [1, -2],
+        [-1, 2],
+        [1, 2],
+    ];
+    let mut table = [[(NONE, NONE); 8]; 90];
+    let mut square = 0;
+    while square < 90 {
+        let mut i = 0;
+        while i < 8 {
+            let [dr, dc] = JUMPS[i];
+            let to = offset(square, dr, dc);
+            if to != NONE {
+                let leg = if reverse {
+                    offset(square, dr.signum(), dc.signum())
+                } else if dr.abs() == 2 {
+                    offset(square, dr.signum(), 0)
+                } else {
+                    offset(square, 0, dc.signum())
+                };
+                table[square][i] = (to, leg);
+            }
+            i += 1;
+        }
+        square += 1;
+    }
+    table
+}
+
+/// Bing destinations: forward, and sideways once across the river.
+const fn bing_steps() -> [[Bitboard; 90]; 2] {
+    let mut table = [[0; 90]; 2];
+    let mut square = 0;
+    while square < 90 {
+        let row = square / 9;
+        let mut s = 0;
+        while s < 2 {
+            let (forward, crossed) = if s == 0 {
+                (-1, row <= 4)
+            } else {
+                (1, row >= 5)
+            };
+            let ahead = offset(square, forward, 0);
+            if ahead != NONE {
+                table[s][square] |= bit(ahead as usize);
+            }
+            if crossed {
+                let (left, right) = (offset(square, 0, -1), offset(square, 0, 1));
+                if left != NONE {
+                    table[s][square] |= bit(left as usize);
+                }
+                if right != NONE {
+                    table[s][square] |= bit(right as usize);
+                }
+            }
+            s += 1;
+        }
+        square += 1;
+    }
+    table
+}
+
+/// For each side and square, the squares from which that side's Bing
+/// attacks it.
+const fn bing_attackers() -> [[Bitboard; 90]; 2] {
+    let mut table = [[0; 90]; 2];
+    let mut s = 0;
+    while s < 2 {
+        let mut from = 0;
+        while from < 90 {
+            let mut to = 0;
+            while to < 90 {
+                if BING[s][from] & bit(to) != 0 {
+                    table[s][to] |= bit(from);
+                }
+                to += 1;
+            }
+            from += 1;
+        }
+        s += 1;
+    }
+    table
+}
+
+/// The squares strictly beyond each square in each direction of
+/// `ORTHOGONAL`: towards row 0, row 9, column 0 and column 8.
+const fn rays() -> [[Bitboard; 90]; 4] {
+    let mut table = [[0; 90]; 4];
+    let mut d = 0;
+    while d < 4 {
+        let mut square = 0;
+        while square < 90 {
+            let mut to = offset(square, ORTHOGONAL[d][0], ORTHOGONAL[d][1]);
+            while to != NONE {
+                table[d][square] |= bit(to as usize);
+                to = offset(to as usize, ORTHOGONAL[d][0], ORTHOGONAL[d][1]);
+            }
+            square += 1;
+        }
+        d += 1;
+    }
+    table
+}
+
+const SHUAI: [[Bitboard; 90]; 2] = palace_steps(ORTHOGONAL);
+const SHI: [[Bitboard; 90]; 2] = palace_steps(DIAGONAL);
+const XIANG: [[(u8, u8); 4]; 90] = xiang_steps();
+const MA: [[(u8, u8); 8]; 90] = ma_steps(false);
+const MA_ATTACKERS: [[(u8, u8); 8]; 90] = ma_steps(true);
+const BING: [[Bitboard; 90]; 2] = bing_steps();
+const BING_ATTACKERS: [[Bitboard; 90]; 2] = bing_attackers();
+const RAYS: [[Bitboard; 90]; 4] = rays();
+
+/// The nearest square of `blockers`, all on ray `d` from some square.
+fn nearest(blockers: Bitboard, d: usize) -> Option<usize> {
+    if blockers == 0 {
+        None
+    } else if d.is_multiple_of(2) {
+        // towards row 0 or column 0, so the nearest has the highest index
+        Some(127 - blockers.leading_zeros() as usize)
+    } else {
+        Some(blockers.trailing_zeros() as usize)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bitboards {
+    pieces: [[Bitboard; 7]; 2],
+    sides: [Bitboard; 2],
+}
+
+impl Bitboards {
+    pub fn new(board: &[[Piece; 9]; 10]) -> Bitboards {
+        let mut bits = Bitboards::default();
+        for (row, pieces) in board.iter().enumerate() {
+            for (col, &piece) in pieces.iter().enumerate() {
+                bits.toggle(row * 9 + col, piece);
+            }
+        }
+        bits
+    }
+
+    /// Adds `piece` to `square`, or removes it if it is there. Empty pieces
+    /// change nothing.
+    pub fn toggle(&mut self, square: usize, piece: Piece) {
+        if let (Some(s), Some(k)) = (side(piece.player()), kind(piece.piecetype())) {
+            self.pieces[s][k] ^= bit(square);
+            self.sides[s] ^= bit(square);
+        }
+    }
+
+    pub fn pieces(&self, player: Player, piecetype: PieceType) -> Bitboard {
+        match (side(player), kind(piecetype)) {
+            (Some(s), Some(k)) => self.pieces[s][k],
+            _ => 0,
+        }
+    }
+
+    pub fn side(&self, player: Player) -> Bitboard {
+        side(player).map_or(0, |s| self.sides[s])
+    }
+
+    pub fn occupied(&self) -> Bitboard {
+        self.sides[0] | self.sides[1]
+    }
+
+    fn slide(&self, square: usize, jump: bool) -> Bitboard {
+        let occupied = self.occupied();
+        let mut attacks = 0;
+        for (d, rays) in RAYS.iter().enumerate() {
+            let ray = rays[square];
+            match nearest(ray & occupied, d) {
+                None => attacks |= ray,
+                Some(screen) if jump => {
+                    attacks |= ray & !rays[screen] & !bit(screen);
+                    if let Some(target) = nearest(rays[screen] & occupied, d) {
+                        attacks |= bit(target);
+                    }
+                }
+                Some(blocker) => attacks |= ray & !rays[blocker],
+            }
+        }
+        attacks
+    }
+
+    /// Where a `piecetype` of `player` on `square` can move by its own
+    /// rules, like `Piece::possible`.
+    pub fn destinations(&self, player: Player, piecetype: PieceType, square: usize) -> Bitboard {
+        let s = match side(player) {
+            Some(s) => s,
+            None => return 0,
+        };
+        let empty = |square: u8| self.occupied() & bit(square as usize) == 0;
+        let targets = match piecetype {
+            PieceType::Shuai => SHUAI[s][square],
+            PieceType::Shi => SHI[s][square],
+            PieceType::Xiang => XIANG[square]
+                .iter()
+                .filter(|&&(to, eye)| to != NONE && empty(eye))
+                .fold(0, |bb, &(to, _)| bb | bit(to as usize)),
+            PieceType::Ma => MA[square]
+                .iter()
+                .filter(|&&(to, leg)| to != NONE && empty(leg))
+                .fold(0, |bb, &(to, _)| bb | bit(to as usize)),
+            PieceType::Ju => self.slide(square, false),
+            PieceType::Pao => self.slide(square, true),
+            PieceType::Bing => BING[s][square],
+            PieceType::None => 0,
+        };
+        targets & !self.sides[s]
+    }
+
+    /// The type of `player`'s piece on `square`, if any.
+    pub fn piece_on(&self, player: Player, square: usize) -> PieceType {
+        match side(player) {
+            Some(s) if self.sides[s] & bit(square) != 0 => KINDS
+                .iter()
+                .zip(&self.pieces[s])
+                .find(|&(_, &bb)| bb & bit(square) != 0)
+                .map_or(PieceType::None, |(&k, _)| k),
+            _ => PieceType::None,
+        }
+    }
+
+    /// Adds every move of `player`'s pieces by their own rules, checks
+    /// aside, to `list`, with the moving and captured piece types.
+    pub fn generate(&self, player: Player, list: &mut MoveList) {
+        self.generate_to(player, !0, list)
+    }
+
+    /// Like `generate`, but only the captures.
+    pub fn generate_captures(&self, player: Player, list: &mut MoveList) {
+        self.generate_to(player, self.side(player.opponent()), list)
+    }
+
+    fn generate_to(&self, player: Player, targets: Bitboard, list: &mut MoveList) {
+        let enemy = self.side(player.opponent());
+        for &piecetype in &KINDS {
+            for from in squares(self.pieces(player, piecetype)) {
+                for to in squares(self.destinations(player, piecetype, from) & targets) {
+                    let captured = if enemy & bit(to) != 0 {
+                        self.piece_on(player.opponent(), to)
+                    } else {
+                        PieceType::None
+                    };
+                    list.push(Move::from_squares(from, to).with_pieces(piecetype, captured));
+                }
+            }
+        }
+    }
+
+    /// The squares of `player`'s pieces that could capture on `square` if
+    /// only the pieces in `occupied` stood on the board. Sliders look through
+    /// the squares left out of `occupied`, so a Ju or Pao behind a piece that
+    /// has already captured becomes an attacker once it moves away, and a Pao
+    /// whose screen has gone stops being one.
+    pub fn attackers(&self, player: Player, square: usize, occupied: Bitboard) -> Bitboard {
+        let s = match side(player) {
+            Some(s) => s,
+            None => return 0,
+        };
+        let [shuai, shi, xiang, ma, ju, pao, bing] = self.pieces[s];
+        let empty = |square: u8| occupied & bit(square as usize) == 0;
+        let mut attackers =
+            SHUAI[s][square] & shuai | SHI[s][square] & shi | BING_ATTACKERS[s][square] & bing;
+        for &(from, eye) in &XIANG[square] {
+            if from != NONE && empty(eye) {
+                attackers |= bit(from as usize) & xiang;
+            }
+        }
+        for &(from, leg) in &MA_ATTACKERS[square] {
+            if from != NONE && empty(leg) {
+                attackers |= bit(from as usize) & ma;
+            }
+        }
+        for (d, rays) in RAYS.iter().enumerate() {
+            if let Some(first) = nearest(rays[square] & occupied, d) {
+                attackers |= bit(first) & ju;
+                if let Some(second) = nearest(rays[first] & occupied, d) {
+                    attackers |= bit(second) & pao;
+                }
+            }
+        }
+        attackers & occupied
+    }
+
+    /// Whether `player`'s general is attacked, including by the opposing
+    /// general along an open file. A missing general counts as attacked.
+    pub fn in_check(&self, player: Player) -> bool {
+        let (s, enemy) = match side(player) {
+            Some(s) => (s, 1 - s),
+            None => return true,
+        };
+        let king = match self.pieces[s][0] {
+            0 => return true,
+            bb => bb.trailing_zeros() as usize,
+        };
+        let [shuai, _, _, ma, ju, pao, bing] = self.pieces[enemy];
+        let occupied = self.occupied();
+        for (d, rays) in RAYS.iter().enumerate() {
+            if let Some(first) = nearest(rays[king] & occupied, d) {
+                if (ju | shuai) & bit(first) != 0 {
+                    return true;
+                }
+                if let Some(second) = nearest(rays[first] & occupied, d) {
+                    if pao & bit(second) != 0 {
+                        return true;
+                    }
+                }
+            }
+        }
+        if MA_ATTACKERS[king].iter().any(|&(from, leg)| {
+            from != NONE && ma & bit(from as usize) != 0 && occupied & bit(leg as usize) == 0
+        }) {
+            return true;
+        }
+        BING_ATTACKERS[enemy][king] & bing != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Board;
+    use crate::rng::Rng;
+
+    /// The array-based answer: can any enemy piece reach the general by
+    /// `Piece::possible`, or do the generals face each other?
+    fn attacked(board: &Board, player: Player) -> bool {
+        let king = match board.find_shuai(player) {
+            Some(king) => king,
+            None => return true,
+        };
+        board.generals_facing()
+            || (0..90).any(|square| {
+                let piece = board.piece([square / 9, square % 9]);
+                piece.player() == player.opponent()
+                    && piece
+                        .possible(board, square / 9, square % 9)
+                        .contains(&king)
+            })
+    }
+
+    #[test]
+    fn matches_array_move_generation() {
+        let mut rng = Rng::new(0x2545_f491_4f6c_dd1d);
+        for _ in 0..40 {
+            let mut board = Board::new();
+            for _ in 0..120 {
+                assert_eq!(*board.bitboards(), Bitboards::new(board.rows()));
+                for square in 0..90 {
+                    let piece = board.piece([square / 9, square % 9]);
+                    let mut expected = piece.possible(&board, square / 9, square % 9);
+                    expected.sort();
+                    let actual = squares(board.bitboards().destinations(
+                        piece.player(),
+                        piece.piecetype(),
+                        square,
+                    ))
+                    .map(|to| [to / 9, to % 9])
+                    .collect::<Vec<_>>();
+                    assert_eq!(actual, expected, "{:?} on {}", piece, square);
+                }
+                for player in [Player::Red, Player::Green] {
+                    assert_eq!(board.in_check(player), attacked(&board, player));
+                }
+                let mut generated = MoveList::new();
+                board.bitboards().generate(board.turn, &mut generated);
+                for mv in generated {
+                    let [from, to] = [mv.from(), mv.to()];
+                    assert_eq!(mv.piece(), board.piece(from).piecetype());
+                    assert_eq!(mv.captured(), board.piece(to).piecetype());
+                }
+                let moves = board.legal_moves();
+                if moves.is_empty() {
+                    break;
+                }
+                let mv = moves[rng.below(moves.len())];
+                board.make_move(mv.from(), mv.to());
+            }
+        }
+    }
+}
@@ -1,9 +1,18 @@
-use crate::color::write_color;
-use anyhow::Result;
-use std::io::{stdin, BufRead /*BufReader*/};
-use termcolor::Color;
+use crate::clock::Clock;
+#[cfg(feature = "term")]
+use crate::render::{write_glyph, RichRenderer};
+use crate::bitboard::{bit, squares, Bitboards};
+use crate::moves::MoveList;
+use crate::zobrist;
+use std::error::Error;
+use std::fmt;
+#[cfg(feature = "term")]
+use std::io::{self, IsTerminal};
+#[cfg(feature = "term")]
+use termcolor::{ColorChoice, StandardStream, WriteColor};
 
-#[derive(Copy, Clone)]
+/// The kind of a piece, by its pinyin name. `None` marks an empty square.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum PieceType {
     None,
     Shuai,
@@ -15,15 +24,92 @@ pub enum PieceType {
     Bing,
 }
 
-#[derive(Copy, Clone)]
+impl PieceType {
+    /// The piece's WXF letter: `K` general, `A` advisor (Shi), `B` elephant
+    /// (Xiang), `N` horse (Ma), `R` chariot (Ju), `C` cannon (Pao) and `P`
+    /// pawn (Bing). Empty squares are `.`.
+    pub fn letter(self) -> char {
+        match self {
+            PieceType::Shuai => 'K',
+            PieceType::Shi => 'A',
+            PieceType::Xiang => 'B',
+            PieceType::Ma => 'N',
+            PieceType::Ju => 'R',
+            PieceType::Pao => 'C',
+            PieceType::Bing => 'P',
+            PieceType::None => '.',
+        }
+    }
+
+    /// The piece for a WXF letter in either case.
+    pub fn from_letter(c: char) -> Option<PieceType> {
+        match c.to_ascii_uppercase() {
+            'K' => Some(PieceType::Shuai),
+            'A' => Some(PieceType::Shi),
+            'B' => Some(PieceType::Xiang),
+            'N' => Some(PieceType::Ma),
+            'R' => Some(PieceType::Ju),
+            'C' => Some(PieceType::Pao),
+            'P' => Some(PieceType::Bing),
+            _ => None,
+        }
+    }
+}
+
+pub use crate::moves::Move;
+
+/// What a move played with `Board::move_piece` led to. A side with no
+/// legal moves, in check or not, has lost.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum MoveResult {
     Valid,
-    Invalid,
     RedWin,
     GreenWin,
 }
 
-#[derive(Copy, Clone)]
+/// Why `Board::move_piece` rejected a move.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MoveError {
+    /// A square lies outside the 10x9 board.
+    OutOfBounds([usize; 2]),
+    /// The side to move has no legal moves left.
+    GameOver,
+    NotYourTurn(Player),
+    NoPieceOnSquare([usize; 2]),
+    NotYourPiece([usize; 2]),
+    /// The piece cannot reach the square by its own movement rules.
+    IllegalDestination([usize; 2]),
+    /// The move would leave the two generals facing on an open file.
+    FlyingGeneral,
+    LeavesGeneralInCheck,
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MoveError::OutOfBounds([row, col]) => {
+                write!(f, "({}, {}) is off the board", row + 1, col + 1)
+            }
+            MoveError::GameOver => write!(f, "The game is over"),
+            MoveError::NotYourTurn(player) => write!(f, "It is not {:?}'s turn", player),
+            MoveError::NoPieceOnSquare([row, col]) => {
+                write!(f, "There's no piece on ({}, {})", row + 1, col + 1)
+            }
+            MoveError::NotYourPiece(..) => write!(f, "You cannot move the opponent's piece"),
+            MoveError::IllegalDestination([row, col]) => {
+                write!(f, "The piece cannot move to ({}, {})", row + 1, col + 1)
+            }
+            MoveError::FlyingGeneral => write!(f, "The generals cannot face each other"),
+            MoveError::LeavesGeneralInCheck => write!(f, "That would leave your general in check"),
+        }
+    }
+}
+
+impl Error for MoveError {}
+
+/// The owner of a square. Empty squares belong to `NoneRed` or `NoneGreen`,
+/// which have no rules attached.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Player {
     NoneRed,
     NoneGreen,
@@ -31,7 +117,20 @@ pub enum Player {
     Green,
 }
 
-#[derive(Copy, Clone)]
+impl Player {
+    /// The other side.
+    pub fn opponent(self) -> Player {
+        match self {
+            Player::Red => Player::Green,
+            Player::Green => Player::Red,
+            Player::NoneRed => Player::NoneGreen,
+            Player::NoneGreen => Player::NoneRed,
+        }
+    }
+}
+
+/// The contents of one square.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Piece {
     piecetype: PieceType,
     player: Player,
@@ -40,331 +139,183 @@ pub struct Piece {
 impl Piece {
     pub fn new(piecetype: PieceType, player: Player) -> Piece {
         Piece {
-            piecetype: piecetype,
-            player: player,
+            piecetype,
+            player,
         }
     }
 
+    pub fn piecetype(&self) -> PieceType {
+        self.piecetype
+    }
+
+    pub fn player(&self) -> Player {
+        self.player
+    }
+
+    /// Whether `(row, col)` is on the board and empty.
     pub fn vacant(&self, board: &Board, row: usize, col: usize) -> bool {
-        let a = (row <= 9) && (col <= 8);
-        let b = match board.board[row][col].piecetype {
-            PieceType::None => true,
-            _ => false,
-        };
-        a && b
+        (row <= 9) && (col <= 8) && matches!(board.board[row][col].piecetype, PieceType::None)
     }
 
+    /// Whether `(row, col)` is on the board and not held by this piece's side.
     pub fn allowed(&self, board: &Board, row: usize, col: usize) -> bool {
-        let a = (row <= 9) && (col <= 8);
-        let b = match (board.board[row][col].player, self.player) {
-            (Player::Red, Player::Red) | (Player::Green, Player::Green) => false,
-            _ => true,
-        };
-        a && b
+        (row <= 9)
+            && (col <= 8)
+            && !matches!(
+                (board.board[row][col].player, self.player),
+                (Player::Red, Player::Red) | (Player::Green, Player::Green)
+            )
     }
 
-    pub fn possible(&self, board: &Board, prow: usize, pcol: usize) -> Vec<[usize; 2]> {
-        match self.piecetype {
-            PieceType::Ju => {
-                let mut v = vec![];
-                for col in pcol..9 {
-                    match (board.board[prow][col].player, self.player) {
-                        (Player::NoneRed, _) | (Player::NoneGreen , _) => v.push([prow, col]),
-                        (Player::Green, Player::Red) | (Player::Red, Player :: Green) => {
-                            v.push([prow, col]);
-                            break;
-                        },
-                        _ => break,
-                    }
-                }
-                for col in (0..pcol).rev() {
-                    match (board.board[prow][col].player, self.player) {
-                        (Player::NoneRed, _) | (Player::NoneGreen , _) => v.push([prow, col]),
-                        (Player::Green, Player::Red) | (Player::Red, Player :: Green) => {
-                            v.push([prow, col]);
-                            break;
-                        },
-                        _ => break,
-                    }
-                }
-                for row in prow..10 {
-                    match (board.board[row][pcol].player, self.player) {
-                        (Player::NoneRed, _) | (Player::NoneGreen , _) => v.push([row, pcol]),
-                        (Player::Green, Player::Red) | (Player::Red, Player :: Green) => {
-                            v.push([row, pcol]);
-                            break;
-                        },
-                        _ => break,
-                    }
-                }
-                for row in (0..prow).rev() {
-                    match (board.board[row][pcol].player, self.player) {
-                        (Player::NoneRed, _) | (Player::NoneGreen, _) => v.push([row, pcol]),
-                        (Player::Green, Player::Red) | (Player::Red, Player :: Green) => {
-                            v.push([row, pcol]);
-                            break;
-                        },
+    /// The square `(dr, dc)` away from `(row, col)`, if it is on the board.
+    fn step(row: usize, col: usize, dr: isize, dc: isize) -> Option<[usize; 2]> {
+        let row = row as isize + dr;
+        let col = col as isize + dc;
+        if (0..10).contains(&row) && (0..9).contains(&col) {
+            Some([row as usize, col as usize])
+        } else {
+            None
+        }
+    }
+
+    /// Squares reachable by moving straight in each direction, as for the
+    /// Ju, or jumping exactly one screen to capture, as for the Pao.
+    fn slide(&self, board: &Board, prow: usize, pcol: usize, jump: bool) -> Vec<[usize; 2]> {
+        let mut v = vec![];
+        for [dr, dc] in [[0, 1], [0, -1], [1, 0], [-1, 0]] {
+            let mut screened = false;
+            let mut pos = Piece::step(prow, pcol, dr, dc);
+            while let Some([row, col]) = pos {
+                let target = board.board[row][col];
+                if !screened {
+                    match target.piecetype {
+                        PieceType::None => v.push([row, col]),
+                        _ if jump => screened = true,
                         _ => {
+                            if self.allowed(board, row, col) {
+                                v.push([row, col]);
+                            }
                             break;
-                        },
+                        }
+                    }
+                } else if !matches!(target.piecetype, PieceType::None) {
+                    if self.allowed(board, row, col) {
+                        v.push([row, col]);
                     }
+                    break;
                 }
-                v
+                pos = Piece::step(row, col, dr, dc);
             }
+        }
+        v
+    }
+
+    /// Squares this piece, standing on `(prow, pcol)`, can move to by its own
+    /// rules. Whether the move leaves the general in check is not considered;
+    /// see `Board::legal_moves`.
+    pub fn possible(&self, board: &Board, prow: usize, pcol: usize) -> Vec<[usize; 2]> {
+        match self.piecetype {
+            PieceType::Ju => self.slide(board, prow, pcol, false),
+            PieceType::Pao => self.slide(board, prow, pcol, true),
             PieceType::Ma => {
                 let mut v = vec![];
-                if self.vacant(board, prow + 1, pcol) {
-                    if self.allowed(board, prow + 2, pcol + 1) {
-                        v.push([prow + 2, pcol + 1])
-                    }
-                    if self.allowed(board, prow + 2, pcol - 1) {
-                        v.push([prow + 2, pcol - 1])
-                    }
-                }
-                if self.vacant(board, prow - 1, pcol) {
-                    if self.allowed(board, prow - 2, pcol + 1) {
-                        v.push([prow - 2, pcol + 1])
-                    }
-                    if self.allowed(board, prow - 2, pcol - 1) {
-                        v.push([prow - 2, pcol - 1])
-                    }
-                }
-                if self.vacant(board, prow, pcol + 1) {
-                    if self.allowed(board, prow - 1, pcol + 2) {
-                        v.push([prow - 1, pcol + 2])
-                    }
-                    if self.allowed(board, prow + 1, pcol + 2) {
-                        v.push([prow + 1, pcol + 2])
-                    }
-                }
-                if self.vacant(board, prow, pcol - 1) {
-                    if self.allowed(board, prow - 1, pcol - 2) {
-                        v.push([prow - 1, pcol - 2])
-                    }
-                    if self.allowed(board, prow + 1, pcol - 2) {
-                        v.push([prow + 1, pcol - 2])
+                for [lr, lc] in [[1, 0], [-1, 0], [0, 1], [0, -1]] {
+                    let leg = match Piece::step(prow, pcol, lr, lc) {
+                        Some([row, col]) if self.vacant(board, row, col) => [row, col],
+                        _ => continue,
+                    };
+                    // the two destinations fanning out from the leg
+                    for side in [-1, 1] {
+                        let (dr, dc) = if lr == 0 { (side, lc) } else { (lr, side) };
+                        if let Some([row, col]) = Piece::step(leg[0], leg[1], dr, dc) {
+                            if self.allowed(board, row, col) {
+                                v.push([row, col]);
+                            }
+                        }
                     }
                 }
                 v
             }
             PieceType::Shi => {
                 let mut v = vec![];
-                match self.player {
-                    Player::Red => {
-                        if ((prow + 1 == 8 && pcol + 1 == 4)
-                            || (prow + 1 == 8 && pcol - 1 == 4)
-                            || (prow - 1 == 8 && pcol + 1 == 4)
-                            || (prow - 1 == 8 && pcol - 1 == 4))
-                            && self.allowed(board, 8, 4)
-                        {
-                            v.push([8, 4]);
-                        }
-                        if prow == 8 && pcol == 4 {
-                            for [row, col] in vec![[9, 5], [9, 3], [7, 5], [7, 3]] {
-                                if self.allowed(board, row, col) {
-                                    v.push([row, col]);
-                                }
-                            }
-                        }
-                    }
-                    Player::Green => {
-                        if ((prow + 1 == 1 && pcol + 1 == 4)
-                            || (prow + 1 == 1 && pcol - 1 == 4)
-                            || (prow - 1 == 1 && pcol + 1 == 4)
-                            || (prow - 1 == 1 && pcol - 1 == 4))
-                            && self.allowed(board, 1, 4)
-                        {
-                            v.push([1, 4]);
-                        }
-                        if prow == 1 && pcol == 4 {
-                            for [row, col] in vec![[2, 5], [2, 3], [0, 5], [0, 3]] {
-                                if self.allowed(board, row, col) {
-                                    v.push([row, col]);
-                                }
+                let center = match self.player {
+                    Player::Red => [8, 4],
+                    Player::Green => [1, 4],
+                    _ => return v,
+                };
+                if prow.abs_diff(center[0]) == 1
+                    && pcol.abs_diff(center[1]) == 1
+                    && self.allowed(board, center[0], center[1])
+                {
+                    v.push(center);
+                }
+                if [prow, pcol] == center {
+                    for [dr, dc] in [[1, 1], [1, -1], [-1, 1], [-1, -1]] {
+                        if let Some([row, col]) = Piece::step(prow, pcol, dr, dc) {
+                            if self.allowed(board, row, col) {
+                                v.push([row, col]);
                             }
                         }
                     }
-                    _ => {}
                 }
                 v
             }
             PieceType::Xiang => {
                 let mut v = vec![];
-                match self.player {
-                    Player::Red | Player::Green => {
-                        if self.vacant(board, prow - 1, pcol - 1)
-                            && self.allowed(board, prow - 2, pcol - 2)
-                        {
-                            v.push([prow - 2, pcol - 2]);
-                        }
-                        if self.vacant(board, prow + 1, pcol + 1)
-                            && self.allowed(board, prow + 2, pcol + 2)
-                        {
-                            v.push([prow + 2, pcol + 2]);
-                        }
-                        if self.vacant(board, prow - 1, pcol + 1)
-                            && self.allowed(board, prow - 2, pcol + 2)
-                        {
-                            v.push([prow - 2, pcol + 2]);
-                        }
-                        if self.vacant(board, prow + 1, pcol - 1)
-                            && self.allowed(board, prow + 2, pcol - 2)
-                        {
-                            v.push([prow + 2, pcol - 2]);
+                // the Xiang never crosses the river
+                let home = match self.player {
+                    Player::Red => 5..=9,
+                    Player::Green => 0..=4,
+                    _ => return v,
+                };
+                for [dr, dc] in [[1, 1], [1, -1], [-1, 1], [-1, -1]] {
+                    let eye = match Piece::step(prow, pcol, dr, dc) {
+                        Some([row, col]) if self.vacant(board, row, col) => [row, col],
+                        _ => continue,
+                    };
+                    if let Some([row, col]) = Piece::step(eye[0], eye[1], dr, dc) {
+                        if home.contains(&row) && self.allowed(board, row, col) {
+                            v.push([row, col]);
                         }
                     }
-                    _ => {}
                 }
                 v
             }
             PieceType::Bing => {
                 let mut v = vec![];
-                match self.player {
-                    Player::Red => {
-                        if prow <= 4 {
-                            for [row, col] in
-                                vec![[prow - 1, pcol], [prow, pcol - 1], [prow, pcol + 1]]
-                            {
-                                if self.allowed(&board, row, col) {
-                                    v.push([row, col]);
-                                }
-                            }
-                        } else if self.allowed(&board, prow - 1, pcol) {
-                            v.push([prow - 1, pcol]);
-                        }
-                    }
-                    Player::Green => {
-                        if prow >= 5 {
-                            for [row, col] in
-                                vec![[prow + 1, pcol], [prow, pcol - 1], [prow, pcol + 1]]
-                            {
-                                if self.allowed(&board, row, col) {
-                                    v.push([row, col]);
-                                }
-                            }
-                        } else if self.allowed(&board, prow + 1, pcol) {
-                            v.push([prow + 1, pcol]);
-                        }
-                    }
-                    _ => {}
+                let (forward, crossed) = match self.player {
+                    Player::Red => (-1, prow <= 4),
+                    Player::Green => (1, prow >= 5),
+                    _ => return v,
+                };
+                let mut steps = vec![[forward, 0]];
+                if crossed {
+                    steps.push([0, -1]);
+                    steps.push([0, 1]);
                 }
-                v
-            }
-            PieceType::Shuai => {
-                let mut v = vec![];
-                match self.player {
-                    Player::Red => {
-                        for [row, col] in vec![
-                            [prow + 1, pcol],
-                            [prow - 1, pcol],
-                            [prow, pcol + 1],
-                            [prow, pcol - 1],
-                        ] {
-                            if row >= 7
-                                && row <= 9
-                                && col >= 3
-                                && col <= 5
-                                && self.allowed(board, row, col)
-                            {
-                                v.push([row, col]);
-                            }
+                for [dr, dc] in steps {
+                    if let Some([row, col]) = Piece::step(prow, pcol, dr, dc) {
+                        if self.allowed(board, row, col) {
+                            v.push([row, col]);
                         }
                     }
-                    Player::Green => {
-                        for [row, col] in vec![
-                            [prow + 1, pcol],
-                            [prow - 1, pcol],
-                            [prow, pcol + 1],
-                            [prow, pcol - 1],
-                        ] {
-                            if row <= 2
-                                && col >= 3
-                                && col <= 5
-                                && self.allowed(board, row, col)
-                            {
-                                v.push([row, col]);
-                            }
-                        }
-                    }
-                    _ => {}
                 }
                 v
             }
-            PieceType::Pao => {
+            PieceType::Shuai => {
                 let mut v = vec![];
-                let mut flag = false;
-                for col in pcol..9 {
-                    if !flag {
-                        match board.board[prow][col].player {
-                            Player::NoneRed | Player::NoneGreen => v.push([prow, col]),
-                            _ => {
-                                flag = true;
-                            }
-                        }
-                    } else {
-                        match (board.board[prow][col].player, self.player) {
-                            (Player::Red, Player::Green) | (Player::Green, Player::Red) =>  {
-                                v.push([prow, col]);
-                                break;
-                            },
-                            _  => break,
-                        }
-                    }
-                }
-                flag = false;
-                for col in (0..pcol).rev() {
-                    if !flag {
-                        match board.board[prow][col].player {
-                            Player::NoneRed | Player::NoneGreen => v.push([prow, col]),
-                            _ => {
-                                flag = true;
-                            }
-                        }
-                    } else {
-                        match (board.board[prow][col].player, self.player) {
-                            (Player::Red, Player::Green) | (Player::Green, Player::Red) => { 
-                                v.push([prow, col]);
-                                break
-                            },
-                            _  => break,
-                        }
-                    }
-                }
-                flag = false;
-                for row in prow..10 {
-                    if !flag {
-                        match board.board[row][pcol].player {
-                            Player::NoneRed | Player::NoneGreen => v.push([row, pcol]),
-                            _ => {
-                                flag = true;
-                            }
-                        }
-                    } else {
-                        match (board.board[row][pcol].player, self.player) {
-                            (Player::Red, Player::Green) | (Player::Green, Player::Red) => { 
-                                v.push([row, pcol]);
-                                break;
-                            }
-                            _  => break,
-                        }
-                    }
-                }
-                flag = false;
-                for row in (0..prow).rev() {
-                    if !flag {
-                        match board.board[row][pcol].player {
-                            Player::NoneRed | Player::NoneGreen => v.push([row, pcol]),
-                            _ => {
-                                flag = true;
-                            }
-                        }
-                    } else {
-                        match (board.board[row][pcol].player, self.player) {
-                            (Player::Red, Player::Green) | (Player::Green, Player::Red) => {
-                                v.push([row, pcol]);
-                                break;
-                            },
-                            _  => break,
+                let palace = match self.player {
+                    Player::Red => 7..=9,
+                    Player::Green => 0..=2,
+                    _ => return v,
+                };
+                for [dr, dc] in [[1, 0], [-1, 0], [0, 1], [0, -1]] {
+                    if let Some([row, col]) = Piece::step(prow, pcol, dr, dc) {
+                        if palace.contains(&row)
+                            && (3..=5).contains(&col)
+                            && self.allowed(board, row, col)
+                        {
+                            v.push([row, col]);
                         }
                     }
                 }
@@ -376,42 +327,37 @@ impl Piece {
         }
     }
 
-    pub fn show_piece(&self) {
-        /*
-        print out the piece on the board
-        */
-        match (self.piecetype, self.player) {
-            (PieceType::Shuai, Player::Red) => write_color("帅", Some(Color::Red)).unwrap(),
-            (PieceType::Shi, Player::Red) => write_color("仕", Some(Color::Red)).unwrap(),
-            (PieceType::Xiang, Player::Red) => write_color("相", Some(Color::Red)).unwrap(),
-            (PieceType::Ma, Player::Red) => write_color("傌", Some(Color::Red)).unwrap(),
-            (PieceType::Ju, Player::Red) => write_color("俥", Some(Color::Red)).unwrap(),
-            (PieceType::Pao, Player::Red) => write_color("炮", Some(Color::Red)).unwrap(),
-            (PieceType::Bing, Player::Red) => write_color("兵", Some(Color::Red)).unwrap(),
-            (PieceType::Shuai, Player::Green) => write_color("将", Some(Color::Green)).unwrap(),
-            (PieceType::Shi, Player::Green) => write_color("士", Some(Color::Green)).unwrap(),
-            (PieceType::Xiang, Player::Green) => write_color("象", Some(Color::Green)).unwrap(),
-            (PieceType::Ma, Player::Green) => write_color("馬", Some(Color::Green)).unwrap(),
-            (PieceType::Ju, Player::Green) => write_color("車", Some(Color::Green)).unwrap(),
-            (PieceType::Pao, Player::Green) => write_color("砲", Some(Color::Green)).unwrap(),
-            (PieceType::Bing, Player::Green) => write_color("卒", Some(Color::Green)).unwrap(),
-            (_, Player::NoneRed) => write_color("..", Some(Color::White)).unwrap(),
-            (_, Player::NoneGreen) => write_color("..", Some(Color::White)).unwrap(),
-            _ => write_color("..", Some(Color::White)).unwrap(),
-        }
+    /// Writes the piece's character to `out` in its side's color.
+    #[cfg(feature = "term")]
+    pub fn show_piece(&self, out: &mut dyn WriteColor) -> io::Result<()> {
+        write_glyph(*self, out)
     }
 }
 
+/// A game in progress: the position, whose turn it is, the moves played and
+/// an optional clock.
+#[derive(Clone)]
 pub struct Board {
-    pub board: [[Piece; 9]; 10],
+    /// Squares indexed `[row][col]`, Green's back rank being row 0. Only
+    /// changed through `put` and `make_move`, so that the bitboards follow.
+    board: [[Piece; 9]; 10],
+    pub turn: Player,
+    /// Moves made through `move_piece`, oldest first.
+    pub history: Vec<Move>,
+    pub clock: Option<Clock>,
+    bits: Bitboards,
 }
 
-impl Board {
-    //const WIDTH: i32 = 30;
-    //const HEIGHT: i32 = 26;
+impl Default for Board {
+    fn default() -> Self {
+        Board::new()
+    }
+}
 
+impl Board {
+    /// The initial position, Red to move.
     pub fn new() -> Board {
-        let board = Board {
+        let mut board = Board {
             board: [
                 [
                     Piece::new(PieceType::Ju, Player::Green),
@@ -524,181 +470,553 @@ impl Board {
                     Piece::new(PieceType::Ju, Player::Red),
                 ],
             ],
+            turn: Player::Red,
+            history: vec![],
+            clock: None,
+            bits: Bitboards::default(),
         };
+        board.bits = Bitboards::new(&board.board);
         board
     }
 
-    pub fn run(&mut self) -> Result<()> {
-        let mut turn = Player::Red;
-        let stdin = stdin();
-        let reader = stdin.lock();
-        for line in reader.lines() {
-            let line = line?;
-            let tokens = line.split_whitespace().collect::<Vec<_>>();
-            match tokens.len() {
-                5 => {
-                    if tokens[0] == "move" {
-                        let f1: usize = tokens[1].parse()?;
-                        let f1 = f1 - 1;
-                        let f2: usize = tokens[2].parse()?;
-                        let f2 = f2 - 1;
-                        let t1: usize = tokens[3].parse()?;
-                        let t1 = t1 - 1;
-                        let t2: usize = tokens[4].parse()?;
-                        let t2 = t2 - 1;
-                        match self.move_piece(turn, [f1, f2], [t1, t2]) {
-                            MoveResult::Valid => {
-                                match turn {
-                                    Player::Red => {
-                                        turn = Player::Green;
-                                    }
-                                    Player::Green => {
-                                        turn = Player::Red;
-                                    }
-                                    _ => {
-
-                                    }
-                                }
-                                self.show()
-                            }
-                            MoveResult::Invalid => {
-                                self.show()
-                            }
-                            MoveResult::RedWin => {
-                                println!("Red has won");
-                                break;
-                            }
-                            MoveResult::GreenWin => {
-                                println!("Green has won");
-                                break;
-                            }
-                        }
-                    } else {
-                        println!("Invalid Command");
-                    }
-                }
-                _ => {
-                    println!("Invalid Command");
-                }
+    /// A board with no pieces, Red to move.
+    pub fn empty() -> Board {
+        let mut board = Board::new();
+        for row in 0..10 {
+            for col in 0..9 {
+                let player = if row < 5 { Player::NoneGreen } else { Player::NoneRed };
+                board.board[row][col] = Piece::new(PieceType::None, player);
             }
         }
-        Ok(())
+        board.bits = Bitboards::default();
+        board
     }
 
-    pub fn show(&self) {
-        println!("xxxxx 1  2  3  4  5  6  7  8  9xx");
-        println!("xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx");
-        for row in 0..5 {
-            print!("{:02} ", row + 1);
-            print!("xx");
+    /// Puts `piece` on `square`, replacing whatever was there.
+    pub fn put(&mut self, [row, col]: [usize; 2], piece: Piece) {
+        self.bits.toggle(row * 9 + col, self.board[row][col]);
+        self.bits.toggle(row * 9 + col, piece);
+        self.board[row][col] = piece;
+    }
+
+    /// The piece on `square`, of type `None` if it is empty.
+    pub fn piece(&self, [row, col]: [usize; 2]) -> Piece {
+        self.board[row][col]
+    }
+
+    /// Every square, indexed `[row][col]`.
+    pub fn rows(&self) -> &[[Piece; 9]; 10] {
+        &self.board
+    }
+
+    pub fn bitboards(&self) -> &Bitboards {
+        &self.bits
+    }
+
+    /// The same position with the colours swapped and the board turned
+    /// upside down, so that the other side is to move.
+    pub fn flipped(&self) -> Board {
+        let mut flipped = Board::empty();
+        for row in 0..10 {
             for col in 0..9 {
-                self.board[row][col].show_piece();
-                if col != 8 {
-                    print!(" ");
-                }
+                let piece = self.board[row][col];
+                flipped.put([9 - row, col], Piece::new(piece.piecetype, piece.player.opponent()));
             }
-            println!("xx")
         }
-        for row in 5..10 {
-            print!("{:02} ", row + 1);
-            print!("xx");
+        flipped.turn = self.turn.opponent();
+        flipped
+    }
+
+    /// Zobrist hash of the position and side to move.
+    pub fn hash(&self) -> u64 {
+        let mut hash = match self.turn {
+            Player::Green => zobrist::GREEN_TO_MOVE,
+            _ => 0,
+        };
+        for row in 0..10 {
             for col in 0..9 {
-                self.board[row][col].show_piece();
-                if col != 8 {
-                    print!(" ");
-                }
+                hash ^= zobrist::key(self.board[row][col], row * 9 + col);
             }
-            println!("xx")
         }
-        println!("xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx");
+        hash
     }
 
-
-    pub fn verify(&self, turn: Player, from: [usize; 2], to: [usize; 2]) -> MoveResult {
-        let piece = self.board[from[0]][from[1]];
-        match piece.piecetype {
-            PieceType::None => {
-                match write_color("Invalid Command: There's no piece!\n", Some(Color::White)) {
-                    Ok(..) => {},
-                    Err(..) => {},
-                };
-                return MoveResult::Invalid;
-            }
-            _ => {}
+    /// Zobrist hash of the position reflected left to right, so that
+    /// mirror-image positions can be recognised as the same.
+    pub fn mirror_hash(&self) -> u64 {
+        let mut hash = match self.turn {
+            Player::Green => zobrist::GREEN_TO_MOVE,
+            _ => 0,
         };
-        match (piece.player, turn) {
-            (Player::Red, Player::Green) => {
-                match write_color(
-                    "Invalid Command: You cannot move the opponent's piece!\n",
-                    Some(Color::White),
-                ) {
-                    Ok(..) => {},
-                    Err(..) => {},
-                };
-                return MoveResult::Invalid;
+        for row in 0..10 {
+            for col in 0..9 {
+                hash ^= zobrist::key(self.board[row][col], row * 9 + 8 - col);
             }
-            (Player::Green, Player::Red) => {
-                match write_color(
-                    "Invalid Command: You cannot move the opponent's piece!\n",
-                    Some(Color::White),
-                ) {
-                    Ok(..) => {},
-                    Err(..) => {},
+        }
+        hash
+    }
+
+    /// The position in FEN: ranks from Green's side, Red in upper case, `w`
+    /// for Red to move and `b` for Green. Captures are not tracked, so the
+    /// halfmove clock is always 0.
+    pub fn fen(&self) -> String {
+        let mut ranks = vec![];
+        for row in &self.board {
+            let mut rank = String::new();
+            let mut empty = 0;
+            for piece in row {
+                let letter = match piece.player {
+                    Player::Red => piece.piecetype.letter(),
+                    Player::Green => piece.piecetype.letter().to_ascii_lowercase(),
+                    _ => {
+                        empty += 1;
+                        continue;
+                    }
                 };
-                return MoveResult::Invalid;
+                if empty > 0 {
+                    rank.push_str(&empty.to_string());
+                    empty = 0;
+                }
+                rank.push(letter);
+            }
+            if empty > 0 {
+                rank.push_str(&empty.to_string());
             }
-            _ => {}
+            ranks.push(rank);
         }
-        println!("from: {:?}", from);
-        println!("to: {:?}", to);
-        let possible_poses = piece.possible(&self, from[0], from[1]);
-        match (piece.piecetype, piece.player) {
-            (PieceType::Shuai, Player::Red) => {
-                if possible_poses.len() == 0 {
-                    match write_color("Green Win!!!", Some(Color::White)) {
-                        Ok(..) => {},
-                        Err(..) => {},
-                    };
-                    return MoveResult::GreenWin;
+        let side = if self.turn == Player::Green { 'b' } else { 'w' };
+        format!("{} {} - - 0 {}", ranks.join("/"), side, self.history.len() / 2 + 1)
+    }
+
+    /// The position in a FEN as written by `fen`, with `w` or `r` for Red to
+    /// move and `b` for Green. Only the placement and side to move are read,
+    /// and the history starts empty. `None` if it is malformed.
+    pub fn from_fen(fen: &str) -> Option<Board> {
+        let mut fields = fen.split_whitespace();
+        let ranks = fields.next()?.split('/').collect::<Vec<_>>();
+        if ranks.len() != 10 {
+            return None;
+        }
+        let mut board = Board::empty();
+        for (row, rank) in ranks.iter().enumerate() {
+            let mut col = 0;
+            for c in rank.chars() {
+                if let Some(empty) = c.to_digit(10) {
+                    col += empty as usize;
+                    continue;
                 }
-            }
-            (PieceType::Shuai, Player::Green) => {
-                if possible_poses.len() == 0 {
-                    match write_color("Red Win!!!", Some(Color::White)) {
-                        Ok(..) => {},
-                        Err(..) => {},
-                    };
-                    return MoveResult::RedWin;
+                let player = if c.is_ascii_uppercase() { Player::Red } else { Player::Green };
+                if col >= 9 {
+                    return None;
                 }
+                board.put([row, col], Piece::new(PieceType::from_letter(c)?, player));
+                col += 1;
+            }
+            if col != 9 {
+                return None;
             }
-            _ => {}
         }
-        println!("{:?}",possible_poses);
-        if !possible_poses.contains(&to) {
-            println!(
-                "Invalid Command: You cannot move the piece to ({}, {}).\n",
-                to[0] + 1, to[1] + 1
-            );
-            return MoveResult::Invalid;
+        board.turn = match fields.next() {
+            None | Some("w") | Some("r") => Player::Red,
+            Some("b") => Player::Green,
+            Some(..) => return None,
+        };
+        Some(board)
+    }
+
+    /// Moves a piece without any checks and passes the turn. Returns the
+    /// captured piece so that `unmake_move` can restore it.
+    pub fn make_move(&mut self, from: [usize; 2], to: [usize; 2]) -> Piece {
+        let piece = self.board[from[0]][from[1]];
+        let captured = self.board[to[0]][to[1]];
+        let (from_square, to_square) = (from[0] * 9 + from[1], to[0] * 9 + to[1]);
+        self.bits.toggle(to_square, captured);
+        self.bits.toggle(from_square, piece);
+        self.bits.toggle(to_square, piece);
+        self.board[to[0]][to[1]] = piece;
+        self.board[from[0]][from[1]] = Piece::new(PieceType::None, Player::NoneGreen);
+        self.turn = self.turn.opponent();
+        captured
+    }
+
+    pub fn unmake_move(&mut self, from: [usize; 2], to: [usize; 2], captured: Piece) {
+        let piece = self.board[to[0]][to[1]];
+        let (from_square, to_square) = (from[0] * 9 + from[1], to[0] * 9 + to[1]);
+        self.bits.toggle(to_square, piece);
+        self.bits.toggle(from_square, piece);
+        self.bits.toggle(to_square, captured);
+        self.board[from[0]][from[1]] = piece;
+        self.board[to[0]][to[1]] = captured;
+        self.turn = self.turn.opponent();
+    }
+
+    /// Where `player`'s general stands.
+    pub fn find_shuai(&self, player: Player) -> Option<[usize; 2]> {
+        (0..10)
+            .flat_map(|row| (0..9).map(move |col| [row, col]))
+            .find(|&[row, col]| {
+                let piece = self.board[row][col];
+                piece.piecetype == PieceType::Shuai && piece.player == player
+            })
+    }
+
+    /// Whether the two generals face each other on an open file.
+    pub fn generals_facing(&self) -> bool {
+        match (self.find_shuai(Player::Red), self.find_shuai(Player::Green)) {
+            (Some(red), Some(green)) if red[1] == green[1] => (green[0] + 1..red[0])
+                .all(|row| self.board[row][red[1]].piecetype == PieceType::None),
+            _ => false,
+        }
+    }
+
+    /// Whether `player`'s general is attacked, including by the opposing
+    /// general along an open file.
+    pub fn in_check(&self, player: Player) -> bool {
+        self.bits.in_check(player)
+    }
+
+    /// Hands the move to the other side without moving, for null-move
+    /// pruning. Passing again undoes it.
+    pub fn pass(&mut self) {
+        self.turn = self.turn.opponent();
+    }
+
+    /// Every move for the side to move that does not leave its general in
+    /// check, which includes facing the opposing general.
+    pub fn legal_moves(&self) -> MoveList {
+        let mut scratch = self.scratch(self.turn);
+        let mut moves = MoveList::new();
+        self.bits.generate(self.turn, &mut moves);
+        moves.retain(|mv| {
+            let captured = scratch.make_move(mv.from(), mv.to());
+            let legal = !scratch.in_check(self.turn);
+            scratch.unmake_move(mv.from(), mv.to(), captured);
+            legal
+        });
+        moves
+    }
+
+    /// The legal captures for the side to move, and with `checks` also the
+    /// quiet moves that give check.
+    pub fn legal_captures(&self, checks: bool) -> MoveList {
+        let mut scratch = self.scratch(self.turn);
+        let mut moves = MoveList::new();
+        if checks {
+            self.bits.generate(self.turn, &mut moves);
+        } else {
+            self.bits.generate_captures(self.turn, &mut moves);
         }
-        return MoveResult::Valid;
+        moves.retain(|mv| {
+            let captured = scratch.make_move(mv.from(), mv.to());
+            let legal = !scratch.in_check(self.turn)
+                && (mv.is_capture() || scratch.in_check(self.turn.opponent()));
+            scratch.unmake_move(mv.from(), mv.to(), captured);
+            legal
+        });
+        moves
     }
-    // 記譜を戻すための関数
-    pub fn reverse_piece(&self) -> MoveResult {
-        return MoveResult::Valid;
+
+    /// A copy of the position without history or clock, with `turn` to move,
+    /// to try moves on.
+    fn scratch(&self, turn: Player) -> Board {
+        Board {
+            board: self.board,
+            turn,
+            history: vec![],
+            clock: None,
+            bits: self.bits,
+        }
     }
 
-    pub fn move_piece(&mut self, turn: Player, from: [usize; 2], to: [usize; 2]) -> MoveResult {
-        let isvalid = self.verify(turn, from, to);
-        match isvalid {
-            MoveResult::Valid => {
-                self.board[to[0]][to[1]] = self.board[from[0]][from[1]];
-                self.board[from[0]][from[1]] = Piece::new(PieceType::None, Player::NoneGreen);
-                return MoveResult::Valid;
+    /// The legal destinations of the piece on `from`, whichever side it
+    /// belongs to, each flagged with whether it captures.
+    pub fn destinations(&self, from: [usize; 2]) -> Vec<([usize; 2], bool)> {
+        let piece = self.board[from[0]][from[1]];
+        if piece.piecetype == PieceType::None {
+            return vec![];
+        }
+        let mut scratch = self.scratch(piece.player);
+        let mut destinations = vec![];
+        let targets = self
+            .bits
+            .destinations(piece.player, piece.piecetype, from[0] * 9 + from[1]);
+        for to in squares(targets).map(|square| [square / 9, square % 9]) {
+            let captured = scratch.make_move(from, to);
+            if !scratch.in_check(piece.player) {
+                destinations.push((to, captured.piecetype != PieceType::None));
             }
-            MoveResult::Invalid => return MoveResult::Invalid,
-            MoveResult::RedWin => return MoveResult::RedWin,
-            MoveResult::GreenWin => return MoveResult::GreenWin,
+            scratch.unmake_move(from, to, captured);
         }
+        destinations
+    }
+
+    /// Prints the board with `RichRenderer`, in color if stdout is a
+    /// terminal.
+    #[cfg(feature = "term")]
+    pub fn show(&self) {
+        self.show_from(Player::Red)
+    }
+
+    /// Like `show`, but seen from `perspective`'s side of the board.
+    #[cfg(feature = "term")]
+    pub fn show_from(&self, perspective: Player) {
+        self.print(RichRenderer {
+            flipped: perspective == Player::Green,
+            selected: None,
+        })
+    }
+
+    /// Like `show_from`, with the legal destinations of the piece on
+    /// `square` highlighted.
+    #[cfg(feature = "term")]
+    pub fn show_destinations(&self, perspective: Player, square: [usize; 2]) {
+        self.print(RichRenderer {
+            flipped: perspective == Player::Green,
+            selected: Some(square),
+        })
+    }
+
+    #[cfg(feature = "term")]
+    fn print(&self, renderer: RichRenderer) {
+        let choice = if io::stdout().is_terminal() {
+            ColorChoice::Auto
+        } else {
+            ColorChoice::Never
+        };
+        let mut out = StandardStream::stdout(choice);
+        let _ = renderer.render_color(self, &mut out);
+    }
+
+    /// Checks that `turn` may move the piece on `from` to `to`.
+    pub fn verify(&self, turn: Player, from: [usize; 2], to: [usize; 2]) -> Result<(), MoveError> {
+        for square in [from, to] {
+            if square[0] >= 10 || square[1] >= 9 {
+                return Err(MoveError::OutOfBounds(square));
+            }
+        }
+        // a move that passes is itself proof the game goes on, so the legal
+        // moves are only generated to explain a rejected one
+        match self.verify_on_board(turn, from, to) {
+            Err(..) if self.legal_moves().is_empty() => Err(MoveError::GameOver),
+            result => result,
+        }
+    }
+
+    fn verify_on_board(
+        &self,
+        turn: Player,
+        from: [usize; 2],
+        to: [usize; 2],
+    ) -> Result<(), MoveError> {
+        if turn != self.turn {
+            return Err(MoveError::NotYourTurn(turn));
+        }
+        let piece = self.board[from[0]][from[1]];
+        if let PieceType::None = piece.piecetype {
+            return Err(MoveError::NoPieceOnSquare(from));
+        }
+        if piece.player != turn {
+            return Err(MoveError::NotYourPiece(from));
+        }
+        let targets = self
+            .bits
+            .destinations(piece.player, piece.piecetype, from[0] * 9 + from[1]);
+        if targets & bit(to[0] * 9 + to[1]) == 0 {
+            return Err(MoveError::IllegalDestination(to));
+        }
+        let mut scratch = self.scratch(self.turn);
+        scratch.make_move(from, to);
+        if scratch.generals_facing() {
+            return Err(MoveError::FlyingGeneral);
+        }
+        if scratch.in_check(turn) {
+            return Err(MoveError::LeavesGeneralInCheck);
+        }
+        Ok(())
+    }
+
+    /// Verifies and plays a move, recording it in `history`. The mover wins
+    /// if the opponent is left without a legal move.
+    pub fn move_piece(
+        &mut self,
+        turn: Player,
+        from: [usize; 2],
+        to: [usize; 2],
+    ) -> Result<MoveResult, MoveError> {
+        self.verify(turn, from, to)?;
+        let piece = self.board[from[0]][from[1]].piecetype;
+        let captured = self.make_move(from, to).piecetype;
+        self.history.push(Move::new(from, to).with_pieces(piece, captured));
+        if !self.legal_moves().is_empty() {
+            Ok(MoveResult::Valid)
+        } else if turn == Player::Red {
+            Ok(MoveResult::RedWin)
+        } else {
+            Ok(MoveResult::GreenWin)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::to_iccs;
+
+    #[test]
+    fn legal_captures() {
+        let board = Board::new();
+        let mut captures = board.legal_captures(false).into_iter().map(to_iccs).collect::<Vec<_>>();
+        captures.sort();
+        assert_eq!(captures, ["b2b9", "h2h9"]);
+
+        let mut board = Board::empty();
+        board.put([9, 5], Piece::new(PieceType::Shuai, Player::Red));
+        board.put([0, 3], Piece::new(PieceType::Shuai, Player::Green));
+        board.put([5, 0], Piece::new(PieceType::Ju, Player::Red));
+        board.put([5, 6], Piece::new(PieceType::Bing, Player::Green));
+        let captures = board.legal_captures(false);
+        assert_eq!(captures.into_iter().map(to_iccs).collect::<Vec<_>>(), ["a4g4"]);
+        let mut checks = board.legal_captures(true).into_iter().map(to_iccs).collect::<Vec<_>>();
+        checks.sort();
+        assert_eq!(checks, ["a4a9", "a4d4", "a4g4"]);
+    }
+
+    #[test]
+    fn piece_rules() {
+        let possible = |board: &Board, square: [usize; 2]| {
+            let piece = board.board[square[0]][square[1]];
+            let mut squares = piece.possible(board, square[0], square[1]);
+            squares.sort();
+            squares
+        };
+        assert_eq!(Board::new().legal_moves().len(), 44);
+
+        let mut board = Board::empty();
+        board.put([9, 4], Piece::new(PieceType::Shuai, Player::Red));
+        board.put([0, 3], Piece::new(PieceType::Shuai, Player::Green));
+        // a Xiang on the river bank cannot cross it, and a piece on its eye
+        // blocks it
+        board.put([5, 2], Piece::new(PieceType::Xiang, Player::Red));
+        board.put([6, 3], Piece::new(PieceType::Bing, Player::Red));
+        assert_eq!(possible(&board, [5, 2]), [[7, 0]]);
+        board.put([4, 2], Piece::new(PieceType::Xiang, Player::Green));
+        assert_eq!(possible(&board, [4, 2]), [[2, 0], [2, 4]]);
+        // a Ma is blocked by a piece on its leg
+        board.put([7, 7], Piece::new(PieceType::Ma, Player::Red));
+        board.put([8, 7], Piece::new(PieceType::Bing, Player::Red));
+        assert_eq!(
+            possible(&board, [7, 7]),
+            [[5, 6], [5, 8], [6, 5], [8, 5]]
+        );
+        // a Shi stays on the palace diagonals
+        board.put([8, 4], Piece::new(PieceType::Shi, Player::Red));
+        assert_eq!(
+            possible(&board, [8, 4]),
+            [[7, 3], [7, 5], [9, 3], [9, 5]]
+        );
+        // a Bing only steps sideways once across the river
+        assert_eq!(possible(&board, [6, 3]), [[5, 3]]);
+        board.put([4, 6], Piece::new(PieceType::Bing, Player::Red));
+        assert_eq!(possible(&board, [4, 6]), [[3, 6], [4, 5], [4, 7]]);
+        // the general stays in the palace
+        assert_eq!(possible(&board, [0, 3]), [[0, 4], [1, 3]]);
+    }
+
+    #[test]
+    fn cannon_destinations() {
+        let board = Board::new();
+        let mut destinations = board.destinations([7, 7]);
+        destinations.sort();
+        // h2 slides along its rank and file, and jumps the cannon on h7 to take
+        // the knight on h9.
+        assert_eq!(destinations.len(), 12);
+        assert!(destinations.contains(&([0, 7], true)));
+        assert!(destinations.contains(&([7, 4], false)));
+        assert!(!destinations.iter().any(|&(to, _)| to == [9, 7]));
+        assert!(board.destinations([4, 4]).is_empty());
+    }
+
+    #[test]
+    fn fen() {
+        let mut board = Board::new();
+        assert_eq!(
+            board.fen(),
+            "rnbakabnr/9/1c5c1/p1p1p1p1p/9/9/P1P1P1P1P/1C5C1/9/RNBAKABNR w - - 0 1"
+        );
+        board.move_piece(Player::Red, [7, 7], [7, 4]).unwrap();
+        board.move_piece(Player::Green, [0, 7], [2, 6]).unwrap();
+        assert_eq!(
+            board.fen(),
+            "rnbakab1r/9/1c4nc1/p1p1p1p1p/9/9/P1P1P1P1P/1C2C4/9/RNBAKABNR w - - 0 2"
+        );
+
+        let parsed = Board::from_fen(&board.fen()).unwrap();
+        assert_eq!(parsed.fen(), board.fen().replace(" 0 2", " 0 1"));
+        assert_eq!(parsed.hash(), board.hash());
+        assert_eq!(Board::from_fen("rnbakabnr/9/1c5c1 w").map(|b| b.fen()), None);
+        assert_eq!(Board::from_fen("4k4/9/9/9/9/9/9/9/9/4K4 x").map(|b| b.fen()), None);
+        assert_eq!(
+            Board::from_fen("3k5/9/9/9/9/9/9/9/9/4K4 b").map(|b| b.fen()),
+            Some("3k5/9/9/9/9/9/9/9/9/4K4 b - - 0 1".to_string())
+        );
+    }
+
+    #[test]
+    fn move_errors() {
+        let mut board = Board::new();
+        assert_eq!(
+            board.move_piece(Player::Red, [10, 0], [9, 0]),
+            Err(MoveError::OutOfBounds([10, 0]))
+        );
+        assert_eq!(
+            board.move_piece(Player::Green, [3, 0], [4, 0]),
+            Err(MoveError::NotYourTurn(Player::Green))
+        );
+        assert_eq!(
+            board.move_piece(Player::Red, [5, 0], [4, 0]),
+            Err(MoveError::NoPieceOnSquare([5, 0]))
+        );
+        assert_eq!(
+            board.move_piece(Player::Red, [3, 0], [4, 0]),
+            Err(MoveError::NotYourPiece([3, 0]))
+        );
+        assert_eq!(
+            board.move_piece(Player::Red, [9, 0], [5, 1]),
+            Err(MoveError::IllegalDestination([5, 1]))
+        );
+
+        // Red's general and Green's rook on the e file, with a Red cannon between.
+        let mut board = Board::empty();
+        board.put([9, 4], Piece::new(PieceType::Shuai, Player::Red));
+        board.put([0, 3], Piece::new(PieceType::Shuai, Player::Green));
+        board.put([5, 4], Piece::new(PieceType::Pao, Player::Red));
+        board.put([2, 4], Piece::new(PieceType::Ju, Player::Green));
+        assert_eq!(
+            board.move_piece(Player::Red, [5, 4], [5, 0]),
+            Err(MoveError::LeavesGeneralInCheck)
+        );
+        assert_eq!(
+            board.move_piece(Player::Red, [9, 4], [9, 3]),
+            Err(MoveError::FlyingGeneral)
+        );
+
+        // Two rooks mate the lone Green general; nothing can be played after.
+        let mut board = Board::empty();
+        board.put([9, 3], Piece::new(PieceType::Shuai, Player::Red));
+        board.put([0, 4], Piece::new(PieceType::Shuai, Player::Green));
+        board.put([1, 8], Piece::new(PieceType::Ju, Player::Red));
+        board.put([5, 0], Piece::new(PieceType::Ju, Player::Red));
+        assert_eq!(
+            board.move_piece(Player::Red, [5, 0], [0, 0]),
+            Ok(MoveResult::RedWin)
+        );
+        assert_eq!(
+            board.move_piece(Player::Green, [0, 4], [0, 5]),
+            Err(MoveError::GameOver)
+        );
+        assert_eq!(
+            board.move_piece(Player::Red, [1, 8], [2, 8]),
+            Err(MoveError::GameOver)
+        );
     }
 }
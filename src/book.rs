@@ -0,0 +1,229 @@
+use crate::board::{Board, Move, Player};
+use crate::record::{Outcome, Record};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"QIBK";
+const ENTRY_SIZE: usize = 12;
+
+/// Only the first this many plies of each game go into the book.
+pub const DEFAULT_MAX_PLY: usize = 30;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    hash: u64,
+    mv: u16,
+    weight: u16,
+}
+
+/// Packs a move into `from * 90 + to`, with squares numbered `row * 9 + col`.
+fn pack(mv: Move) -> u16 {
+    (mv.from_square() * 90 + mv.to_square()) as u16
+}
+
+fn unpack(mv: u16) -> Move {
+    Move::from_squares(mv as usize / 90, mv as usize % 90)
+}
+
+/// An opening book: candidate moves and their weights for each position,
+/// keyed by Zobrist hash.
+///
+/// On disk it is the magic `QIBK` followed by 12-byte little-endian entries
+/// (`u64` hash, `u16` packed move, `u16` weight) sorted by hash, so lookups
+/// are a binary search.
+#[derive(Clone, Debug, Default)]
+pub struct Book {
+    entries: Vec<Entry>,
+}
+
+impl Book {
+    /// Builds a book from the first `max_ply` plies of `records`, each
+    /// replayed from its `FEN` header if it has one. Each time a move is
+    /// played it scores 3 if the side playing it went on to win, 1 if it
+    /// lost and 2 otherwise. Games stop contributing at their first illegal
+    /// move.
+    pub fn build(records: &[Record], max_ply: usize) -> Book {
+        let mut weights: HashMap<(u64, u16), u32> = HashMap::new();
+        for record in records {
+            let mut ply = 0;
+            // a game that goes wrong still counts up to the illegal move
+            let _ = record.replay_with(|board, mv| {
+                if ply < max_ply {
+                    let score = match (record.outcome, board.turn) {
+                        (Outcome::RedWin, Player::Red) | (Outcome::GreenWin, Player::Green) => 3,
+                        (Outcome::RedWin, _) | (Outcome::GreenWin, _) => 1,
+                        _ => 2,
+                    };
+                    *weights.entry((board.hash(), pack(mv))).or_insert(0) += score;
+                }
+                ply += 1;
+            });
+        }
+        let mut entries = weights
+            .into_iter()
+            .map(|((hash, mv), weight)| Entry {
+                hash,
+                mv,
+                weight: weight.min(u16::MAX as u32) as u16,
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|e| (e.hash, e.mv));
+        Book { entries }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Book> {
+        Book::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Book> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            bail!("not an opening book");
+        }
+        let body = &bytes[MAGIC.len()..];
+        if !body.len().is_multiple_of(ENTRY_SIZE) {
+            bail!("truncated opening book");
+        }
+        let mut entries = body
+            .chunks(ENTRY_SIZE)
+            .map(|c| Entry {
+                hash: u64::from_le_bytes([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]]),
+                mv: u16::from_le_bytes([c[8], c[9]]),
+                weight: u16::from_le_bytes([c[10], c[11]]),
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|e| (e.hash, e.mv));
+        Ok(Book { entries })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MAGIC.len() + self.entries.len() * ENTRY_SIZE);
+        bytes.extend_from_slice(MAGIC);
+        for e in &self.entries {
+            bytes.extend_from_slice(&e.hash.to_le_bytes());
+            bytes.extend_from_slice(&e.mv.to_le_bytes());
+            bytes.extend_from_slice(&e.weight.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    /// Book moves for `board` that are legal there, heaviest first.
+    pub fn probe(&self, board: &Board) -> Vec<(Move, u16)> {
+        let hash = board.hash();
+        let start = self.entries.partition_point(|e| e.hash < hash);
+        let legal = board.legal_moves();
+        let mut moves = self.entries[start..]
+            .iter()
+            .take_while(|e| e.hash == hash)
+            .map(|e| (unpack(e.mv), e.weight))
+            .filter(|(mv, _)| legal.contains(mv))
+            .collect::<Vec<_>>();
+        moves.sort_by_key(|&(_, weight)| std::cmp::Reverse(weight));
+        moves
+    }
+
+    /// Chooses a book move at random in proportion to the weights, using
+    /// `seed` as the source of randomness.
+    pub fn pick(&self, board: &Board, seed: u64) -> Option<Move> {
+        let moves = self.probe(board);
+        let total = moves.iter().map(|&(_, w)| w as u64).sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+        let mut target = seed % total;
+        for (mv, weight) in moves {
+            if target < weight as u64 {
+                return Some(mv);
+            }
+            target -= weight as u64;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::parse_iccs;
+
+    fn record(moves: &str, outcome: Outcome) -> Record {
+        Record {
+            headers: vec![],
+            moves: moves
+                .split_whitespace()
+                .map(|m| parse_iccs(m).unwrap())
+                .collect(),
+            outcome,
+        }
+    }
+
+    #[test]
+    fn builds_and_picks() {
+        let records = [
+            record("h2e2 h9g7 h0g2", Outcome::RedWin),
+            record("h2e2 b9c7", Outcome::GreenWin),
+            record("b2e2 h9g7", Outcome::Draw),
+            // the illegal second move ends this game's contribution
+            record("c3c4 c3c4", Outcome::Draw),
+        ];
+        let book = Book::build(&records, 2);
+        assert_eq!(book.len(), 6);
+        let h2e2 = parse_iccs("h2e2").unwrap();
+        let (b2e2, c3c4) = (parse_iccs("b2e2").unwrap(), parse_iccs("c3c4").unwrap());
+        let board = Board::new();
+        assert_eq!(book.probe(&board), [(h2e2, 4), (c3c4, 2), (b2e2, 2)]);
+        // seeds below 4 fall on h2e2, then two each on c3c4 and b2e2
+        assert_eq!(book.pick(&board, 3), Some(h2e2));
+        assert_eq!(book.pick(&board, 4), Some(c3c4));
+        assert_eq!(book.pick(&board, 8 + 7), Some(b2e2));
+
+        let mut board = Board::new();
+        board.make_move(h2e2.from(), h2e2.to());
+        let mut moves = book.probe(&board);
+        moves.sort_by_key(|&(mv, _)| mv.to_square());
+        let (h9g7, b9c7) = (parse_iccs("h9g7").unwrap(), parse_iccs("b9c7").unwrap());
+        assert_eq!(moves, [(b9c7, 3), (h9g7, 1)]);
+        // h0g2 is past the book's two plies
+        board.make_move(h9g7.from(), h9g7.to());
+        assert_eq!(book.pick(&board, 0), None);
+    }
+
+    #[test]
+    fn round_trips() {
+        let book = Book::build(&[record("h2e2 h9g7", Outcome::RedWin)], DEFAULT_MAX_PLY);
+        let bytes = book.to_bytes();
+        assert_eq!(bytes.len(), 4 + 2 * ENTRY_SIZE);
+        let loaded = Book::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.entries, book.entries);
+        assert!(Book::from_bytes(b"QIBX").is_err());
+        assert!(Book::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn starts_from_the_fen_header() {
+        let h2e2 = parse_iccs("h2e2").unwrap();
+        let mut board = Board::new();
+        board.make_move(h2e2.from(), h2e2.to());
+        board.history.push(h2e2);
+        let mut game = record("h9g7 h0g2", Outcome::Draw);
+        game.set_header("FEN", &board.fen());
+        let book = Book::build(&[game], DEFAULT_MAX_PLY);
+        assert_eq!(book.len(), 2);
+        assert_eq!(book.probe(&board), [(parse_iccs("h9g7").unwrap(), 2)]);
+        assert!(book.probe(&Board::new()).is_empty());
+    }
+}
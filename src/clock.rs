@@ -0,0 +1,372 @@
+use crate::board::Player;
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How much thinking time each side gets.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TimeControl {
+    /// A single allotment; running out loses.
+    SuddenDeath { base: Duration },
+    /// `increment` is added after every completed move.
+    Fischer { base: Duration, increment: Duration },
+    /// Once `base` runs out, every move must be made within one `period`.
+    /// Overrunning a period consumes it; running out of periods loses.
+    Byoyomi {
+        base: Duration,
+        period: Duration,
+        periods: u32,
+    },
+}
+
+impl TimeControl {
+    fn base(&self) -> Duration {
+        match *self {
+            TimeControl::SuddenDeath { base }
+            | TimeControl::Fischer { base, .. }
+            | TimeControl::Byoyomi { base, .. } => base,
+        }
+    }
+}
+
+impl FromStr for TimeControl {
+    type Err = anyhow::Error;
+
+    /// Parses `300` (sudden death), `300+5` (Fischer) or `300/30x3`
+    /// (byo-yomi), all in seconds.
+    fn from_str(s: &str) -> Result<TimeControl> {
+        let secs = |t: &str| -> Result<Duration> {
+            t.parse::<u64>()
+                .map(Duration::from_secs)
+                .map_err(|_| anyhow!("invalid time control: {}", s))
+        };
+        if let Some((base, increment)) = s.split_once('+') {
+            Ok(TimeControl::Fischer {
+                base: secs(base)?,
+                increment: secs(increment)?,
+            })
+        } else if let Some((base, rest)) = s.split_once('/') {
+            let (period, periods) = rest
+                .split_once('x')
+                .ok_or_else(|| anyhow!("invalid time control: {}", s))?;
+            let periods = periods
+                .parse()
+                .map_err(|_| anyhow!("invalid time control: {}", s))?;
+            Ok(TimeControl::Byoyomi {
+                base: secs(base)?,
+                period: secs(period)?,
+                periods,
+            })
+        } else {
+            Ok(TimeControl::SuddenDeath { base: secs(s)? })
+        }
+    }
+}
+
+/// A monotonic time source. Only differences between readings matter.
+pub trait TimeSource: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+/// Wall-clock time, used by the REPL.
+pub struct SystemTime {
+    epoch: Instant,
+}
+
+impl SystemTime {
+    pub fn new() -> SystemTime {
+        SystemTime {
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemTime {
+    fn default() -> Self {
+        SystemTime::new()
+    }
+}
+
+impl TimeSource for SystemTime {
+    fn now(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+}
+
+/// A time source that only moves when told to. Clones share the same time,
+/// so a test can keep one handle and give the other to a `Clock`.
+#[derive(Clone, Default)]
+pub struct ManualTime {
+    nanos: Arc<AtomicU64>,
+}
+
+impl ManualTime {
+    pub fn new() -> ManualTime {
+        ManualTime::default()
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.nanos.fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl TimeSource for ManualTime {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct SideClock {
+    remaining: Duration,
+    periods: u32,
+    flagged: bool,
+}
+
+/// A two-sided game clock. Only the side to move has its time running.
+#[derive(Clone)]
+pub struct Clock {
+    control: TimeControl,
+    red: SideClock,
+    green: SideClock,
+    running: Option<Player>,
+    since: Duration,
+    source: Arc<dyn TimeSource>,
+}
+
+impl Clock {
+    pub fn new(control: TimeControl, source: Arc<dyn TimeSource>) -> Clock {
+        let side = SideClock {
+            remaining: control.base(),
+            periods: match control {
+                TimeControl::Byoyomi { periods, .. } => periods,
+                _ => 0,
+            },
+            flagged: false,
+        };
+        Clock {
+            control,
+            red: side,
+            green: side,
+            running: None,
+            since: Duration::ZERO,
+            source,
+        }
+    }
+
+    pub fn control(&self) -> TimeControl {
+        self.control
+    }
+
+    /// Starts `player`'s time.
+    pub fn start(&mut self, player: Player) {
+        self.running = Some(player);
+        self.since = self.source.now();
+    }
+
+    /// Ends the running side's move: charges the time spent, adds any
+    /// increment and starts the opponent's time. Returns the side whose
+    /// flag fell, if any.
+    pub fn press(&mut self) -> Option<Player> {
+        let player = self.running?;
+        let now = self.source.now();
+        let side = self.side_after(player, now - self.since);
+        let side = match self.control {
+            TimeControl::Fischer { increment, .. } if !side.flagged => SideClock {
+                remaining: side.remaining + increment,
+                ..side
+            },
+            _ => side,
+        };
+        *self.side_mut(player) = side;
+        if side.flagged {
+            self.running = None;
+            return Some(player);
+        }
+        self.running = Some(player.opponent());
+        self.since = now;
+        None
+    }
+
+    /// The side whose flag has fallen, checked against the current time.
+    pub fn flag(&self) -> Option<Player> {
+        [Player::Red, Player::Green]
+            .iter()
+            .copied()
+            .find(|&player| self.current(player).flagged)
+    }
+
+    /// Main time left for `player`, including the move in progress.
+    pub fn remaining(&self, player: Player) -> Duration {
+        self.current(player).remaining
+    }
+
+    /// Byo-yomi periods left for `player`.
+    pub fn periods(&self, player: Player) -> u32 {
+        self.current(player).periods
+    }
+
+    fn current(&self, player: Player) -> SideClock {
+        if self.running == Some(player) {
+            self.side_after(player, self.source.now() - self.since)
+        } else {
+            *self.side(player)
+        }
+    }
+
+    fn side(&self, player: Player) -> &SideClock {
+        match player {
+            Player::Green | Player::NoneGreen => &self.green,
+            _ => &self.red,
+        }
+    }
+
+    fn side_mut(&mut self, player: Player) -> &mut SideClock {
+        match player {
+            Player::Green | Player::NoneGreen => &mut self.green,
+            _ => &mut self.red,
+        }
+    }
+
+    /// `player`'s clock once `elapsed` has been spent on the current move.
+    fn side_after(&self, player: Player, elapsed: Duration) -> SideClock {
+        let side = *self.side(player);
+        if elapsed <= side.remaining {
+            return SideClock {
+                remaining: side.remaining - elapsed,
+                ..side
+            };
+        }
+        let over = elapsed - side.remaining;
+        match self.control {
+            TimeControl::Byoyomi { period, .. } if !period.is_zero() => {
+                let used = (over.as_nanos() / period.as_nanos()) as u32;
+                if used >= side.periods {
+                    SideClock {
+                        remaining: Duration::ZERO,
+                        periods: 0,
+                        flagged: true,
+                    }
+                } else {
+                    SideClock {
+                        remaining: Duration::ZERO,
+                        periods: side.periods - used,
+                        flagged: false,
+                    }
+                }
+            }
+            _ => SideClock {
+                remaining: Duration::ZERO,
+                periods: side.periods,
+                flagged: true,
+            },
+        }
+    }
+
+    fn fmt_side(&self, f: &mut fmt::Formatter, player: Player) -> fmt::Result {
+        let side = self.current(player);
+        let secs = side.remaining.as_secs();
+        write!(f, "{:02}:{:02}", secs / 60, secs % 60)?;
+        if let TimeControl::Byoyomi { period, .. } = self.control {
+            write!(f, " ({}x{}s)", side.periods, period.as_secs())?;
+        }
+        if side.flagged {
+            write!(f, " flag")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Red ")?;
+        self.fmt_side(f, Player::Red)?;
+        if self.running == Some(Player::Red) {
+            write!(f, " *")?;
+        }
+        write!(f, " | Green ")?;
+        self.fmt_side(f, Player::Green)?;
+        if self.running == Some(Player::Green) {
+            write!(f, " *")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(spec: &str) -> (Clock, ManualTime) {
+        let time = ManualTime::new();
+        let mut clock = Clock::new(spec.parse().unwrap(), Arc::new(time.clone()));
+        clock.start(Player::Red);
+        (clock, time)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "60".parse::<TimeControl>().unwrap(),
+            TimeControl::SuddenDeath {
+                base: Duration::from_secs(60)
+            }
+        );
+        assert_eq!(
+            "60+2".parse::<TimeControl>().unwrap(),
+            TimeControl::Fischer {
+                base: Duration::from_secs(60),
+                increment: Duration::from_secs(2)
+            }
+        );
+        assert_eq!(
+            "0/30x3".parse::<TimeControl>().unwrap(),
+            TimeControl::Byoyomi {
+                base: Duration::ZERO,
+                period: Duration::from_secs(30),
+                periods: 3
+            }
+        );
+        assert!("1:00".parse::<TimeControl>().is_err());
+    }
+
+    #[test]
+    fn sudden_death() {
+        let (mut clock, time) = clock("10");
+        time.advance(Duration::from_secs(4));
+        assert_eq!(clock.press(), None);
+        assert_eq!(clock.remaining(Player::Red), Duration::from_secs(6));
+        time.advance(Duration::from_secs(11));
+        assert_eq!(clock.flag(), Some(Player::Green));
+        assert_eq!(clock.press(), Some(Player::Green));
+    }
+
+    #[test]
+    fn fischer() {
+        let (mut clock, time) = clock("10+5");
+        time.advance(Duration::from_secs(8));
+        assert_eq!(clock.press(), None);
+        assert_eq!(clock.remaining(Player::Red), Duration::from_secs(7));
+        assert_eq!(clock.remaining(Player::Green), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn byoyomi() {
+        let (mut clock, time) = clock("10/5x2");
+        // Finishing inside the first period keeps both periods.
+        time.advance(Duration::from_secs(14));
+        assert_eq!(clock.press(), None);
+        assert_eq!(clock.periods(Player::Red), 2);
+        assert_eq!(clock.press(), None);
+        // Overrunning one period uses it up.
+        time.advance(Duration::from_secs(7));
+        assert_eq!(clock.press(), None);
+        assert_eq!(clock.periods(Player::Red), 1);
+        assert_eq!(clock.press(), None);
+        time.advance(Duration::from_secs(5));
+        assert_eq!(clock.press(), Some(Player::Red));
+    }
+}
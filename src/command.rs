@@ -0,0 +1,228 @@
+use qi::record::{parse_iccs, parse_square};
+use std::error::Error;
+use std::fmt;
+
+/// A REPL command. Squares are zero-based `[row, col]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Move { from: [usize; 2], to: [usize; 2] },
+    Go,
+    Analyze,
+    Moves { square: [usize; 2] },
+    Flip,
+    Options,
+    Set { name: String, value: String },
+    Book,
+    Save { path: String },
+    Help,
+}
+
+/// Every command with its syntax and what it does, as `help` lists them.
+pub const COMMANDS: &[(&str, &str, &str)] = &[
+    (
+        "move",
+        "move <row> <col> <row> <col> | move <iccs>",
+        "move a piece, e.g. move 8 8 8 5 or move h2e2",
+    ),
+    ("go", "go", "let the engine move"),
+    (
+        "analyze",
+        "analyze",
+        "show the engine's best move and score",
+    ),
+    (
+        "moves",
+        "moves <row> <col> | moves <square>",
+        "show where a piece can move, e.g. moves h2",
+    ),
+    ("flip", "flip", "view the board from the other side"),
+    (
+        "set",
+        "set [<name> <value>]",
+        "list engine options, or set one",
+    ),
+    ("book", "book", "list opening book moves"),
+    ("save", "save <file>", "save the game so far"),
+    ("help", "help", "list commands"),
+];
+
+fn usage(command: &str) -> &'static str {
+    COMMANDS
+        .iter()
+        .find(|&&(name, _, _)| name == command)
+        .map_or("", |&(_, syntax, _)| syntax)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The line was blank.
+    Empty,
+    UnknownCommand(String),
+    /// The command got the wrong number of arguments.
+    Usage(&'static str),
+    NotANumber(String),
+    RowOutOfRange(usize),
+    ColumnOutOfRange(usize),
+    InvalidSquare(String),
+    InvalidMove(String),
+    /// The line was not valid UTF-8.
+    NotUtf8,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "Empty command"),
+            ParseError::UnknownCommand(name) => {
+                write!(f, "Unknown command: {} (type help for a list)", name)
+            }
+            ParseError::Usage(syntax) => write!(f, "Usage: {}", syntax),
+            ParseError::NotANumber(token) => write!(f, "Not a number: {}", token),
+            ParseError::RowOutOfRange(row) => write!(f, "Row {} is not between 1 and 10", row),
+            ParseError::ColumnOutOfRange(col) => {
+                write!(f, "Column {} is not between 1 and 9", col)
+            }
+            ParseError::InvalidSquare(token) => {
+                write!(f, "Not a square: {} (expected e.g. h2)", token)
+            }
+            ParseError::InvalidMove(token) => {
+                write!(f, "Not a move: {} (expected e.g. h2e2)", token)
+            }
+            ParseError::NotUtf8 => write!(f, "Not valid UTF-8"),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// Parses a one-based row and column, as shown around the board.
+fn coordinates(row: &str, col: &str) -> Result<[usize; 2], ParseError> {
+    let number = |token: &str| {
+        token
+            .parse::<usize>()
+            .map_err(|_| ParseError::NotANumber(token.to_string()))
+    };
+    let (row, col) = (number(row)?, number(col)?);
+    if !(1..=10).contains(&row) {
+        return Err(ParseError::RowOutOfRange(row));
+    }
+    if !(1..=9).contains(&col) {
+        return Err(ParseError::ColumnOutOfRange(col));
+    }
+    Ok([row - 1, col - 1])
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, ParseError> {
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        let (&name, args) = tokens.split_first().ok_or(ParseError::Empty)?;
+        let command = match (name, args) {
+            ("move", &[iccs]) => {
+                let mv =
+                    parse_iccs(iccs).ok_or_else(|| ParseError::InvalidMove(iccs.to_string()))?;
+                Command::Move {
+                    from: mv.from(),
+                    to: mv.to(),
+                }
+            }
+            ("move", &[r1, c1, r2, c2]) => Command::Move {
+                from: coordinates(r1, c1)?,
+                to: coordinates(r2, c2)?,
+            },
+            ("go", []) => Command::Go,
+            ("analyze", []) => Command::Analyze,
+            ("moves", &[square]) => Command::Moves {
+                square: parse_square(square)
+                    .ok_or_else(|| ParseError::InvalidSquare(square.to_string()))?,
+            },
+            ("moves", &[row, col]) => Command::Moves {
+                square: coordinates(row, col)?,
+            },
+            ("flip", []) => Command::Flip,
+            ("set", []) => Command::Options,
+            ("set", &[name, value]) => Command::Set {
+                name: name.to_string(),
+                value: value.to_string(),
+            },
+            ("book", []) => Command::Book,
+            ("save", &[path]) => Command::Save {
+                path: path.to_string(),
+            },
+            ("help", []) => Command::Help,
+            _ if COMMANDS.iter().any(|&(command, _, _)| command == name) => {
+                return Err(ParseError::Usage(usage(name)))
+            }
+            _ => return Err(ParseError::UnknownCommand(name.to_string())),
+        };
+        Ok(command)
+    }
+
+    /// Parses a line read as raw bytes, which need not be valid UTF-8.
+    pub fn parse_bytes(line: &[u8]) -> Result<Command, ParseError> {
+        let line = std::str::from_utf8(line).map_err(|_| ParseError::NotUtf8)?;
+        Command::parse(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            Command::parse("move 8 8 8 5"),
+            Ok(Command::Move {
+                from: [7, 7],
+                to: [7, 4]
+            })
+        );
+        assert_eq!(
+            Command::parse("  move h2e2 "),
+            Ok(Command::Move {
+                from: [7, 7],
+                to: [7, 4]
+            })
+        );
+        assert_eq!(
+            Command::parse("moves b9"),
+            Ok(Command::Moves { square: [0, 1] })
+        );
+        assert_eq!(Command::parse("set"), Ok(Command::Options));
+        assert_eq!(Command::parse("help"), Ok(Command::Help));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert_eq!(Command::parse(""), Err(ParseError::Empty));
+        assert_eq!(
+            Command::parse("move a 1 2 3"),
+            Err(ParseError::NotANumber("a".to_string()))
+        );
+        assert_eq!(
+            Command::parse("move 0 1 2 3"),
+            Err(ParseError::RowOutOfRange(0))
+        );
+        assert_eq!(
+            Command::parse("move 1 10 2 3"),
+            Err(ParseError::ColumnOutOfRange(10))
+        );
+        assert_eq!(
+            Command::parse("move 1 2"),
+            Err(ParseError::Usage(usage("move")))
+        );
+        assert_eq!(
+            Command::parse("moves z0"),
+            Err(ParseError::InvalidSquare("z0".to_string()))
+        );
+        assert_eq!(
+            Command::parse("castle"),
+            Err(ParseError::UnknownCommand("castle".to_string()))
+        );
+        assert_eq!(Command::parse_bytes(b"go\r"), Ok(Command::Go));
+        assert_eq!(
+            Command::parse_bytes(b"move h2\xffe2"),
+            Err(ParseError::NotUtf8)
+        );
+    }
+}
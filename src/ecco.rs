@@ -0,0 +1,143 @@
+use crate::board::{Board, Move};
+use crate::record::parse_iccs;
+use std::sync::OnceLock;
+
+/// An entry of the ECCO (Encyclopedia of Chinese Chess Openings) catalogue.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Opening {
+    pub code: &'static str,
+    pub name: &'static str,
+    /// The defining moves, in ICCS notation, for one of the two mirror-image
+    /// versions of the opening.
+    pub line: &'static str,
+}
+
+const fn opening(code: &'static str, name: &'static str, line: &'static str) -> Opening {
+    Opening { code, name, line }
+}
+
+/// The main families of each ECCO volume. Only the top-level lines are
+/// listed, not all five hundred codes.
+pub const OPENINGS: &[Opening] = &[
+    opening("A10", "飞相局", "g0e2"),
+    opening("A40", "起马局", "h0g2"),
+    opening("A50", "仕角炮局", "h2f2"),
+    opening("A60", "过宫炮局", "h2d2"),
+    opening("B00", "中炮局", "h2e2"),
+    opening("B20", "中炮对左三步虎", "h2e2 h9g7 h0g2 h7i7"),
+    opening("B30", "中炮对反宫马", "h2e2 b9c7 h0g2 h7f7"),
+    opening("C00", "中炮对屏风马", "h2e2 h9g7 h0g2 b9c7"),
+    opening(
+        "C20",
+        "中炮过河车对屏风马",
+        "h2e2 h9g7 h0g2 i9h9 i0h0 b9c7 h0h6",
+    ),
+    opening(
+        "C60",
+        "五七炮对屏风马",
+        "h2e2 h9g7 h0g2 i9h9 i0h0 b9c7 b2c2",
+    ),
+    opening("D00", "顺炮缓开车局", "h2e2 h7e7"),
+    opening("D10", "顺炮直车对缓开车", "h2e2 h7e7 h0g2 h9g7 i0h0"),
+    opening("D20", "顺炮直车对横车", "h2e2 h7e7 h0g2 h9g7 i0h0 i9i8"),
+    opening("D50", "中炮对列炮", "h2e2 b7e7"),
+    opening("E00", "仙人指路", "c3c4"),
+    opening("E10", "仙人指路对卒底炮", "c3c4 b7c7"),
+    opening("E40", "对兵局", "c3c4 g6g5"),
+];
+
+/// Hash and length of the position each opening line leads to.
+fn positions() -> &'static [(u64, usize, &'static Opening)] {
+    static POSITIONS: OnceLock<Vec<(u64, usize, &'static Opening)>> = OnceLock::new();
+    POSITIONS.get_or_init(|| {
+        OPENINGS
+            .iter()
+            .map(|opening| {
+                let mut board = Board::new();
+                let mut plies = 0;
+                for mv in opening.line.split_whitespace().filter_map(parse_iccs) {
+                    board.make_move(mv.from(), mv.to());
+                    plies += 1;
+                }
+                (board.hash(), plies, opening)
+            })
+            .collect()
+    })
+}
+
+/// Classifies a game from its moves: the most specific opening whose
+/// position, or its mirror image, was reached after the same number of
+/// moves. Transpositions into a line therefore count.
+pub fn classify(moves: &[Move]) -> Option<&'static Opening> {
+    let positions = positions();
+    let mut board = Board::new();
+    let mut found = None;
+    for (ply, mv) in moves.iter().enumerate() {
+        board.make_move(mv.from(), mv.to());
+        let (hash, mirror) = (board.hash(), board.mirror_hash());
+        if let Some(&(_, _, opening)) = positions
+            .iter()
+            .find(|&&(h, plies, _)| plies == ply + 1 && (h == hash || h == mirror))
+        {
+            found = Some(opening);
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::{Outcome, Record};
+
+    fn moves(line: &str) -> Vec<Move> {
+        line.split_whitespace().filter_map(parse_iccs).collect()
+    }
+
+    fn code(line: &str) -> Option<&'static str> {
+        classify(&moves(line)).map(|opening| opening.code)
+    }
+
+    #[test]
+    fn classifies_lines() {
+        assert_eq!(code("h2e2 h9g7 h0g2 b9c7"), Some("C00"));
+        // the same opening on the other wing, and reached by transposition
+        assert_eq!(code("b2e2 b9c7 b0c2 h9g7"), Some("C00"));
+        assert_eq!(code("h0g2 h9g7 h2e2 b9c7"), Some("C00"));
+        // a later move out of the catalogue keeps the last opening found
+        assert_eq!(code("h2e2 h9g7 h0g2 b9c7 a3a4"), Some("C00"));
+        assert_eq!(code("h2e2 h9g7"), Some("B00"));
+        assert_eq!(code("a3a4"), None);
+        assert_eq!(code(""), None);
+    }
+
+    #[test]
+    fn mirrors_positions() {
+        let mut board = Board::new();
+        assert_eq!(board.mirror_hash(), board.hash());
+        for mv in moves("h2e2 h9g7") {
+            board.make_move(mv.from(), mv.to());
+        }
+        let mut mirror = Board::new();
+        for mv in moves("b2e2 b9c7") {
+            mirror.make_move(mv.from(), mv.to());
+        }
+        assert_eq!(board.mirror_hash(), mirror.hash());
+        assert_ne!(board.hash(), mirror.hash());
+    }
+
+    #[test]
+    fn names_recorded_openings() {
+        let mut board = Board::new();
+        for mv in moves("h2e2 h9g7 h0g2 b9c7") {
+            board.make_move(mv.from(), mv.to());
+            board.history.push(mv);
+        }
+        let record = Record::from_board(&board, Outcome::Unfinished);
+        assert_eq!(record.header("ECCO"), Some("C00"));
+        assert_eq!(record.header("Opening"), Some("中炮对屏风马"));
+        assert!(record.to_string().contains("[ECCO \"C00\"]"));
+        let record = Record::from_board(&Board::new(), Outcome::Unfinished);
+        assert_eq!(record.header("ECCO"), None);
+    }
+}
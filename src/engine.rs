@@ -0,0 +1,1015 @@
+use crate::board::{Board, Move, Piece, PieceType, Player};
+use crate::book::Book;
+use crate::eval::Params;
+use crate::mcts::{self, Tree};
+use crate::moves::MoveList;
+use crate::nnue::{Accumulator, Network};
+use crate::see::see;
+use crate::tablebase::{Probe, Tablebases, Wdl};
+use crate::tt::{Bound, Entry, TranspositionTable};
+use anyhow::{anyhow, bail, Result};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MATE: i32 = 30000;
+const MAX_DEPTH: u32 = 64;
+/// How far below alpha a capture may leave the material, after winning its
+/// victim, before quiescence search skips it.
+const DELTA_MARGIN: i32 = 200;
+/// How far below alpha the static score must be, per ply of depth left, for
+/// quiet moves to be skipped at depths 1 and 2.
+const FUTILITY_MARGIN: i32 = 250;
+/// How far below alpha the static score must be, per ply of depth left, to
+/// drop into quiescence search at depths 1 and 2.
+const RAZOR_MARGIN: i32 = 350;
+/// How much shallower than usual the search after a null move is.
+const NULL_MOVE_REDUCTION: u32 = 2;
+/// Plies from the root that killer moves are kept for.
+const MAX_PLY: usize = 128;
+
+/// The parts of the search that can be turned off one at a time, so that
+/// self-play can measure what each is worth. All but quiescence checks are
+/// on by default.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Switches {
+    /// Let the opponent move twice and prune if that still fails high. Not
+    /// tried in check, or without a Ju, Ma or Pao, where passing could be
+    /// better than any move.
+    pub null_move: bool,
+    /// Search late quiet moves shallower, and again at full depth if they
+    /// turn out to raise alpha.
+    pub late_move_reductions: bool,
+    /// Skip quiet moves near the leaves when the static score is far below
+    /// alpha.
+    pub futility: bool,
+    /// Return the quiescence score near the leaves when the static score is
+    /// far below alpha and quiescence agrees.
+    pub razoring: bool,
+    /// Search one ply deeper when in check.
+    pub check_extensions: bool,
+    /// Try the quiet moves that last caused a cutoff at the same ply early.
+    pub killers: bool,
+    /// Order quiet moves by how often they caused cutoffs anywhere.
+    pub history: bool,
+    /// Also try quiet checks at the first ply of quiescence search.
+    pub quiescence_checks: bool,
+}
+
+impl Default for Switches {
+    fn default() -> Self {
+        Switches {
+            null_move: true,
+            late_move_reductions: true,
+            futility: true,
+            razoring: true,
+            check_extensions: true,
+            killers: true,
+            history: true,
+            quiescence_checks: false,
+        }
+    }
+}
+
+impl Switches {
+    /// The option name of every switch, as `set` takes them.
+    pub fn names() -> [&'static str; 8] {
+        Switches::default().flags().map(|(name, _)| name)
+    }
+
+    /// Every switch with its option name.
+    fn flags(&mut self) -> [(&'static str, &mut bool); 8] {
+        [
+            ("NullMove", &mut self.null_move),
+            ("LMR", &mut self.late_move_reductions),
+            ("Futility", &mut self.futility),
+            ("Razoring", &mut self.razoring),
+            ("CheckExtensions", &mut self.check_extensions),
+            ("Killers", &mut self.killers),
+            ("History", &mut self.history),
+            ("QuiescenceChecks", &mut self.quiescence_checks),
+        ]
+    }
+}
+
+/// Positions searched by `qi bench`, as ICCS moves from the initial position.
+pub const BENCH_LINES: &[&str] = &[
+    "",
+    "h2e2 h9g7 h0g2 i9h9 i0h0 b9c7",
+    "c3c4 b7c7 b0c2 c9e7 h2e2 h9g7",
+    "h2e2 h7e7 h0g2 h9g7 i0h0 i9i8 h0h4 b9c7",
+];
+
+/// The computer player: plays from the opening book while it has moves for
+/// the position, then from the endgame tablebases, and searches otherwise.
+pub struct Engine {
+    pub book: Option<Book>,
+    pub tablebases: Option<Tablebases>,
+    pub depth: u32,
+    /// Search threads. Helpers share the transposition table with the main
+    /// thread; with a single thread and a cleared table the search is
+    /// deterministic.
+    pub threads: usize,
+    pub switches: Switches,
+    /// The evaluation's weights, loaded from `eval_file` if set.
+    pub params: Params,
+    eval_file: Option<String>,
+    /// A network that evaluates in place of `params`, loaded from
+    /// `nnue_file`.
+    pub nnue: Option<Network>,
+    nnue_file: Option<String>,
+    /// Playouts per move for a Monte Carlo tree search instead of
+    /// alpha-beta, or 0 for alpha-beta.
+    pub playouts: usize,
+    /// The last Monte Carlo tree, reused when the next search follows from
+    /// it and it has no more than `tree_limit` nodes.
+    tree: Mutex<Option<Tree>>,
+    tree_limit: usize,
+    hash_mb: usize,
+    tt: TranspositionTable,
+    nodes: AtomicU64,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine::new()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Engine {
+        Engine {
+            book: None,
+            tablebases: None,
+            depth: 3,
+            threads: 1,
+            switches: Switches::default(),
+            params: Params::default(),
+            eval_file: None,
+            nnue: None,
+            nnue_file: None,
+            playouts: 0,
+            tree: Mutex::new(None),
+            tree_limit: mcts::MAX_NODES,
+            hash_mb: 16,
+            tt: TranspositionTable::new(16),
+            nodes: AtomicU64::new(0),
+        }
+    }
+
+    /// Sets an option by name as in `set Threads 4`: `Threads`, `Depth`,
+    /// `Hash` (megabytes), `EvalFile` (a file written by `qi tune`, or
+    /// `none` for the defaults), `NnueFile` (a network, or `none`), or one
+    /// of the `Switches` such as `NullMove` to 0 or 1.
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<()> {
+        if name.eq_ignore_ascii_case("EvalFile") {
+            if value == "none" {
+                self.params = Params::default();
+                self.eval_file = None;
+            } else {
+                self.params = Params::load(value).map_err(|e| anyhow!("{}: {}", value, e))?;
+                self.eval_file = Some(value.to_string());
+            }
+            return Ok(());
+        }
+        if name.eq_ignore_ascii_case("NnueFile") {
+            if value == "none" {
+                self.nnue = None;
+                self.nnue_file = None;
+            } else {
+                self.nnue = Some(Network::load(value).map_err(|e| anyhow!("{}: {}", value, e))?);
+                self.nnue_file = Some(value.to_string());
+            }
+            return Ok(());
+        }
+        let value: usize = value
+            .parse()
+            .map_err(|_| anyhow!("{}: expected a number, got {}", name, value))?;
+        let mut flags = self.switches.flags();
+        if let Some((_, flag)) = flags.iter_mut().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            if value > 1 {
+                bail!("{}: {} is out of range", name, value);
+            }
+            **flag = value == 1;
+            return Ok(());
+        }
+        match name.to_ascii_lowercase().as_str() {
+            "threads" if (1..=256).contains(&value) => self.threads = value,
+            "depth" if (1..=MAX_DEPTH as usize).contains(&value) => self.depth = value as u32,
+            "playouts" if value <= 10_000_000 => self.playouts = value,
+            "hash" if (1..=65536).contains(&value) => {
+                self.hash_mb = value;
+                self.tt = TranspositionTable::new(value);
+            }
+            "threads" | "depth" | "hash" | "playouts" => {
+                bail!("{}: {} is out of range", name, value)
+            }
+            _ => bail!("unknown option {}", name),
+        }
+        Ok(())
+    }
+
+    /// The current value of every option.
+    pub fn options(&self) -> Vec<(&'static str, String)> {
+        let mut options = vec![
+            ("Threads", self.threads.to_string()),
+            ("Depth", self.depth.to_string()),
+            ("Hash", self.hash_mb.to_string()),
+            ("Playouts", self.playouts.to_string()),
+            (
+                "EvalFile",
+                self.eval_file.clone().unwrap_or_else(|| "none".to_string()),
+            ),
+            (
+                "NnueFile",
+                self.nnue_file.clone().unwrap_or_else(|| "none".to_string()),
+            ),
+        ];
+        let mut switches = self.switches;
+        for (name, flag) in switches.flags() {
+            options.push((name, (*flag as u8).to_string()));
+        }
+        options
+    }
+
+    /// Forgets the transposition table and the Monte Carlo tree.
+    pub fn clear_hash(&self) {
+        self.tt.clear();
+        *self.tree.lock().unwrap() = None;
+    }
+
+    /// Nodes visited by all threads during the last search.
+    pub fn nodes(&self) -> u64 {
+        self.nodes.load(Ordering::Relaxed)
+    }
+
+    /// The static score for the side to move on `board`, from the network
+    /// if there is one.
+    pub fn evaluate(&self, board: &Board) -> i32 {
+        match self.nnue.as_ref().and_then(|n| n.evaluate_board(board)) {
+            Some(score) => score,
+            None => self.params.evaluate(board),
+        }
+    }
+
+    pub fn best_move(&self, board: &Board) -> Option<Move> {
+        if let Some(book) = &self.book {
+            let seed = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(0);
+            if let Some(mv) = book.pick(board, seed) {
+                return Some(mv);
+            }
+        }
+        if let Some(mv) = self.tablebase_move(board) {
+            return Some(mv);
+        }
+        self.search(board).map(|(mv, _)| mv)
+    }
+
+    /// The move that wins quickest, or failing that draws, or failing that
+    /// loses slowest, if the tablebases cover the position.
+    pub fn tablebase_move(&self, board: &Board) -> Option<Move> {
+        let tbs = self.tablebases.as_ref()?;
+        tbs.probe(board)?;
+        let mut board = board.clone();
+        let mut best: Option<(Move, i32)> = None;
+        for mv in board.legal_moves() {
+            let captured = board.make_move(mv.from(), mv.to());
+            let score = tbs.probe(&board).map(|p| -probe_score(p, 1));
+            board.unmake_move(mv.from(), mv.to(), captured);
+            let score = score?;
+            if best.is_none_or(|(_, s)| score > s) {
+                best = Some((mv, score));
+            }
+        }
+        best.map(|(mv, _)| mv)
+    }
+
+    /// `playouts` playouts of PUCT search on `threads` threads, guided by
+    /// the static evaluation, continuing the last tree if `board` follows
+    /// from its root and what is left of it is small enough to keep.
+    fn search_mcts(&self, board: &Board) -> Option<(Move, i32)> {
+        let mut slot = self.tree.lock().unwrap();
+        let mut tree = slot
+            .take()
+            .and_then(|mut tree| {
+                (tree.reuse(board) && tree.size() <= self.tree_limit).then_some(tree)
+            })
+            .unwrap_or_else(|| Tree::new(board));
+        tree.search(self, self.playouts, self.threads);
+        self.nodes.store(self.playouts as u64, Ordering::Relaxed);
+        let best = tree.best();
+        *slot = Some(tree);
+        best.map(|(mv, value)| (mv, mcts::engine_score(value)))
+    }
+
+    /// Iterative-deepening alpha-beta search to `depth` using Lazy SMP: the
+    /// helper threads search the same position, starting at staggered
+    /// depths, and only help by filling the shared transposition table.
+    /// Returns the main thread's best move and its score for the side to
+    /// move, or `None` if there are no legal moves. With `playouts` set,
+    /// runs a Monte Carlo tree search instead.
+    pub fn search(&self, board: &Board) -> Option<(Move, i32)> {
+        if self.playouts > 0 {
+            return self.search_mcts(board);
+        }
+        let stop = AtomicBool::new(false);
+        let (result, nodes) = thread::scope(|scope| {
+            let helpers = (1..self.threads)
+                .map(|id| {
+                    let stop = &stop;
+                    scope.spawn(move || {
+                        let mut worker = Worker::new(self, board, id, stop);
+                        worker.iterate(MAX_DEPTH);
+                        worker.nodes
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mut main = Worker::new(self, board, 0, &stop);
+            let result = main.iterate(self.depth);
+            stop.store(true, Ordering::Relaxed);
+            let nodes = helpers
+                .into_iter()
+                .map(|h| h.join().unwrap_or(0))
+                .sum::<u64>();
+            (result, main.nodes + nodes)
+        });
+        self.nodes.store(nodes, Ordering::Relaxed);
+        result
+    }
+}
+
+/// One search thread's private state.
+struct Worker<'a> {
+    engine: &'a Engine,
+    board: Board,
+    id: usize,
+    stop: &'a AtomicBool,
+    nodes: u64,
+    /// The depth of the current iteration.
+    root_depth: u32,
+    /// Two quiet moves per ply that recently caused a cutoff there.
+    killers: [[Option<Move>; 2]; MAX_PLY],
+    /// Cutoffs caused by each quiet move, by origin and destination square,
+    /// weighted by the square of the depth.
+    history: Box<[[i32; 90]; 90]>,
+    /// The engine's network, unless the root lacks a general it needs.
+    network: Option<&'a Network>,
+    /// With a network, its accumulators for each position from the root to
+    /// `current`, the one on the board. Deeper ones are kept to be reused.
+    accumulators: Vec<Accumulator>,
+    current: usize,
+}
+
+impl<'a> Worker<'a> {
+    fn new(engine: &'a Engine, board: &Board, id: usize, stop: &'a AtomicBool) -> Worker<'a> {
+        let with_network = engine
+            .nnue
+            .as_ref()
+            .and_then(|network| Some((network, network.accumulator(board)?)));
+        let (network, accumulators) = match with_network {
+            Some((network, accumulator)) => (Some(network), vec![accumulator]),
+            None => (None, vec![]),
+        };
+        Worker {
+            engine,
+            board: board.clone(),
+            id,
+            stop,
+            nodes: 0,
+            root_depth: 0,
+            killers: [[None; 2]; MAX_PLY],
+            history: Box::new([[0; 90]; 90]),
+            network,
+            accumulators,
+            current: 0,
+        }
+    }
+
+    /// Plays `mv`, updating the network's accumulator if there is one.
+    fn make(&mut self, mv: Move) -> Piece {
+        let captured = self.board.make_move(mv.from(), mv.to());
+        if let Some(network) = self.network {
+            self.current += 1;
+            if self.current == self.accumulators.len() {
+                let parent = self.accumulators[self.current - 1].clone();
+                self.accumulators.push(parent);
+            } else {
+                let (parents, children) = self.accumulators.split_at_mut(self.current);
+                children[0].clone_from(&parents[self.current - 1]);
+            }
+            network.update(
+                &mut self.accumulators[self.current],
+                &self.board,
+                mv,
+                captured,
+            );
+        }
+        captured
+    }
+
+    fn unmake(&mut self, mv: Move, captured: Piece) {
+        self.board.unmake_move(mv.from(), mv.to(), captured);
+        if self.network.is_some() {
+            self.current -= 1;
+        }
+    }
+
+    /// The static score for the side to move, from the network if there is
+    /// one.
+    fn evaluate(&self) -> i32 {
+        match self.network {
+            Some(network) => network.evaluate(&self.accumulators[self.current], self.board.turn),
+            None => self.engine.params.evaluate(&self.board),
+        }
+    }
+
+    /// Searches to increasing depths, keeping the last complete result.
+    /// Odd-numbered helpers skip the first iteration so that the threads
+    /// spread over different depths.
+    fn iterate(&mut self, depth: u32) -> Option<(Move, i32)> {
+        let mut best = None;
+        for d in 1 + (self.id % 2) as u32..=depth {
+            match self.root(d) {
+                Some(result) => best = Some(result),
+                None => break,
+            }
+        }
+        best
+    }
+
+    /// Returns `None` if there are no legal moves or the search was stopped.
+    fn root(&mut self, depth: u32) -> Option<(Move, i32)> {
+        self.root_depth = depth;
+        let hash = self.board.hash();
+        let tt_move = self.engine.tt.probe(hash).and_then(|e| e.mv);
+        let mut best = None;
+        let mut alpha = -MATE - 1;
+        for mv in self.ordered_moves(tt_move, 0) {
+            let captured = self.make(mv);
+            let score = -self.negamax(depth - 1, -MATE - 1, -alpha, 1, true);
+            self.unmake(mv, captured);
+            if self.stopped() {
+                return None;
+            }
+            if score > alpha {
+                alpha = score;
+                best = Some((mv, score));
+            }
+        }
+        if let Some((mv, score)) = best {
+            self.engine.tt.store(
+                hash,
+                Entry {
+                    mv: Some(mv),
+                    score,
+                    depth,
+                    bound: Bound::Exact,
+                },
+            );
+        }
+        best
+    }
+
+    fn stopped(&self) -> bool {
+        self.id != 0 && self.stop.load(Ordering::Relaxed)
+    }
+
+    /// Alpha-beta search `depth` plies deep, `ply` plies from the root. A
+    /// null move is only tried if `null` allows it, which it does not right
+    /// after another one.
+    fn negamax(&mut self, mut depth: u32, mut alpha: i32, beta: i32, ply: i32, null: bool) -> i32 {
+        self.nodes += 1;
+        if self.stopped() {
+            return 0;
+        }
+        if let Some(tbs) = &self.engine.tablebases {
+            // counting the pieces is far cheaper than working out the material
+            if self.board.bitboards().occupied().count_ones() as usize <= tbs.max_pieces() {
+                if let Some(probe) = tbs.probe(&self.board) {
+                    return probe_score(probe, ply);
+                }
+            }
+        }
+        let switches = self.engine.switches;
+        let in_check = self.board.in_check(self.board.turn);
+        // perpetual checks would otherwise be extended forever
+        if in_check && switches.check_extensions && ply < 2 * self.root_depth as i32 {
+            depth += 1;
+        }
+        if depth == 0 {
+            return self.quiesce(alpha, beta, ply, 0);
+        }
+        let hash = self.board.hash();
+        let entry = self.engine.tt.probe(hash);
+        if let Some(entry) = entry.filter(|e| e.depth >= depth) {
+            let score = from_tt(entry.score, ply);
+            match entry.bound {
+                Bound::Exact => return score,
+                Bound::Lower if score >= beta => return score,
+                Bound::Upper if score <= alpha => return score,
+                _ => {}
+            }
+        }
+        let eval = self.evaluate();
+        if !in_check {
+            if switches.razoring && depth <= 2 && eval + RAZOR_MARGIN * (depth as i32) <= alpha {
+                let score = self.quiesce(alpha, alpha + 1, ply, 0);
+                if score <= alpha {
+                    return score;
+                }
+            }
+            if switches.null_move
+                && null
+                && depth > NULL_MOVE_REDUCTION
+                && eval >= beta
+                && beta < MATE - 1000
+                && self.has_attackers()
+            {
+                self.board.pass();
+                let score = -self.negamax(
+                    depth - 1 - NULL_MOVE_REDUCTION,
+                    -beta,
+                    -beta + 1,
+                    ply + 1,
+                    false,
+                );
+                self.board.pass();
+                if self.stopped() {
+                    return 0;
+                }
+                if score >= beta {
+                    // a mate found after passing proves nothing
+                    return score.min(MATE - 1000);
+                }
+            }
+        }
+        let moves = self.ordered_moves(entry.and_then(|e| e.mv), ply);
+        if moves.is_empty() {
+            // being stalemated loses in xiangqi, just like being mated
+            return -MATE + ply;
+        }
+        let futile = switches.futility
+            && !in_check
+            && depth <= 2
+            && eval + FUTILITY_MARGIN * (depth as i32) <= alpha;
+        let original_alpha = alpha;
+        let mut best = (-MATE - 1, None);
+        for (i, mv) in moves.into_iter().enumerate() {
+            let quiet = !mv.is_capture();
+            let captured = self.make(mv);
+            let gives_check = quiet && self.board.in_check(self.board.turn);
+            if futile && quiet && !gives_check && i > 0 {
+                self.unmake(mv, captured);
+                continue;
+            }
+            let reduction = if switches.late_move_reductions
+                && depth >= 3
+                && i >= 3
+                && quiet
+                && !in_check
+                && !gives_check
+                && !self.killers(ply).contains(&Some(mv))
+            {
+                if i >= 6 {
+                    2
+                } else {
+                    1
+                }
+            } else {
+                0
+            };
+            // principal variation search: the first move gets the full
+            // window, the rest a null window to show they are no better,
+            // searched again in full only if they turn out to be
+            let score = if i == 0 {
+                -self.negamax(depth - 1, -beta, -alpha, ply + 1, true)
+            } else {
+                let mut score =
+                    -self.negamax(depth - 1 - reduction, -alpha - 1, -alpha, ply + 1, true);
+                if reduction > 0 && score > alpha {
+                    score = -self.negamax(depth - 1, -alpha - 1, -alpha, ply + 1, true);
+                }
+                if score > alpha && score < beta {
+                    score = -self.negamax(depth - 1, -beta, -alpha, ply + 1, true);
+                }
+                score
+            };
+            self.unmake(mv, captured);
+            if self.stopped() {
+                return 0;
+            }
+            if score > best.0 {
+                best = (score, Some(mv));
+            }
+            if score >= beta {
+                if quiet {
+                    self.cutoff(mv, depth, ply);
+                }
+                break;
+            }
+            alpha = alpha.max(score);
+        }
+        let bound = if best.0 >= beta {
+            Bound::Lower
+        } else if best.0 > original_alpha {
+            Bound::Exact
+        } else {
+            Bound::Upper
+        };
+        self.engine.tt.store(
+            hash,
+            Entry {
+                mv: best.1,
+                score: to_tt(best.0, ply),
+                depth,
+                bound,
+            },
+        );
+        best.0
+    }
+
+    /// Whether the side to move has a piece that can attack, a Ju, Ma or
+    /// Pao. Without one, passing is often better than any move, so a null
+    /// move proves nothing.
+    fn has_attackers(&self) -> bool {
+        let bits = self.board.bitboards();
+        [PieceType::Ju, PieceType::Ma, PieceType::Pao]
+            .iter()
+            .any(|&piecetype| bits.pieces(self.board.turn, piecetype) != 0)
+    }
+
+    /// The killer moves at `ply`, if killers are switched on.
+    fn killers(&self, ply: i32) -> [Option<Move>; 2] {
+        match self.killers.get(ply as usize) {
+            Some(&killers) if self.engine.switches.killers => killers,
+            _ => [None; 2],
+        }
+    }
+
+    /// Remembers that the quiet move `mv` caused a cutoff.
+    fn cutoff(&mut self, mv: Move, depth: u32, ply: i32) {
+        if let Some(killers) = self.killers.get_mut(ply as usize) {
+            if killers[0] != Some(mv) {
+                killers[1] = killers[0];
+                killers[0] = Some(mv);
+            }
+        }
+        let count = &mut self.history[mv.from_square()][mv.to_square()];
+        *count = count.saturating_add((depth * depth) as i32);
+    }
+
+    /// Legal moves with the transposition table's move first, then captures
+    /// that do not lose material in MVV-LVA order, the killers, the other
+    /// quiet moves by history and last the losing captures.
+    fn ordered_moves(&self, tt_move: Option<Move>, ply: i32) -> MoveList {
+        let board = &self.board;
+        let killers = self.killers(ply);
+        let history = self.engine.switches.history;
+        let mut moves = board.legal_moves();
+        moves.sort_by_cached_key(|&mv| {
+            if Some(mv) == tt_move {
+                (0, 0)
+            } else if mv.is_capture() {
+                let tier = if see(board, mv) < 0 { 4 } else { 1 };
+                (tier, -mvv_lva(mv, board.turn))
+            } else if let Some(i) = killers.iter().position(|&k| k == Some(mv)) {
+                (2, i as i32)
+            } else if history {
+                (3, -self.history[mv.from_square()][mv.to_square()])
+            } else {
+                (3, 0)
+            }
+        });
+        moves
+    }
+
+    /// Searches captures only, from `qply` plies into quiescence, until the
+    /// position is quiet enough to trust the static evaluation. The side to
+    /// move may stand pat on the static score unless it is in check, when
+    /// every evasion is searched instead. Captures are tried in MVV-LVA
+    /// order, and skipped when even winning the victim cannot lift the score
+    /// to alpha or when they lose material in the exchange that follows.
+    fn quiesce(&mut self, mut alpha: i32, beta: i32, ply: i32, qply: u32) -> i32 {
+        if qply > 0 {
+            self.nodes += 1;
+        }
+        if self.stopped() {
+            return 0;
+        }
+        let in_check = self.board.in_check(self.board.turn);
+        let stand_pat = self.evaluate();
+        let mut moves = if in_check {
+            self.board.legal_moves()
+        } else {
+            if stand_pat >= beta {
+                return stand_pat;
+            }
+            alpha = alpha.max(stand_pat);
+            let checks = self.engine.switches.quiescence_checks && qply == 0;
+            self.board.legal_captures(checks)
+        };
+        if in_check && moves.is_empty() {
+            return -MATE + ply;
+        }
+        let turn = self.board.turn;
+        moves.sort_by_key(|&mv| -mvv_lva(mv, turn));
+        let mut best = if in_check { -MATE - 1 } else { stand_pat };
+        for mv in moves {
+            if !in_check
+                && mv.is_capture()
+                && (stand_pat + value(mv.captured(), mv.to(), turn.opponent()) + DELTA_MARGIN
+                    < alpha
+                    || see(&self.board, mv) < 0)
+            {
+                continue;
+            }
+            let captured = self.make(mv);
+            let score = -self.quiesce(-beta, -alpha, ply + 1, qply + 1);
+            self.unmake(mv, captured);
+            if self.stopped() {
+                return 0;
+            }
+            best = best.max(score);
+            if score >= beta {
+                break;
+            }
+            alpha = alpha.max(score);
+        }
+        best
+    }
+}
+
+/// Mate scores are stored relative to the node rather than the root.
+fn to_tt(score: i32, ply: i32) -> i32 {
+    if score > MATE - 1000 {
+        score + ply
+    } else if score < -MATE + 1000 {
+        score - ply
+    } else {
+        score
+    }
+}
+
+fn from_tt(score: i32, ply: i32) -> i32 {
+    if score > MATE - 1000 {
+        score - ply
+    } else if score < -MATE + 1000 {
+        score + ply
+    } else {
+        score
+    }
+}
+
+/// A tablebase result as a search score `ply` plies from the root.
+fn probe_score(probe: Probe, ply: i32) -> i32 {
+    match probe.wdl {
+        Wdl::Win => MATE - ply - probe.plies as i32,
+        Wdl::Draw => 0,
+        Wdl::Loss => -MATE + ply + probe.plies as i32,
+    }
+}
+
+/// Most valuable victim, least valuable attacker: orders captures by what
+/// they win, then by what they risk. The general, worth nothing in
+/// `value`, attacks last.
+fn mvv_lva(mv: Move, player: Player) -> i32 {
+    let attacker = match mv.piece() {
+        PieceType::Shuai => 1000,
+        piece => value(piece, mv.from(), player),
+    };
+    value(mv.captured(), mv.to(), player.opponent()) * 1024 - attacker
+}
+
+/// Material balance from the point of view of the side to move, with the
+/// default `Params`. `Engine::evaluate` uses the engine's own weights or
+/// network instead.
+pub fn evaluate(board: &Board) -> i32 {
+    Params::default().evaluate(board)
+}
+
+pub(crate) fn value(kind: PieceType, pos: [usize; 2], player: Player) -> i32 {
+    match kind {
+        PieceType::None | PieceType::Shuai => 0,
+        PieceType::Shi | PieceType::Xiang => 120,
+        PieceType::Ma => 270,
+        PieceType::Pao => 285,
+        PieceType::Ju => 600,
+        PieceType::Bing => {
+            let crossed = match player {
+                Player::Red => pos[0] <= 4,
+                _ => pos[0] >= 5,
+            };
+            if crossed {
+                60
+            } else {
+                30
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_thread_is_deterministic() {
+        let mut engine = Engine::new();
+        engine.set_option("Depth", "3").unwrap();
+        let board = Board::new();
+        let first = engine.search(&board);
+        let nodes = engine.nodes();
+        engine.clear_hash();
+        assert_eq!(engine.search(&board), first);
+        assert_eq!(engine.nodes(), nodes);
+    }
+
+    #[test]
+    fn evaluates_material() {
+        let mut board = Board::new();
+        assert_eq!(evaluate(&board), 0);
+        board.make_move([7, 7], [0, 7]);
+        assert_eq!(evaluate(&board), -270);
+        assert_eq!(Engine::new().evaluate(&board), evaluate(&board));
+    }
+
+    #[test]
+    fn quiescence_sees_the_recapture() {
+        use crate::board::Piece;
+
+        // Red's rook can take a pawn that Green's rook defends.
+        let mut board = Board::empty();
+        board.put([9, 5], Piece::new(PieceType::Shuai, Player::Red));
+        board.put([0, 3], Piece::new(PieceType::Shuai, Player::Green));
+        board.put([5, 0], Piece::new(PieceType::Ju, Player::Red));
+        board.put([5, 6], Piece::new(PieceType::Bing, Player::Green));
+        board.put([8, 6], Piece::new(PieceType::Ju, Player::Green));
+        let mut engine = Engine::new();
+        engine.set_option("Depth", "1").unwrap();
+        let (mv, score) = engine.search(&board).unwrap();
+        assert_ne!(mv, Move::new([5, 0], [5, 6]));
+        assert_eq!(score, -60);
+
+        // Undefended, the pawn is simply won.
+        board.put([8, 6], Piece::new(PieceType::None, Player::NoneRed));
+        engine.clear_hash();
+        assert_eq!(
+            engine.search(&board),
+            Some((Move::new([5, 0], [5, 6]), 600))
+        );
+    }
+
+    #[test]
+    fn every_switch_finds_mate() {
+        use crate::board::Piece;
+
+        // Red's rooks mate on the ninth rank.
+        let mut board = Board::empty();
+        board.put([9, 5], Piece::new(PieceType::Shuai, Player::Red));
+        board.put([0, 3], Piece::new(PieceType::Shuai, Player::Green));
+        board.put([1, 0], Piece::new(PieceType::Ju, Player::Red));
+        board.put([5, 1], Piece::new(PieceType::Ju, Player::Red));
+        board.put([3, 6], Piece::new(PieceType::Ma, Player::Green));
+        let mut engine = Engine::new();
+        engine.set_option("Depth", "4").unwrap();
+        let option = |engine: &Engine, name| {
+            let mut options = engine.options().into_iter();
+            options.find(|&(n, _)| n == name).unwrap().1
+        };
+        for name in Switches::names() {
+            let value = option(&engine, name);
+            let flipped = if value == "1" { "0" } else { "1" };
+            engine.set_option(name, flipped).unwrap();
+            assert_eq!(option(&engine, name), flipped);
+            engine.clear_hash();
+            assert_eq!(
+                engine.search(&board),
+                Some((Move::new([5, 1], [0, 1]), MATE - 1)),
+                "{}",
+                name
+            );
+            engine.set_option(name, &value).unwrap();
+        }
+        assert_eq!(engine.switches, Switches::default());
+        assert!(engine.set_option("NullMove", "2").is_err());
+    }
+
+    #[test]
+    fn pruning_saves_nodes() {
+        use crate::record::parse_iccs;
+
+        let mut board = Board::new();
+        for mv in BENCH_LINES[3].split_whitespace().filter_map(parse_iccs) {
+            board.make_move(mv.from(), mv.to());
+        }
+        let nodes = |off: &[&str]| {
+            let mut engine = Engine::new();
+            engine.set_option("Depth", "4").unwrap();
+            for name in off {
+                engine.set_option(name, "0").unwrap();
+            }
+            engine.search(&board).unwrap();
+            engine.nodes()
+        };
+        let default = nodes(&[]);
+        for name in ["NullMove", "LMR", "Futility"] {
+            assert!(nodes(&[name]) > default, "{}", name);
+        }
+        // together they at least halve the search, which they could not if
+        // moves after the first were each searched twice
+        assert!(2 * default < nodes(&Switches::names()));
+    }
+
+    #[test]
+    fn helper_threads_find_a_legal_move() {
+        let mut engine = Engine::new();
+        engine.set_option("Depth", "3").unwrap();
+        engine.set_option("Threads", "3").unwrap();
+        let board = Board::new();
+        let (mv, _) = engine.search(&board).unwrap();
+        assert!(board.legal_moves().contains(&mv));
+        assert!(engine.set_option("Threads", "0").is_err());
+    }
+
+    #[test]
+    fn searches_with_a_network() {
+        let path = std::env::temp_dir().join(format!("qi-network-{}", std::process::id()));
+        crate::nnue::tests::random_network().save(&path).unwrap();
+        let mut engine = Engine::new();
+        engine.set_option("Depth", "3").unwrap();
+        engine
+            .set_option("NnueFile", path.to_str().unwrap())
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(engine.nnue.is_some());
+        // captures and general moves along the way
+        let mut record = crate::record::Record::new();
+        record.moves = "h2e2 h9g7 e2e6 e9e8"
+            .split_whitespace()
+            .filter_map(crate::record::parse_iccs)
+            .collect();
+        let board = record.replay().unwrap();
+        let first = engine.search(&board);
+        let (mv, _) = first.unwrap();
+        assert!(board.legal_moves().contains(&mv));
+        engine.clear_hash();
+        assert_eq!(engine.search(&board), first);
+
+        // without Green's general the search falls back to the Params
+        let mut board = Board::empty();
+        board.put([9, 4], Piece::new(PieceType::Shuai, Player::Red));
+        board.put([5, 0], Piece::new(PieceType::Ju, Player::Red));
+        board.put([2, 2], Piece::new(PieceType::Ma, Player::Green));
+        let scores = (engine.search(&board), engine.evaluate(&board));
+        engine.set_option("NnueFile", "none").unwrap();
+        engine.clear_hash();
+        assert_eq!(scores, (engine.search(&board), engine.evaluate(&board)));
+        assert!(engine.nnue.is_none());
+        assert!(engine
+            .set_option("NnueFile", "/nonexistent/network")
+            .is_err());
+    }
+
+    #[test]
+    fn searches_by_mcts() {
+        let mut engine = Engine::new();
+        engine.set_option("Playouts", "300").unwrap();
+        engine.set_option("Threads", "2").unwrap();
+        assert!(engine.options().contains(&("Playouts", "300".to_string())));
+        let mut board = Board::new();
+        let mut searched = vec![];
+        for _ in 0..2 {
+            searched.push(board.fen());
+            let (mv, _) = engine.search(&board).unwrap();
+            assert!(board.legal_moves().contains(&mv));
+            board.make_move(mv.from(), mv.to());
+            board.history.push(mv);
+        }
+        assert_eq!(engine.nodes(), 300);
+        // the tree was carried forward to the second search's position
+        {
+            let tree = engine.tree.lock().unwrap();
+            let tree = tree.as_ref().unwrap();
+            assert_eq!(tree.board().fen(), searched[1]);
+            assert!(tree.visits() > 300);
+        }
+        engine.clear_hash();
+        assert!(engine.tree.lock().unwrap().is_none());
+
+        // a tree grown past the limit is dropped rather than carried
+        engine.tree_limit = 100;
+        let board = Board::new();
+        engine.search(&board).unwrap();
+        let (mv, _) = engine.search(&board).unwrap();
+        let mut board = board.clone();
+        board.make_move(mv.from(), mv.to());
+        board.history.push(mv);
+        engine.search(&board).unwrap();
+        let tree = engine.tree.lock().unwrap();
+        assert_eq!(tree.as_ref().unwrap().visits(), 300);
+    }
+}
@@ -0,0 +1,166 @@
+//! The evaluation's weights: a value for each piece type and a table of
+//! bonuses by square, which `qi tune` fits to game results and writes out.
+
+use crate::bitboard::{squares, KINDS};
+use crate::board::{Board, PieceType, Player};
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Piece values and piece-square tables, indexed like `KINDS`. The tables
+/// are seen from Red's side, row 0 being Green's back rank, and flipped top
+/// to bottom for Green.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Params {
+    pub values: [i32; 7],
+    pub pst: [[i32; 90]; 7],
+}
+
+impl Default for Params {
+    /// Material only, with a Bing worth double once it has crossed the
+    /// river.
+    fn default() -> Self {
+        let mut pst = [[0; 90]; 7];
+        for bonus in &mut pst[6][..45] {
+            *bonus = 30;
+        }
+        Params {
+            values: [0, 120, 120, 270, 600, 285, 30],
+            pst,
+        }
+    }
+}
+
+impl Params {
+    /// The index into a table of `player`'s piece on `square`.
+    pub fn square(player: Player, square: usize) -> usize {
+        match player {
+            Player::Green => (9 - square / 9) * 9 + square % 9,
+            _ => square,
+        }
+    }
+
+    /// The score of the position for the side to move.
+    pub fn evaluate(&self, board: &Board) -> i32 {
+        let bits = board.bitboards();
+        let mut score = 0;
+        for &player in &[Player::Red, Player::Green] {
+            let mut side = 0;
+            for (k, &piecetype) in KINDS.iter().enumerate() {
+                for square in squares(bits.pieces(player, piecetype)) {
+                    side += self.values[k] + self.pst[k][Params::square(player, square)];
+                }
+            }
+            score += if player == board.turn { side } else { -side };
+        }
+        score
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Params> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+}
+
+/// A `values` line with the seven values, then for each piece a `pst` line
+/// with its WXF letter followed by ten rows of nine numbers.
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "values")?;
+        for value in &self.values {
+            write!(f, " {}", value)?;
+        }
+        writeln!(f)?;
+        for (k, table) in self.pst.iter().enumerate() {
+            writeln!(f, "pst {}", KINDS[k].letter())?;
+            for row in table.chunks(9) {
+                let row = row.iter().map(|v| format!("{:>4}", v)).collect::<Vec<_>>();
+                writeln!(f, "{}", row.join(" "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Params {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Params> {
+        let mut params = Params {
+            values: [0; 7],
+            pst: [[0; 90]; 7],
+        };
+        let mut lines = s
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        let numbers = |line: &str| -> Result<Vec<i32>> {
+            line.split_whitespace()
+                .map(|n| n.parse().map_err(|_| anyhow!("not a number: {}", n)))
+                .collect()
+        };
+        match lines.next().and_then(|line| line.strip_prefix("values")) {
+            Some(values) => {
+                let values = numbers(values)?;
+                if values.len() != 7 {
+                    bail!("expected 7 values, got {}", values.len());
+                }
+                params.values.copy_from_slice(&values);
+            }
+            None => bail!("expected a values line"),
+        }
+        let mut seen = [false; 7];
+        while let Some(line) = lines.next() {
+            let letter = line
+                .strip_prefix("pst ")
+                .and_then(|letter| letter.trim().chars().next())
+                .ok_or_else(|| anyhow!("expected a pst line, got {}", line))?;
+            let k = PieceType::from_letter(letter)
+                .and_then(|piecetype| KINDS.iter().position(|&kind| kind == piecetype))
+                .ok_or_else(|| anyhow!("unknown piece {}", letter))?;
+            for row in 0..10 {
+                let values = numbers(lines.next().unwrap_or(""))?;
+                if values.len() != 9 {
+                    bail!("pst {}: expected 9 numbers in row {}", letter, row + 1);
+                }
+                params.pst[k][row * 9..row * 9 + 9].copy_from_slice(&values);
+            }
+            seen[k] = true;
+        }
+        if seen.contains(&false) {
+            bail!("expected a pst for every piece");
+        }
+        Ok(params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Piece;
+
+    #[test]
+    fn default_is_material() {
+        let params = Params::default();
+        let mut board = Board::new();
+        assert_eq!(params.evaluate(&board), 0);
+        // Red's h-file Bing crosses the river: worth 60, against 30 before
+        board.put([6, 8], Piece::new(PieceType::None, Player::NoneRed));
+        board.put([4, 8], Piece::new(PieceType::Bing, Player::Red));
+        assert_eq!(params.evaluate(&board), 30);
+        board.turn = Player::Green;
+        assert_eq!(params.evaluate(&board), -30);
+        board.put([5, 0], Piece::new(PieceType::Bing, Player::Green));
+        assert_eq!(params.evaluate(&board), 30);
+
+        let text = params.to_string();
+        assert_eq!(text.parse::<Params>().unwrap(), params);
+        assert!(text.replace("pst P", "pst X").parse::<Params>().is_err());
+        assert!("values 1 2 3".parse::<Params>().is_err());
+    }
+}
@@ -0,0 +1,427 @@
+//! Other engines as opponents: a subprocess spoken to over UCCI, or over UCI
+//! as xiangqi variants of UCI engines expect it.
+
+use crate::board::{Board, Move};
+use crate::engine::MATE;
+use crate::record::{parse_iccs, parse_square};
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long an engine has to exit after `quit` before it is killed.
+const QUIT_GRACE: Duration = Duration::from_secs(1);
+
+/// How to talk to an external engine.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// The Universal Chinese Chess Interface: ICCS moves, ranks 0-9.
+    Ucci,
+    /// UCI with xiangqi moves as in UCI variant engines: ranks 1-10.
+    Uci,
+}
+
+impl Protocol {
+    pub fn parse(s: &str) -> Option<Protocol> {
+        match s {
+            "ucci" => Some(Protocol::Ucci),
+            "uci" => Some(Protocol::Uci),
+            _ => None,
+        }
+    }
+
+    fn parse_move(self, s: &str) -> Option<Move> {
+        match self {
+            Protocol::Ucci if s.len() == 4 => parse_iccs(s),
+            Protocol::Uci => {
+                let split = s
+                    .char_indices()
+                    .skip(1)
+                    .find(|&(_, c)| c.is_ascii_alphabetic())?
+                    .0;
+                let square = |s: &str| -> Option<[usize; 2]> {
+                    let (file, rank) = s.split_at(1);
+                    let rank: usize = rank.parse().ok()?;
+                    parse_square(&format!("{}{}", file, rank.checked_sub(1)?))
+                };
+                Some(Move::new(square(&s[..split])?, square(&s[split..])?))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A running external engine. It is told to quit when dropped, and killed
+/// if it does not.
+pub struct ExternalEngine {
+    protocol: Protocol,
+    child: Child,
+    stdin: ChildStdin,
+    /// The engine's output, line by line, from a thread reading it.
+    lines: Receiver<io::Result<String>>,
+    /// The name the engine gave, or its command.
+    pub name: String,
+    /// How deep to search, unless `movetime` is set.
+    pub depth: u32,
+    /// Milliseconds to search for each move.
+    pub movetime: Option<u64>,
+    /// How long to wait for an answer, on top of any `movetime`, before
+    /// giving up on the engine.
+    pub timeout: Duration,
+}
+
+impl ExternalEngine {
+    /// Starts `command` and waits until it is ready.
+    pub fn launch(command: &str, args: &[String], protocol: Protocol) -> Result<ExternalEngine> {
+        let mut child = Command::new(command)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("could not start {}", command))?;
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("no stdin for {}", command))?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("no stdout for {}", command))?;
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let failed = line.is_err();
+                if sender.send(line).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        let mut engine = ExternalEngine {
+            protocol,
+            child,
+            stdin,
+            lines,
+            name: command.to_string(),
+            depth: 4,
+            movetime: None,
+            timeout: Duration::from_secs(30),
+        };
+        let (hello, ok) = match protocol {
+            Protocol::Ucci => ("ucci", "ucciok"),
+            Protocol::Uci => ("uci", "uciok"),
+        };
+        engine.send(hello)?;
+        let mut name = None;
+        engine.wait_for(&[ok], Duration::ZERO, |line| {
+            if let Some(id) = line.strip_prefix("id name ") {
+                name = Some(id.trim().to_string());
+            }
+        })?;
+        if let Some(name) = name {
+            engine.name = name;
+        }
+        if protocol == Protocol::Uci {
+            // UCI variant engines play chess until told otherwise
+            engine.send("setoption name UCI_Variant value xiangqi")?;
+        }
+        engine.ready()?;
+        Ok(engine)
+    }
+
+    fn send(&mut self, line: &str) -> Result<()> {
+        writeln!(self.stdin, "{}", line)?;
+        self.stdin.flush()?;
+        Ok(())
+    }
+
+    /// Reads lines up to one starting with one of `tokens`, which it
+    /// returns, handing the others to `each`. Fails if none comes within
+    /// `timeout` plus `extra`.
+    fn wait_for(
+        &mut self,
+        tokens: &[&str],
+        extra: Duration,
+        mut each: impl FnMut(&str),
+    ) -> Result<String> {
+        let deadline = Instant::now() + self.timeout + extra;
+        loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            let line = match self.lines.recv_timeout(wait) {
+                Ok(line) => line?,
+                Err(RecvTimeoutError::Timeout) => {
+                    bail!("{} did not send {} in time", self.name, tokens.join(" or "))
+                }
+                Err(RecvTimeoutError::Disconnected) => bail!(
+                    "{} exited while we waited for {}",
+                    self.name,
+                    tokens.join(" or ")
+                ),
+            };
+            let trimmed = line.trim();
+            let first = trimmed.split_whitespace().next().unwrap_or("");
+            if tokens.contains(&first) {
+                return Ok(trimmed.to_string());
+            }
+            each(trimmed);
+        }
+    }
+
+    fn ready(&mut self) -> Result<()> {
+        self.send("isready")?;
+        self.wait_for(&["readyok"], Duration::ZERO, |_| {})?;
+        Ok(())
+    }
+
+    /// Sets an option: `Depth`, `MoveTime` and `Timeout` (milliseconds)
+    /// here, anything else in the engine.
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<()> {
+        match name.to_ascii_lowercase().as_str() {
+            "depth" => self.depth = value.parse()?,
+            "movetime" => self.movetime = Some(value.parse()?),
+            "timeout" => self.timeout = Duration::from_millis(value.parse()?),
+            _ => {
+                let line = match self.protocol {
+                    Protocol::Ucci => format!("setoption {} {}", name, value),
+                    Protocol::Uci => format!("setoption name {} value {}", name, value),
+                };
+                self.send(&line)?;
+                self.ready()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn new_game(&mut self) -> Result<()> {
+        if self.protocol == Protocol::Uci {
+            self.send("ucinewgame")?;
+        }
+        self.ready()
+    }
+
+    /// The engine's move in the position on `board` and its last reported
+    /// score for the side to move, or `None` if it has no move.
+    pub fn go(&mut self, board: &Board) -> Result<Option<(Move, i32)>> {
+        self.send(&format!("position fen {}", board.fen()))?;
+        let go = match self.movetime {
+            Some(ms) if self.protocol == Protocol::Ucci => format!("go time {}", ms),
+            Some(ms) => format!("go movetime {}", ms),
+            None => format!("go depth {}", self.depth),
+        };
+        self.send(&go)?;
+        let mut score = 0;
+        let protocol = self.protocol;
+        let thinking = Duration::from_millis(self.movetime.unwrap_or(0));
+        let line = self.wait_for(&["bestmove", "nobestmove"], thinking, |line| {
+            if let Some(s) = parse_score(line, protocol) {
+                score = s;
+            }
+        })?;
+        let mut tokens = line.split_whitespace();
+        if tokens.next() == Some("nobestmove") {
+            return Ok(None);
+        }
+        match tokens.next() {
+            None | Some("(none)") => Ok(None),
+            Some(mv) => match self.protocol.parse_move(mv) {
+                Some(mv) => Ok(Some((mv, score))),
+                None => bail!("{} sent a malformed move: {}", self.name, line),
+            },
+        }
+    }
+}
+
+/// The score in an `info` line: `score <n>` in UCCI, `score cp <n>` or
+/// `score mate <moves>` in UCI.
+fn parse_score(line: &str, protocol: Protocol) -> Option<i32> {
+    let mut tokens = line.split_whitespace();
+    if tokens.next() != Some("info") {
+        return None;
+    }
+    tokens.find(|&token| token == "score")?;
+    match (protocol, tokens.next()?) {
+        (Protocol::Uci, "cp") => tokens.next()?.parse().ok(),
+        (Protocol::Uci, "mate") => {
+            let moves: i32 = tokens.next()?.parse().ok()?;
+            Some(if moves > 0 {
+                MATE - (2 * moves - 1)
+            } else {
+                -MATE - 2 * moves
+            })
+        }
+        (_, score) => score.parse().ok(),
+    }
+}
+
+impl Drop for ExternalEngine {
+    fn drop(&mut self) {
+        let _ = self.send("quit");
+        let deadline = Instant::now() + QUIT_GRACE;
+        while let Ok(None) = self.child.try_wait() {
+            if Instant::now() >= deadline {
+                let _ = self.child.kill();
+                let _ = self.child.wait();
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+pub(crate) mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// A script in a temporary directory of its own, removed on drop.
+    pub(crate) struct StandIn {
+        pub(crate) path: PathBuf,
+    }
+
+    impl StandIn {
+        fn new(name: &str, script: &str) -> StandIn {
+            let dir =
+                std::env::temp_dir().join(format!("qi-stand-in-{}-{}", std::process::id(), name));
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join(name);
+            fs::write(&path, script).unwrap();
+            std::process::Command::new("chmod")
+                .arg("+x")
+                .arg(&path)
+                .status()
+                .unwrap();
+            StandIn { path }
+        }
+    }
+
+    impl Drop for StandIn {
+        fn drop(&mut self) {
+            if let Some(dir) = self.path.parent() {
+                let _ = fs::remove_dir_all(dir);
+            }
+        }
+    }
+
+    /// A shell script that answers the handshake, logs every line it is
+    /// sent to `<path>.log`, and always plays `h2e2` with a score of 15.
+    pub(crate) fn stand_in(name: &str, protocol: Protocol) -> StandIn {
+        let (hello, ok, info, best) = match protocol {
+            Protocol::Ucci => ("ucci", "ucciok", "info depth 1 score 15 pv h2e2", "h2e2"),
+            Protocol::Uci => ("uci", "uciok", "info depth 1 score cp 15 pv h3e3", "h3e3"),
+        };
+        let script = format!(
+            "#!/bin/sh\n\
+             while read -r line; do\n\
+             \x20 echo \"$line\" >> \"$0.log\"\n\
+             \x20 case \"$line\" in\n\
+             \x20   {hello}) echo 'id name stand-in'; echo {ok} ;;\n\
+             \x20   isready) echo readyok ;;\n\
+             \x20   go*) echo '{info}'; echo 'bestmove {best}' ;;\n\
+             \x20   quit) exit 0 ;;\n\
+             \x20 esac\n\
+             done\n",
+            hello = hello,
+            ok = ok,
+            info = info,
+            best = best,
+        );
+        StandIn::new(name, &script)
+    }
+
+    #[test]
+    fn talks_to_a_stand_in() {
+        for &(name, protocol) in &[("ucci", Protocol::Ucci), ("uci", Protocol::Uci)] {
+            let stand_in = stand_in(name, protocol);
+            let path = &stand_in.path;
+            let mut engine = ExternalEngine::launch(path.to_str().unwrap(), &[], protocol).unwrap();
+            assert_eq!(engine.name, "stand-in");
+            engine.set_option("Hash", "16").unwrap();
+            engine.set_option("Depth", "7").unwrap();
+            engine.new_game().unwrap();
+            assert_eq!(
+                engine.go(&Board::new()).unwrap(),
+                Some((Move::new([7, 7], [7, 4]), 15))
+            );
+            drop(engine);
+
+            let log = fs::read_to_string(path.with_extension("log")).unwrap();
+            let expected: &[&str] = match protocol {
+                Protocol::Ucci => &["ucci", "isready", "setoption Hash 16", "isready", "isready"],
+                Protocol::Uci => &[
+                    "uci",
+                    "setoption name UCI_Variant value xiangqi",
+                    "isready",
+                    "setoption name Hash value 16",
+                    "isready",
+                    "ucinewgame",
+                    "isready",
+                ],
+            };
+            let mut lines = log.lines().collect::<Vec<_>>();
+            assert_eq!(&lines[..expected.len()], expected);
+            let rest = lines.split_off(expected.len());
+            assert_eq!(
+                rest,
+                [
+                    "position fen rnbakabnr/9/1c5c1/p1p1p1p1p/9/9/P1P1P1P1P/1C5C1/9/RNBAKABNR w - - 0 1",
+                    "go depth 7",
+                    "quit"
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn gives_up_on_a_silent_engine() {
+        // answers the handshake, then neither moves nor quits
+        let stand_in = StandIn::new(
+            "silent",
+            "#!/bin/sh\n\
+             while read -r line; do\n\
+             \x20 case \"$line\" in\n\
+             \x20   ucci) echo ucciok ;;\n\
+             \x20   isready) echo readyok ;;\n\
+             \x20 esac\n\
+             done\n",
+        );
+        let path = stand_in.path.to_str().unwrap();
+        let mut engine = ExternalEngine::launch(path, &[], Protocol::Ucci).unwrap();
+        engine.set_option("Timeout", "100").unwrap();
+        let start = Instant::now();
+        let error = engine.go(&Board::new()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("{} did not send bestmove or nobestmove in time", path)
+        );
+        drop(engine);
+        assert!(start.elapsed() < QUIT_GRACE * 3);
+    }
+
+    #[test]
+    fn parses_scores_and_moves() {
+        assert_eq!(
+            parse_score("info depth 3 score -40 pv h2e2", Protocol::Ucci),
+            Some(-40)
+        );
+        assert_eq!(
+            parse_score("info score cp 12 nodes 30", Protocol::Uci),
+            Some(12)
+        );
+        assert_eq!(
+            parse_score("info score mate 2", Protocol::Uci),
+            Some(MATE - 3)
+        );
+        assert_eq!(
+            parse_score("info score mate -1", Protocol::Uci),
+            Some(-MATE + 2)
+        );
+        assert_eq!(parse_score("info depth 3", Protocol::Uci), None);
+        let mv = Move::new([9, 0], [0, 0]);
+        assert_eq!(Protocol::Uci.parse_move("a1a10"), Some(mv));
+        assert_eq!(Protocol::Ucci.parse_move("a0a9"), Some(mv));
+        assert_eq!(Protocol::Uci.parse_move("a0a1"), None);
+        assert_eq!(Protocol::Ucci.parse_move("a1a10"), None);
+    }
+}
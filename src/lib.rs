@@ -0,0 +1,46 @@
+//! Rules, engine and game records for xiangqi (Chinese chess).
+//!
+//! The board is a `[[Piece; 9]; 10]` grid indexed `[row][col]`, with Green at
+//! row 0 and Red, who moves first, at row 9. It is read with `Board::piece`
+//! and changed with `Board::put` or by playing moves:
+//!
+//! ```
+//! use qi::{Board, MoveResult, Player};
+//!
+//! let mut board = Board::new();
+//! // the central cannon: Red's right Pao from (8, 8) to (8, 5), counting from 1
+//! assert_eq!(board.move_piece(Player::Red, [7, 7], [7, 4]), Ok(MoveResult::Valid));
+//! assert_eq!(board.turn, Player::Green);
+//! ```
+//!
+//! [`render`] draws boards to any `io::Write`. The colored renderers,
+//! `Piece::show_piece` and the stdout helpers (`Board::show` and the `color`
+//! module) need the `term` feature, which is on by default. Build with
+//! `default-features = false` to use the rules without a terminal.
+
+pub mod bitboard;
+pub mod board;
+pub mod book;
+pub mod clock;
+#[cfg(feature = "term")]
+pub mod color;
+pub mod ecco;
+pub mod engine;
+pub mod eval;
+pub mod external;
+pub mod mcts;
+pub mod moves;
+pub mod nnue;
+mod pool;
+pub mod record;
+pub mod render;
+mod rng;
+pub mod see;
+pub mod selfplay;
+pub mod sfen;
+pub mod tablebase;
+mod tt;
+pub mod tune;
+pub mod zobrist;
+
+pub use board::{Board, Move, MoveError, MoveResult, Piece, PieceType, Player};
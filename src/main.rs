@@ -1,24 +1,522 @@
-use board::{Board};
+mod batch;
+mod command;
+mod json;
+mod repl;
 
-fn main() {
-    //println!("Hello, world!");
-    let mut board = Board::new();
-    board.show();
-    match board.run() {
-        Ok(..) => {
-            println!("Finished correctly!!")
-        },
-        Err(..) => {
-            println!("Error occured!!")
+use qi::book::{self, Book};
+use qi::clock::{Clock, SystemTime, TimeControl};
+use qi::engine::{self, Engine};
+use qi::eval::Params;
+use qi::moves::MoveList;
+use qi::record::{self, Record};
+use qi::selfplay::{Adjudication, Config, Match, Opponent, Sprt, Verdict};
+use qi::sfen::{self, Generator, Settings};
+use qi::tablebase::{Material, Tablebases};
+use qi::tune::{self, Tuner};
+use qi::{Board, Move, Player};
+use std::fs::File;
+use std::io::{self, stdin, BufReader, Write};
+use std::process;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// `qi mkbook <out> <games>...`: builds an opening book from saved games.
+fn mkbook(args: &[String]) -> anyhow::Result<()> {
+    let (out, inputs) = match args.split_first() {
+        Some((out, inputs)) if !inputs.is_empty() => (out, inputs),
+        _ => anyhow::bail!("usage: qi mkbook <out> <games>..."),
+    };
+    let mut records = vec![];
+    for input in inputs {
+        records.extend(Record::load(input)?);
+    }
+    let book = Book::build(&records, book::DEFAULT_MAX_PLY);
+    book.save(out)?;
+    println!(
+        "{} games, {} book entries written to {}",
+        records.len(),
+        book.len(),
+        out
+    );
+    Ok(())
+}
+
+/// `qi tbgen <dir> <material>...`: generates endgame tables such as `KR-KAB`
+/// and every table they depend on.
+fn tbgen(args: &[String]) -> anyhow::Result<()> {
+    let (dir, materials) = match args.split_first() {
+        Some((dir, materials)) if !materials.is_empty() => (dir, materials),
+        _ => anyhow::bail!("usage: qi tbgen <dir> <material>..."),
+    };
+    let mut tbs = Tablebases::new();
+    for material in materials {
+        tbs.generate(&Material::parse(material)?);
+    }
+    tbs.save(dir)?;
+    for table in tbs.tables() {
+        println!("{}: {} positions", table.material(), table.len());
+    }
+    Ok(())
+}
+
+/// `qi bench movegen [iterations]`: generates the moves of the bench
+/// positions with per-piece vectors and with the side-wide generator filling
+/// one `MoveList`, and reports how many positions per second each manages.
+fn bench_movegen(args: &[String]) -> anyhow::Result<()> {
+    let iterations: usize = match args.first() {
+        Some(n) => n.parse()?,
+        None => 100_000,
+    };
+    let mut boards = vec![];
+    for line in engine::BENCH_LINES {
+        let mut record = Record::new();
+        record.moves = line
+            .split_whitespace()
+            .filter_map(record::parse_iccs)
+            .collect();
+        boards.push(record.replay()?);
+    }
+    let time = |name: &str, generate: &mut dyn FnMut(&Board) -> usize| {
+        let mut moves = 0;
+        let start = Instant::now();
+        for _ in 0..iterations {
+            for board in &boards {
+                moves += generate(board);
+            }
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        let rate = (iterations * boards.len()) as f64 / elapsed;
+        println!(
+            "{:>8}: {:>10} moves {:>8.3} s {:>10.0} positions/s",
+            name, moves, elapsed, rate
+        );
+        rate
+    };
+    let vectors = time("vectors", &mut |board| {
+        let mut moves: Vec<Move> = vec![];
+        for (row, pieces) in board.rows().iter().enumerate() {
+            for (col, piece) in pieces.iter().enumerate() {
+                if piece.player() == board.turn {
+                    for to in piece.possible(board, row, col) {
+                        moves.push(Move::new([row, col], to));
+                    }
+                }
+            }
         }
+        moves.len()
+    });
+    let mut list = MoveList::new();
+    let movelist = time("movelist", &mut |board| {
+        list.clear();
+        board.bitboards().generate(board.turn, &mut list);
+        list.len()
+    });
+    println!("speedup {:.2}x", movelist / vectors);
+    Ok(())
+}
+
+/// `qi bench [threads] [depth]`: searches a fixed set of positions with 1 to
+/// `threads` threads and reports nodes per second for each.
+fn bench(args: &[String]) -> anyhow::Result<()> {
+    if args.first().map(String::as_str) == Some("movegen") {
+        return bench_movegen(&args[1..]);
     }
+    let threads = match args.first() {
+        Some(n) => n.parse()?,
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let mut engine = Engine::new();
+    if let Some(depth) = args.get(1) {
+        engine.set_option("Depth", depth)?;
+    }
+    for n in 1..=threads {
+        engine.set_option("Threads", &n.to_string())?;
+        let mut nodes = 0;
+        let start = Instant::now();
+        for line in engine::BENCH_LINES {
+            let mut record = Record::new();
+            record.moves = line
+                .split_whitespace()
+                .filter_map(record::parse_iccs)
+                .collect();
+            let board = record.replay()?;
+            engine.clear_hash();
+            engine.search(&board);
+            nodes += engine.nodes();
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        println!(
+            "threads {:>3}: {:>10} nodes {:>8.3} s {:>10.0} nps",
+            n,
+            nodes,
+            elapsed,
+            nodes as f64 / elapsed
+        );
+    }
+    Ok(())
 }
 
-#[test]
-fn test(){
+/// `qi match <out> [--first <options>] [--second <options>] [--openings <file>]
+/// [--games N] [--concurrency N] [--max-plies N] [--elo0 E] [--elo1 E]
+/// [--alpha A] [--beta B]`: plays two engine configurations, such as
+/// `Depth=5,NullMove=0` or an external `ucci:/usr/bin/eleeye,Depth=8`,
+/// against each other from every opening FEN in `file` (or the initial
+/// position) with colours swapped, appending each game to `out`, until
+/// `games` are played or the SPRT decides.
+fn play_match(args: &[String]) -> anyhow::Result<()> {
+    let usage = "usage: qi match <out> [--first <options>] [--second <options>] \
+                 [--openings <file>] [--games N] [--concurrency N] [--max-plies N] \
+                 [--elo0 E] [--elo1 E] [--alpha A] [--beta B]";
+    let (out, flags) = match args.split_first() {
+        Some((out, flags)) if !out.starts_with("--") => (out, flags),
+        _ => anyhow::bail!(usage),
+    };
+    let mut first = Config::parse("first", "")?;
+    let mut second = Config::parse("second", "")?;
+    let mut openings = vec![Board::new()];
+    let mut games = 100;
+    let mut concurrency = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut adjudication = Adjudication::default();
+    let mut sprt = Sprt::default();
+    for pair in flags.chunks(2) {
+        let (flag, value) = match pair {
+            [flag, value] => (flag.as_str(), value.as_str()),
+            _ => anyhow::bail!(usage),
+        };
+        match flag {
+            "--first" => first = Config::parse("first", value)?,
+            "--second" => second = Config::parse("second", value)?,
+            "--openings" => {
+                openings = std::fs::read_to_string(value)?
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(|fen| {
+                        Board::from_fen(fen)
+                            .ok_or_else(|| anyhow::anyhow!("malformed FEN: {}", fen))
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                if openings.is_empty() {
+                    anyhow::bail!("no openings in {}", value);
+                }
+            }
+            "--games" => games = value.parse()?,
+            "--concurrency" => concurrency = value.parse()?,
+            "--max-plies" => adjudication.max_plies = value.parse()?,
+            "--elo0" => sprt.elo0 = value.parse()?,
+            "--elo1" => sprt.elo1 = value.parse()?,
+            "--alpha" => sprt.alpha = value.parse()?,
+            "--beta" => sprt.beta = value.parse()?,
+            _ => anyhow::bail!(usage),
+        }
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(out)?;
+    let run = Match {
+        first: &first,
+        second: &second,
+        openings: &openings,
+        games,
+        concurrency,
+        adjudication,
+        sprt,
+    };
+    let (lower, upper) = sprt.bounds();
+    let mut written = Ok(());
+    let results = run.run(|i, game, results| {
+        if written.is_ok() {
+            written = writeln!(file, "{}", game.record);
+        }
+        let (elo, margin) = results.elo().unwrap_or_default();
+        let elo = elo + 0.0;
+        println!(
+            "game {:>4}: {} {:<7} {:<16} +{} ={} -{}  elo {:+.1} +/- {:.1}  llr {:.2} ({:.2}, {:.2})",
+            i + 1,
+            if i % 2 == 0 { "first red " } else { "second red" },
+            game.record.outcome.as_str(),
+            game.termination.as_str(),
+            results.wins,
+            results.draws,
+            results.losses,
+            elo,
+            margin,
+            sprt.llr(results),
+            lower,
+            upper
+        );
+    })?;
+    written?;
+    let verdict = match sprt.verdict(&results) {
+        Verdict::AcceptH1 => "H1 accepted: first is stronger",
+        Verdict::AcceptH0 => "H0 accepted: first is not stronger",
+        Verdict::Continue => "inconclusive",
+    };
+    println!("{} games, {}", results.games(), verdict);
+    Ok(())
+}
 
+/// `qi tune <out> <games>... [--iterations N] [--rate R] [--skip N]
+/// [--from <params>]`: fits piece values and piece-square tables to the
+/// results of saved games, starting from `params` or the built-in weights,
+/// and writes them to `out` for the engine's `EvalFile` option.
+fn tune(args: &[String]) -> anyhow::Result<()> {
+    let usage = "usage: qi tune <out> <games>... [--iterations N] [--rate R] \
+                 [--skip N] [--from <params>]";
+    let (out, rest) = match args.split_first() {
+        Some((out, rest)) if !out.starts_with("--") => (out, rest),
+        _ => anyhow::bail!(usage),
+    };
+    let mut inputs = vec![];
+    let mut iterations = 1000;
+    let mut rate = 1.0;
+    let mut skip = 8;
+    let mut params = Params::default();
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        if !arg.starts_with("--") {
+            inputs.push(arg);
+            continue;
+        }
+        let value = rest.next().ok_or_else(|| anyhow::anyhow!(usage))?;
+        match arg.as_str() {
+            "--iterations" => iterations = value.parse()?,
+            "--rate" => rate = value.parse()?,
+            "--skip" => skip = value.parse()?,
+            "--from" => params = Params::load(value)?,
+            _ => anyhow::bail!(usage),
+        }
+    }
+    if inputs.is_empty() {
+        anyhow::bail!(usage);
+    }
+    let mut records = vec![];
+    for input in inputs {
+        records.extend(Record::load(input)?);
+    }
+    let samples = tune::samples(&records, &params, skip)?;
+    if samples.is_empty() {
+        anyhow::bail!("no positions to tune on in {} games", records.len());
+    }
+    let mut tuner = Tuner::new(samples, &params);
+    tuner.rate = rate;
+    println!(
+        "{} positions from {} games, k = {:.3}, error {:.6}",
+        tuner.len(),
+        records.len(),
+        tuner.k,
+        tuner.error()
+    );
+    for i in 1..=iterations {
+        tuner.step();
+        if i % 100 == 0 || i == iterations {
+            println!("iteration {:>5}: error {:.6}", i, tuner.error());
+        }
+    }
+    tuner.params().save(out)?;
+    println!("written to {}", out);
+    Ok(())
+}
+
+/// `qi gensfen <out> [--engine <options>] [--games N] [--concurrency N]
+/// [--random-plies N] [--max-plies N] [--max-score N] [--seed S]`: plays
+/// fixed-depth self-play games, such as with `Depth=6`, from random
+/// openings and writes their quiet positions to `out` as training data.
+fn gensfen(args: &[String]) -> anyhow::Result<()> {
+    let usage = "usage: qi gensfen <out> [--engine <options>] [--games N] [--concurrency N] \
+                 [--random-plies N] [--max-plies N] [--max-score N] [--seed S]";
+    let (out, flags) = match args.split_first() {
+        Some((out, flags)) if !out.starts_with("--") => (out, flags),
+        _ => anyhow::bail!(usage),
+    };
+    let mut engine = Config::parse("gensfen", "")?;
+    let mut games = 100;
+    let mut concurrency = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut seed = 1;
+    let mut settings = Settings::default();
+    for pair in flags.chunks(2) {
+        let (flag, value) = match pair {
+            [flag, value] => (flag.as_str(), value.as_str()),
+            _ => anyhow::bail!(usage),
+        };
+        match flag {
+            "--engine" => engine = Config::parse("gensfen", value)?,
+            "--games" => games = value.parse()?,
+            "--concurrency" => concurrency = value.parse()?,
+            "--random-plies" => settings.random_plies = value.parse()?,
+            "--max-plies" => settings.adjudication.max_plies = value.parse()?,
+            "--max-score" => settings.max_score = value.parse()?,
+            "--seed" => seed = value.parse()?,
+            _ => anyhow::bail!(usage),
+        }
+    }
+    if engine.external.is_some() {
+        anyhow::bail!("gensfen plays with the built-in engine only");
+    }
+    let mut writer = sfen::Writer::new(io::BufWriter::new(File::create(out)?))?;
+    let generator = Generator {
+        options: &engine.options,
+        games,
+        concurrency,
+        seed,
+        settings,
+    };
+    let mut written = Ok(());
+    let mut positions = 0;
+    generator.run(|i, game, entries| {
+        for entry in entries {
+            if written.is_ok() {
+                written = writer.write(entry);
+            }
+        }
+        positions += entries.len();
+        println!(
+            "game {:>5}: {:<7} {:<16} {:>4} plies {:>4} positions, {} in all",
+            i + 1,
+            game.record.outcome.as_str(),
+            game.termination.as_str(),
+            game.record.moves.len(),
+            entries.len(),
+            positions
+        );
+    })?;
+    written?;
+    writer.into_inner().flush()?;
+    println!("{} positions written to {}", positions, out);
+    Ok(())
+}
+
+/// `qi sfen2txt <in> [out]`: converts training data to one line per
+/// position: the FEN, score, result and ply separated by semicolons.
+fn sfen2txt(args: &[String]) -> anyhow::Result<()> {
+    let (input, out): (&String, Box<dyn Write>) = match args {
+        [input] => (input, Box::new(io::stdout().lock())),
+        [input, out] => (input, Box::new(File::create(out)?)),
+        _ => anyhow::bail!("usage: qi sfen2txt <in> [out]"),
+    };
+    let mut out = io::BufWriter::new(out);
+    for entry in sfen::Reader::new(BufReader::new(File::open(input)?))? {
+        writeln!(out, "{}", entry?)?;
+    }
+    out.flush()?;
+    Ok(())
+}
 
+/// `qi batch [file]`: plays a script of moves from `file` or stdin and
+/// reports the final position, the result and the first illegal move, with
+/// an exit code for each outcome.
+fn batch(args: &[String]) -> anyhow::Result<()> {
+    let report = match args {
+        [] => batch::replay(io::stdin().lock())?,
+        [file] => batch::replay(BufReader::new(File::open(file)?))?,
+        _ => anyhow::bail!("usage: qi batch [file]"),
+    };
+    print!("{}", report);
+    process::exit(report.exit_code())
 }
 
-pub mod board;
-pub mod color;
\ No newline at end of file
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let command = match args.first().map(String::as_str) {
+        Some("mkbook") => Some(mkbook as fn(&[String]) -> anyhow::Result<()>),
+        Some("tbgen") => Some(tbgen as fn(&[String]) -> anyhow::Result<()>),
+        Some("bench") => Some(bench as fn(&[String]) -> anyhow::Result<()>),
+        Some("batch") => Some(batch as fn(&[String]) -> anyhow::Result<()>),
+        Some("match") => Some(play_match as fn(&[String]) -> anyhow::Result<()>),
+        Some("tune") => Some(tune as fn(&[String]) -> anyhow::Result<()>),
+        Some("gensfen") => Some(gensfen as fn(&[String]) -> anyhow::Result<()>),
+        Some("sfen2txt") => Some(sfen2txt as fn(&[String]) -> anyhow::Result<()>),
+        _ => None,
+    };
+    if let Some(command) = command {
+        if let Err(e) = command(&args[1..]) {
+            println!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    let mut board = Board::new();
+    let mut engine = Engine::new();
+    let mut perspective = Player::Red;
+    let mut json = false;
+    let mut opponent = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--clock" => match args.next().map(|spec| spec.parse::<TimeControl>()) {
+                Some(Ok(control)) => {
+                    board.clock = Some(Clock::new(control, Arc::new(SystemTime::new())));
+                }
+                Some(Err(e)) => {
+                    println!("{}", e);
+                    return;
+                }
+                None => {
+                    println!("--clock needs a time control, e.g. 300, 300+5 or 300/30x3");
+                    return;
+                }
+            },
+            "--book" => match args.next().map(Book::load) {
+                Some(Ok(book)) => engine.book = Some(book),
+                Some(Err(e)) => {
+                    println!("Could not load opening book: {}", e);
+                    return;
+                }
+                None => {
+                    println!("--book needs a book file");
+                    return;
+                }
+            },
+            "--tb" => match args.next().map(Tablebases::load) {
+                Some(Ok(tbs)) => engine.tablebases = Some(tbs),
+                Some(Err(e)) => {
+                    println!("Could not load tablebases: {}", e);
+                    return;
+                }
+                None => {
+                    println!("--tb needs a tablebase directory");
+                    return;
+                }
+            },
+            "--opponent" => match args
+                .next()
+                .map(|spec| Config::parse("opponent", spec).and_then(|c| c.opponent()))
+            {
+                Some(Ok(o)) => opponent = Some(o),
+                Some(Err(e)) => {
+                    println!("Could not start the opponent: {}", e);
+                    return;
+                }
+                None => {
+                    println!("--opponent needs an engine, e.g. ucci:/usr/bin/eleeye,Depth=8");
+                    return;
+                }
+            },
+            "--flip" => perspective = Player::Green,
+            "--json" => json = true,
+            _ => {
+                println!("Unknown option: {}", arg);
+                return;
+            }
+        }
+    }
+    let opponent = opponent.as_deref_mut().map(|o| o as &mut dyn Opponent);
+    match repl::run(
+        &mut board,
+        &mut engine,
+        opponent,
+        perspective,
+        json,
+        stdin().lock(),
+    ) {
+        Ok(..) if json => {}
+        Ok(..) => {
+            println!("Finished correctly!!")
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
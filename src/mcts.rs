@@ -0,0 +1,438 @@
+//! Monte Carlo tree search with PUCT selection, as an alternative to the
+//! alpha-beta search: each playout walks down the tree to a leaf, choosing
+//! the child with the best value plus an exploration bonus weighted by its
+//! prior, asks an `Evaluator` for the leaf's move priors and value, and
+//! backs the value up the path.
+//!
+//! Threads share one tree behind a lock that they hold only to select and
+//! to back up. A thread adds a virtual loss to every node on its path while
+//! it evaluates, so that the others tend to explore elsewhere. Repetitions
+//! are not detected.
+
+use crate::board::{Board, Move};
+use crate::engine::{Engine, MATE};
+use crate::eval::Params;
+use crate::rng::Rng;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// How strongly the priors pull the search towards unexplored moves.
+pub const C_PUCT: f32 = 1.5;
+/// Centipawns per factor of ten in the odds of winning. A Ju ahead is worth
+/// a value of about 0.5, leaving room above for what the tree proves.
+const VALUE_SCALE: f32 = 1200.0;
+/// The most nodes a tree is kept with between searches, some 60 MB.
+pub const MAX_NODES: usize = 1 << 20;
+
+/// The policy and value a search consults at each new leaf.
+pub trait Evaluator: Sync {
+    /// Prior probabilities for `moves`, the legal moves on `board`, and the
+    /// value of the position for the side to move, from -1 for a loss to 1
+    /// for a win.
+    fn evaluate(&self, board: &Board, moves: &[Move]) -> (Vec<f32>, f32);
+}
+
+/// The same prior for every move.
+pub fn uniform(moves: &[Move]) -> Vec<f32> {
+    vec![1.0 / moves.len().max(1) as f32; moves.len()]
+}
+
+/// A score in centipawns as a value between -1 and 1, through a logistic
+/// curve.
+pub fn value(score: i32) -> f32 {
+    2.0 / (1.0 + 10f32.powf(-score as f32 / VALUE_SCALE)) - 1.0
+}
+
+/// The inverse of `value`, kept short of mate scores.
+pub fn score(value: f32) -> i32 {
+    let value = value.clamp(-0.999, 0.999);
+    let score = -VALUE_SCALE * (2.0 / (value + 1.0) - 1.0).log10();
+    score.round() as i32
+}
+
+/// A uniform policy and the static evaluation.
+#[derive(Clone, Debug, Default)]
+pub struct StaticEval {
+    pub params: Params,
+}
+
+impl Evaluator for StaticEval {
+    fn evaluate(&self, board: &Board, moves: &[Move]) -> (Vec<f32>, f32) {
+        (uniform(moves), value(self.params.evaluate(board)))
+    }
+}
+
+/// A uniform policy and random playouts of up to `plies` plies, scored by
+/// who is left without a move or else by the static evaluation.
+#[derive(Debug, Default)]
+pub struct Rollout {
+    pub plies: usize,
+    pub params: Params,
+    seed: AtomicU64,
+}
+
+impl Rollout {
+    pub fn new(plies: usize) -> Rollout {
+        Rollout {
+            plies,
+            params: Params::default(),
+            seed: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Evaluator for Rollout {
+    fn evaluate(&self, board: &Board, moves: &[Move]) -> (Vec<f32>, f32) {
+        let mut rng = Rng::new(
+            board.hash()
+                ^ self
+                    .seed
+                    .fetch_add(1, Ordering::Relaxed)
+                    .wrapping_mul(0x9e37_79b9_7f4a_7c15),
+        );
+        let mut playout = board.clone();
+        let mut sign = 1.0;
+        for _ in 0..self.plies {
+            let legal = playout.legal_moves();
+            if legal.is_empty() {
+                return (uniform(moves), -sign);
+            }
+            let mv = legal[rng.below(legal.len())];
+            playout.make_move(mv.from(), mv.to());
+            sign = -sign;
+        }
+        (uniform(moves), sign * value(self.params.evaluate(&playout)))
+    }
+}
+
+/// A uniform policy and the engine's static evaluation, from its network if
+/// it has one.
+impl Evaluator for Engine {
+    fn evaluate(&self, board: &Board, moves: &[Move]) -> (Vec<f32>, f32) {
+        (uniform(moves), value(Engine::evaluate(self, board)))
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    /// The move that leads here, `None` at the root.
+    mv: Option<Move>,
+    prior: f32,
+    visits: u32,
+    /// The sum of the values backed up through here, for the side that
+    /// played `mv`.
+    total: f32,
+    /// Playouts in progress through here, each counted as a loss.
+    virtual_loss: u32,
+    expanded: bool,
+    children: Vec<usize>,
+}
+
+impl Node {
+    fn new(mv: Option<Move>, prior: f32) -> Node {
+        Node {
+            mv,
+            prior,
+            visits: 0,
+            total: 0.0,
+            virtual_loss: 0,
+            expanded: false,
+            children: vec![],
+        }
+    }
+}
+
+/// A search tree rooted at a position, kept between moves by `advance` and
+/// `reuse`.
+#[derive(Clone)]
+pub struct Tree {
+    board: Board,
+    nodes: Vec<Node>,
+}
+
+impl Tree {
+    pub fn new(board: &Board) -> Tree {
+        Tree {
+            board: board.clone(),
+            nodes: vec![Node::new(None, 1.0)],
+        }
+    }
+
+    pub fn board(&self) -> &Board {
+        &self.board
+    }
+
+    /// The number of nodes, each holding one move.
+    pub fn size(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Playouts that have passed through the root.
+    pub fn visits(&self) -> u32 {
+        self.nodes[0].visits
+    }
+
+    /// Each root move with its visits and its average value for the side to
+    /// move, most visited first.
+    pub fn moves(&self) -> Vec<(Move, u32, f32)> {
+        let mut moves = self.nodes[0]
+            .children
+            .iter()
+            .filter_map(|&i| {
+                let node = &self.nodes[i];
+                let mean = node.total / node.visits.max(1) as f32;
+                node.mv.map(|mv| (mv, node.visits, mean))
+            })
+            .collect::<Vec<_>>();
+        moves.sort_by_key(|&(_, visits, _)| std::cmp::Reverse(visits));
+        moves
+    }
+
+    /// The most visited root move and its value, or `None` before the
+    /// first playout or with no legal moves.
+    pub fn best(&self) -> Option<(Move, f32)> {
+        self.moves()
+            .first()
+            .filter(|&&(_, visits, _)| visits > 0)
+            .map(|&(mv, _, value)| (mv, value))
+    }
+
+    /// Runs `playouts` more playouts on `threads` threads.
+    pub fn search(&mut self, evaluator: &dyn Evaluator, playouts: usize, threads: usize) {
+        let started = AtomicUsize::new(0);
+        let tree = Mutex::new(self);
+        thread::scope(|scope| {
+            for _ in 0..threads.max(1) {
+                scope.spawn(|| {
+                    while started.fetch_add(1, Ordering::Relaxed) < playouts {
+                        let (path, board) = tree.lock().unwrap().select();
+                        let moves = board.legal_moves();
+                        let (priors, value) = if moves.is_empty() {
+                            // being stalemated loses too
+                            (vec![], -1.0)
+                        } else {
+                            evaluator.evaluate(&board, &moves)
+                        };
+                        let mut tree = tree.lock().unwrap();
+                        tree.expand(*path.last().unwrap_or(&0), &moves, &priors);
+                        tree.backup(&path, value);
+                    }
+                });
+            }
+        });
+    }
+
+    /// Walks down from the root by PUCT to a node not yet expanded or
+    /// without moves, adding a virtual loss along the way, and returns the
+    /// path and the position there.
+    fn select(&mut self) -> (Vec<usize>, Board) {
+        let mut board = self.board.clone();
+        let mut path = vec![0];
+        let mut current = 0;
+        self.nodes[0].virtual_loss += 1;
+        while self.nodes[current].expanded && !self.nodes[current].children.is_empty() {
+            let parent = &self.nodes[current];
+            let sqrt_visits = ((parent.visits + parent.virtual_loss) as f32).sqrt();
+            let mut best = (f32::NEG_INFINITY, current);
+            for &i in &parent.children {
+                let child = &self.nodes[i];
+                let visits = (child.visits + child.virtual_loss) as f32;
+                let mean = if visits > 0.0 {
+                    (child.total - child.virtual_loss as f32) / visits
+                } else {
+                    0.0
+                };
+                let puct = mean + C_PUCT * child.prior * sqrt_visits / (1.0 + visits);
+                if puct > best.0 {
+                    best = (puct, i);
+                }
+            }
+            current = best.1;
+            let node = &mut self.nodes[current];
+            node.virtual_loss += 1;
+            if let Some(mv) = node.mv {
+                board.make_move(mv.from(), mv.to());
+                board.history.push(mv);
+            }
+            path.push(current);
+        }
+        (path, board)
+    }
+
+    /// Adds a child for each of `moves` with its prior, normalised, unless
+    /// another thread got there first.
+    fn expand(&mut self, node: usize, moves: &[Move], priors: &[f32]) {
+        if self.nodes[node].expanded {
+            return;
+        }
+        let sum: f32 = priors.iter().sum();
+        let first = self.nodes.len();
+        for (i, &mv) in moves.iter().enumerate() {
+            let prior = match priors.get(i) {
+                Some(&p) if sum > 0.0 => p / sum,
+                _ => 1.0 / moves.len() as f32,
+            };
+            self.nodes.push(Node::new(Some(mv), prior));
+        }
+        let node = &mut self.nodes[node];
+        node.expanded = true;
+        node.children = (first..first + moves.len()).collect();
+    }
+
+    /// Adds `value`, for the side to move at the end of `path`, to every
+    /// node on it from the point of view of the side that moved there, and
+    /// takes back the virtual losses.
+    fn backup(&mut self, path: &[usize], mut value: f32) {
+        for &i in path.iter().rev() {
+            let node = &mut self.nodes[i];
+            node.visits += 1;
+            node.virtual_loss -= 1;
+            node.total -= value;
+            value = -value;
+        }
+    }
+
+    /// Makes the position after `mv` the root, keeping its subtree.
+    pub fn advance(&mut self, mv: Move) {
+        let child = self.nodes[0]
+            .children
+            .iter()
+            .copied()
+            .find(|&i| self.nodes[i].mv == Some(mv));
+        self.board.make_move(mv.from(), mv.to());
+        self.board.history.push(mv);
+        let child = match child {
+            Some(child) => child,
+            None => {
+                self.nodes = vec![Node::new(None, 1.0)];
+                return;
+            }
+        };
+        let mut nodes = vec![];
+        let mut queue = std::collections::VecDeque::from(vec![child]);
+        while let Some(i) = queue.pop_front() {
+            let mut node = self.nodes[i].clone();
+            let first = nodes.len() + queue.len() + 1;
+            queue.extend(node.children.iter().copied());
+            node.children = (first..first + node.children.len()).collect();
+            nodes.push(node);
+        }
+        nodes[0].mv = None;
+        nodes[0].prior = 1.0;
+        self.nodes = nodes;
+    }
+
+    /// Moves the root forward to `board` if it follows from the root by the
+    /// moves in its history, keeping what is known about it. Returns
+    /// whether it did.
+    pub fn reuse(&mut self, board: &Board) -> bool {
+        let played = &self.board.history;
+        if !board.history.starts_with(played) {
+            return false;
+        }
+        let mut check = self.board.clone();
+        for &mv in &board.history[played.len()..] {
+            check.make_move(mv.from(), mv.to());
+        }
+        if check.hash() != board.hash() {
+            return false;
+        }
+        for &mv in &board.history[played.len()..] {
+            self.advance(mv);
+        }
+        true
+    }
+}
+
+/// A score in centipawns for a root move's value, with a win or loss the
+/// tree has proven shown as mate.
+pub(crate) fn engine_score(value: f32) -> i32 {
+    if value >= 1.0 {
+        MATE - 1
+    } else if value <= -1.0 {
+        -MATE + 2
+    } else {
+        score(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::{Piece, PieceType, Player};
+    use crate::record::parse_iccs;
+
+    /// Red's rooks mate on the ninth rank with `b4b9`.
+    fn mate_in_one() -> Board {
+        let mut board = Board::empty();
+        board.put([9, 5], Piece::new(PieceType::Shuai, Player::Red));
+        board.put([0, 3], Piece::new(PieceType::Shuai, Player::Green));
+        board.put([1, 0], Piece::new(PieceType::Ju, Player::Red));
+        board.put([5, 1], Piece::new(PieceType::Ju, Player::Red));
+        board.put([3, 6], Piece::new(PieceType::Ma, Player::Green));
+        board
+    }
+
+    #[test]
+    fn finds_mate() {
+        let board = mate_in_one();
+        let mate = Move::new([5, 1], [0, 1]);
+        for &threads in &[1, 4] {
+            let mut tree = Tree::new(&board);
+            tree.search(&StaticEval::default(), 2000, threads);
+            assert_eq!(tree.visits(), 2000);
+            let (mv, value) = tree.best().unwrap();
+            assert_eq!(mv, mate, "{} threads", threads);
+            assert!(value > 0.9);
+            assert!(tree.nodes.iter().all(|node| node.virtual_loss == 0));
+        }
+        let mut tree = Tree::new(&board);
+        tree.search(&Rollout::new(20), 2000, 2);
+        assert_eq!(tree.best().map(|(mv, _)| mv), Some(mate));
+    }
+
+    #[test]
+    fn takes_the_hanging_rook() {
+        let board = Board::from_fen("3k5/9/9/9/9/r8/9/9/9/R4K3 w").unwrap();
+        let mut tree = Tree::new(&board);
+        tree.search(&StaticEval::default(), 400, 1);
+        assert_eq!(tree.best().map(|(mv, _)| mv), parse_iccs("a0a4"));
+    }
+
+    #[test]
+    fn reuses_the_tree() {
+        let mut board = Board::new();
+        let mut tree = Tree::new(&board);
+        tree.search(&StaticEval::default(), 500, 1);
+        let (mv, _) = tree.best().unwrap();
+        let visits = tree.moves()[0].1;
+        board.make_move(mv.from(), mv.to());
+        board.history.push(mv);
+        assert!(tree.reuse(&board));
+        assert_eq!(tree.visits(), visits);
+        assert_eq!(tree.board().fen(), board.fen());
+        // the subtree's links survive the move
+        tree.search(&StaticEval::default(), 100, 1);
+        assert_eq!(tree.visits(), visits + 100);
+        let children = tree.moves().iter().map(|&(_, v, _)| v).sum::<u32>();
+        assert_eq!(children + 1, tree.visits());
+        assert!(!tree.reuse(&Board::new()));
+
+        // a move never searched starts afresh
+        let mut fresh = Tree::new(&board);
+        fresh.advance(parse_iccs("h9g7").unwrap());
+        assert_eq!(fresh.visits(), 0);
+        assert_eq!(fresh.best(), None);
+        assert_eq!(fresh.board().history.len(), 2);
+    }
+
+    #[test]
+    fn converts_scores() {
+        assert!(value(0).abs() < 1e-6);
+        assert!((value(600) - 0.52).abs() < 0.01);
+        assert!((value(-600) + 0.52).abs() < 0.01);
+        assert_eq!(score(value(250)), 250);
+        assert_eq!(engine_score(1.0), MATE - 1);
+    }
+}
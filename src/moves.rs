@@ -0,0 +1,207 @@
+//! The packed `Move` and the fixed-capacity `MoveList` that move generation
+//! fills without allocating.
+
+use crate::bitboard::{kind, KINDS};
+use crate::board::PieceType;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Deref, DerefMut};
+
+const SQUARES: u32 = 0x3fff;
+
+/// A move packed in a `u32`: the origin square in bits 0-6 and the
+/// destination in bits 7-13 (squares being `row * 9 + col`), then the
+/// moving piece's type in bits 14-16 and the captured piece's in bits 17-19,
+/// each stored as its index in `KINDS` plus one, or 0 when empty or unknown.
+///
+/// Moves built from squares alone, as when parsed, carry no piece types, so
+/// equality and hashing only look at the squares.
+#[derive(Copy, Clone)]
+pub struct Move(u32);
+
+impl Move {
+    pub fn new(from: [usize; 2], to: [usize; 2]) -> Move {
+        Move::from_squares(from[0] * 9 + from[1], to[0] * 9 + to[1])
+    }
+
+    pub fn from_squares(from: usize, to: usize) -> Move {
+        Move(from as u32 | (to as u32) << 7)
+    }
+
+    /// The move with the types of the moving and the captured piece.
+    pub fn with_pieces(self, piece: PieceType, captured: PieceType) -> Move {
+        let code = |piecetype| kind(piecetype).map_or(0, |k| k as u32 + 1);
+        Move(self.0 & SQUARES | code(piece) << 14 | code(captured) << 17)
+    }
+
+    pub fn from_square(self) -> usize {
+        (self.0 & 0x7f) as usize
+    }
+
+    pub fn to_square(self) -> usize {
+        (self.0 >> 7 & 0x7f) as usize
+    }
+
+    pub fn from(self) -> [usize; 2] {
+        let square = self.from_square();
+        [square / 9, square % 9]
+    }
+
+    pub fn to(self) -> [usize; 2] {
+        let square = self.to_square();
+        [square / 9, square % 9]
+    }
+
+    fn piecetype(code: u32) -> PieceType {
+        match code {
+            0 => PieceType::None,
+            code => KINDS[code as usize - 1],
+        }
+    }
+
+    /// The moving piece's type, if known.
+    pub fn piece(self) -> PieceType {
+        Move::piecetype(self.0 >> 14 & 7)
+    }
+
+    /// The captured piece's type: `None` for a quiet move, or when unknown.
+    pub fn captured(self) -> PieceType {
+        Move::piecetype(self.0 >> 17 & 7)
+    }
+
+    pub fn is_capture(self) -> bool {
+        self.captured() != PieceType::None
+    }
+}
+
+impl PartialEq for Move {
+    fn eq(&self, other: &Move) -> bool {
+        self.0 & SQUARES == other.0 & SQUARES
+    }
+}
+
+impl Eq for Move {}
+
+impl Hash for Move {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.0 & SQUARES).hash(state)
+    }
+}
+
+/// The move in ICCS notation.
+impl fmt::Debug for Move {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let square = |[row, col]: [usize; 2]| format!("{}{}", (b'a' + col as u8) as char, 9 - row);
+        write!(f, "{}{}", square(self.from()), square(self.to()))
+    }
+}
+
+/// More than the most moves any side can have in a position.
+pub const MAX_MOVES: usize = 128;
+
+/// A list of moves on the stack. It dereferences to a slice of its moves.
+#[derive(Copy, Clone)]
+pub struct MoveList {
+    moves: [Move; MAX_MOVES],
+    len: usize,
+}
+
+impl Default for MoveList {
+    fn default() -> Self {
+        MoveList::new()
+    }
+}
+
+impl MoveList {
+    pub fn new() -> MoveList {
+        MoveList {
+            moves: [Move(0); MAX_MOVES],
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, mv: Move) {
+        self.moves[self.len] = mv;
+        self.len += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Keeps only the moves for which `f` returns true, in order.
+    pub fn retain(&mut self, mut f: impl FnMut(Move) -> bool) {
+        let mut kept = 0;
+        for i in 0..self.len {
+            if f(self.moves[i]) {
+                self.moves[kept] = self.moves[i];
+                kept += 1;
+            }
+        }
+        self.len = kept;
+    }
+}
+
+impl Deref for MoveList {
+    type Target = [Move];
+
+    fn deref(&self) -> &[Move] {
+        &self.moves[..self.len]
+    }
+}
+
+impl DerefMut for MoveList {
+    fn deref_mut(&mut self) -> &mut [Move] {
+        &mut self.moves[..self.len]
+    }
+}
+
+impl IntoIterator for MoveList {
+    type Item = Move;
+    type IntoIter = std::iter::Take<std::array::IntoIter<Move, MAX_MOVES>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIterator::into_iter(self.moves).take(self.len)
+    }
+}
+
+impl<'a> IntoIterator for &'a MoveList {
+    type Item = &'a Move;
+    type IntoIter = std::slice::Iter<'a, Move>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl fmt::Debug for MoveList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packs_moves() {
+        let mv = Move::new([7, 7], [0, 7]).with_pieces(PieceType::Pao, PieceType::Ma);
+        assert_eq!((mv.from(), mv.to()), ([7, 7], [0, 7]));
+        assert_eq!((mv.piece(), mv.captured()), (PieceType::Pao, PieceType::Ma));
+        assert!(mv.is_capture());
+        assert_eq!(mv, Move::new([7, 7], [0, 7]));
+        assert_eq!(format!("{:?}", mv), "h2h9");
+
+        let mut list = MoveList::new();
+        for to in 0..9 {
+            list.push(Move::new([9, 0], [to, 0]));
+        }
+        list.retain(|mv| mv.to()[0] % 2 == 0);
+        assert_eq!(list.len(), 5);
+        assert_eq!(
+            list.iter().map(|mv| mv.to()[0]).collect::<Vec<_>>(),
+            [0, 2, 4, 6, 8]
+        );
+    }
+}
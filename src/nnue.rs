@@ -0,0 +1,471 @@
+//! An efficiently updatable neural network evaluation.
+//!
+//! The inputs are seen from each side in turn, with the board flipped top to
+//! bottom for Green: one of the nine palace squares for the side's own
+//! general, times thirteen planes of 90 squares, one for each other kind of
+//! piece and colour and one for the enemy general. Each side's first layer
+//! output, its accumulator, is the sum of the weights of the inputs that are
+//! set, so a move only adds and subtracts a few weight rows, except a
+//! general's move, which refreshes that side's accumulator from scratch.
+//!
+//! The side to move's accumulator and then the other side's, clipped to
+//! 0..=127, feed a hidden layer whose outputs are shifted right by 6 and
+//! clipped again, then a single output. Divided by 16 that is the score in
+//! centipawns for the side to move.
+//!
+//! On disk a network is the magic `QINN`, the accumulator size and the
+//! hidden layer size as `u32`s, then little-endian: the first layer's `i16`
+//! weights input by input and its `i16` biases, the hidden layer's `i8`
+//! weights neuron by neuron and its `i32` biases, and the output's `i8`
+//! weights and `i32` bias. The accumulator size must be a multiple of 16, at
+//! most `MAX_SIZE`.
+//!
+//! Only positions with both generals in their palaces can be evaluated,
+//! which excludes only set-ups that no game reaches.
+
+use crate::bitboard::{squares, KINDS};
+use crate::board::{Board, Move, Piece, PieceType, Player};
+use crate::eval::Params;
+use anyhow::{bail, Result};
+use std::fs;
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"QINN";
+/// Piece planes per general square: two colours of six kinds, and the enemy
+/// general.
+const PLANES: usize = 13;
+/// Inputs seen from one side.
+pub const FEATURES: usize = 9 * PLANES * 90;
+/// The largest accumulator, so that evaluation can clip it into a buffer on
+/// the stack.
+pub const MAX_SIZE: usize = 1024;
+const HIDDEN_SHIFT: u32 = 6;
+const OUTPUT_SCALE: i32 = 16;
+
+/// A network's weights.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Network {
+    size: usize,
+    hidden: usize,
+    feature_weights: Vec<i16>,
+    feature_biases: Vec<i16>,
+    hidden_weights: Vec<i8>,
+    hidden_biases: Vec<i32>,
+    output_weights: Vec<i8>,
+    output_bias: i32,
+}
+
+/// The first layer's outputs seen from Red and from Green.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Accumulator {
+    sides: [Vec<i16>; 2],
+}
+
+fn side(player: Player) -> usize {
+    match player {
+        Player::Green => 1,
+        _ => 0,
+    }
+}
+
+/// Where `perspective`'s general stands in its palace, 0 to 8, or `None`
+/// if it has no general there.
+fn general_square(board: &Board, perspective: Player) -> Option<usize> {
+    let general = board.bitboards().pieces(perspective, PieceType::Shuai);
+    if general == 0 {
+        return None;
+    }
+    let square = Params::square(perspective, general.trailing_zeros() as usize);
+    let (row, col) = (square / 9, square % 9);
+    if row < 7 || !(3..=5).contains(&col) {
+        return None;
+    }
+    Some((row - 7) * 3 + col - 3)
+}
+
+/// The input set by `piece` on `square` as seen from `perspective`, whose
+/// general is on `general`, or `None` for that general itself.
+fn feature(perspective: Player, general: usize, piece: Piece, square: usize) -> Option<usize> {
+    let own = piece.player() == perspective;
+    let plane = match piece.piecetype() {
+        PieceType::None => return None,
+        PieceType::Shuai if own => return None,
+        PieceType::Shuai => 12,
+        piecetype => {
+            let kind = KINDS.iter().position(|&k| k == piecetype)? - 1;
+            kind * 2 + !own as usize
+        }
+    };
+    Some((general * PLANES + plane) * 90 + Params::square(perspective, square))
+}
+
+impl Network {
+    /// A network of zeros with `size` accumulator entries per side and
+    /// `hidden` neurons.
+    pub fn new(size: usize, hidden: usize) -> Result<Network> {
+        if size == 0 || !size.is_multiple_of(16) || size > MAX_SIZE || hidden == 0 {
+            bail!(
+                "accumulator size {} or hidden layer size {} unsupported",
+                size,
+                hidden
+            );
+        }
+        Ok(Network {
+            size,
+            hidden,
+            feature_weights: vec![0; FEATURES * size],
+            feature_biases: vec![0; size],
+            hidden_weights: vec![0; hidden * 2 * size],
+            hidden_biases: vec![0; hidden],
+            output_weights: vec![0; hidden],
+            output_bias: 0,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Network> {
+        Network::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Network> {
+        if bytes.len() < 12 || &bytes[..4] != MAGIC {
+            bail!("not a network file");
+        }
+        let size = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+        let hidden = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize;
+        let expected = 12 + 2 * (FEATURES + 1) * size + 2 * hidden * size + 4 * hidden + hidden + 4;
+        if bytes.len() != expected {
+            bail!(
+                "network file is {} bytes, expected {}",
+                bytes.len(),
+                expected
+            );
+        }
+        let mut network = Network::new(size, hidden)?;
+        let mut rest = &bytes[12..];
+        let mut take = |n: usize| {
+            let (head, tail) = rest.split_at(n);
+            rest = tail;
+            head
+        };
+        let i16s = |b: &[u8]| {
+            b.chunks(2)
+                .map(|c| i16::from_le_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>()
+        };
+        let i32s = |b: &[u8]| {
+            b.chunks(4)
+                .map(|c| i32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect::<Vec<_>>()
+        };
+        let i8s = |b: &[u8]| b.iter().map(|&b| b as i8).collect::<Vec<_>>();
+        network.feature_weights = i16s(take(2 * FEATURES * size));
+        network.feature_biases = i16s(take(2 * size));
+        network.hidden_weights = i8s(take(2 * hidden * size));
+        network.hidden_biases = i32s(take(4 * hidden));
+        network.output_weights = i8s(take(hidden));
+        network.output_bias = i32s(take(4))[0];
+        Ok(network)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&(self.size as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.hidden as u32).to_le_bytes());
+        for w in self.feature_weights.iter().chain(&self.feature_biases) {
+            bytes.extend_from_slice(&w.to_le_bytes());
+        }
+        bytes.extend(self.hidden_weights.iter().map(|&w| w as u8));
+        for b in &self.hidden_biases {
+            bytes.extend_from_slice(&b.to_le_bytes());
+        }
+        bytes.extend(self.output_weights.iter().map(|&w| w as u8));
+        bytes.extend_from_slice(&self.output_bias.to_le_bytes());
+        bytes
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    fn row(&self, feature: usize) -> &[i16] {
+        &self.feature_weights[feature * self.size..(feature + 1) * self.size]
+    }
+
+    /// `perspective`'s accumulator computed from scratch, its general being
+    /// on `general`.
+    fn refresh(&self, board: &Board, perspective: Player, general: usize, values: &mut Vec<i16>) {
+        values.clear();
+        values.extend_from_slice(&self.feature_biases);
+        let bits = board.bitboards();
+        for &player in &[Player::Red, Player::Green] {
+            for &piecetype in &KINDS {
+                for square in squares(bits.pieces(player, piecetype)) {
+                    let piece = Piece::new(piecetype, player);
+                    if let Some(f) = feature(perspective, general, piece, square) {
+                        add(values, self.row(f));
+                    }
+                }
+            }
+        }
+    }
+
+    /// The accumulator for the position on `board`, or `None` unless both
+    /// generals are in their palaces.
+    pub fn accumulator(&self, board: &Board) -> Option<Accumulator> {
+        let mut accumulator = Accumulator {
+            sides: [vec![], vec![]],
+        };
+        for &player in &[Player::Red, Player::Green] {
+            let general = general_square(board, player)?;
+            self.refresh(board, player, general, &mut accumulator.sides[side(player)]);
+        }
+        Some(accumulator)
+    }
+
+    /// Brings `accumulator` from the position before `mv` to `board`, where
+    /// it has just been played and took `captured`. Legal moves from a
+    /// position `accumulator` accepted always lead to another.
+    pub fn update(&self, accumulator: &mut Accumulator, board: &Board, mv: Move, captured: Piece) {
+        let [row, col] = mv.to();
+        let piece = board.piece([row, col]);
+        for &perspective in &[Player::Red, Player::Green] {
+            let values = &mut accumulator.sides[side(perspective)];
+            let general = general_square(board, perspective).expect("a general left its palace");
+            if piece.piecetype() == PieceType::Shuai && piece.player() == perspective {
+                self.refresh(board, perspective, general, values);
+                continue;
+            }
+            if let Some(f) = feature(perspective, general, piece, mv.from_square()) {
+                sub(values, self.row(f));
+            }
+            if let Some(f) = feature(perspective, general, piece, mv.to_square()) {
+                add(values, self.row(f));
+            }
+            if let Some(f) = feature(perspective, general, captured, mv.to_square()) {
+                sub(values, self.row(f));
+            }
+        }
+    }
+
+    /// The score for `turn`, the side to move, in centipawns.
+    pub fn evaluate(&self, accumulator: &Accumulator, turn: Player) -> i32 {
+        let (us, them) = (side(turn), 1 - side(turn));
+        let mut buffer = [0; 2 * MAX_SIZE];
+        let inputs = &mut buffer[..2 * self.size];
+        let values = accumulator.sides[us].iter().chain(&accumulator.sides[them]);
+        for (input, &v) in inputs.iter_mut().zip(values) {
+            *input = v.clamp(0, 127);
+        }
+        let mut output = self.output_bias;
+        for (j, weights) in self.hidden_weights.chunks(2 * self.size).enumerate() {
+            let sum = self.hidden_biases[j] + dot(inputs, weights);
+            let activation = (sum >> HIDDEN_SHIFT).clamp(0, 127);
+            output += activation * i32::from(self.output_weights[j]);
+        }
+        output / OUTPUT_SCALE
+    }
+
+    /// The score for the side to move on `board`, from scratch, or `None`
+    /// unless both generals are in their palaces.
+    pub fn evaluate_board(&self, board: &Board) -> Option<i32> {
+        Some(self.evaluate(&self.accumulator(board)?, board.turn))
+    }
+}
+
+fn add(values: &mut [i16], weights: &[i16]) {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: AVX2 is available
+            return unsafe { simd::add(values, weights) };
+        }
+    }
+    for (v, w) in values.iter_mut().zip(weights) {
+        *v = v.wrapping_add(*w);
+    }
+}
+
+fn sub(values: &mut [i16], weights: &[i16]) {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: AVX2 is available
+            return unsafe { simd::sub(values, weights) };
+        }
+    }
+    for (v, w) in values.iter_mut().zip(weights) {
+        *v = v.wrapping_sub(*w);
+    }
+}
+
+fn dot(inputs: &[i16], weights: &[i8]) -> i32 {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: AVX2 is available
+            return unsafe { simd::dot(inputs, weights) };
+        }
+    }
+    inputs
+        .iter()
+        .zip(weights)
+        .map(|(&x, &w)| i32::from(x) * i32::from(w))
+        .sum()
+}
+
+/// AVX2 versions of the loops above, for slices whose length is a multiple
+/// of 16. They give exactly the same results.
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod simd {
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn add(values: &mut [i16], weights: &[i16]) {
+        for (v, w) in values.chunks_exact_mut(16).zip(weights.chunks_exact(16)) {
+            let sum = _mm256_add_epi16(
+                _mm256_loadu_si256(v.as_ptr() as *const __m256i),
+                _mm256_loadu_si256(w.as_ptr() as *const __m256i),
+            );
+            _mm256_storeu_si256(v.as_mut_ptr() as *mut __m256i, sum);
+        }
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn sub(values: &mut [i16], weights: &[i16]) {
+        for (v, w) in values.chunks_exact_mut(16).zip(weights.chunks_exact(16)) {
+            let difference = _mm256_sub_epi16(
+                _mm256_loadu_si256(v.as_ptr() as *const __m256i),
+                _mm256_loadu_si256(w.as_ptr() as *const __m256i),
+            );
+            _mm256_storeu_si256(v.as_mut_ptr() as *mut __m256i, difference);
+        }
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn dot(inputs: &[i16], weights: &[i8]) -> i32 {
+        let mut sums = _mm256_setzero_si256();
+        for (x, w) in inputs.chunks_exact(16).zip(weights.chunks_exact(16)) {
+            let x = _mm256_loadu_si256(x.as_ptr() as *const __m256i);
+            let w = _mm256_cvtepi8_epi16(_mm_loadu_si128(w.as_ptr() as *const __m128i));
+            sums = _mm256_add_epi32(sums, _mm256_madd_epi16(x, w));
+        }
+        let mut lanes = [0i32; 8];
+        _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, sums);
+        lanes.iter().sum()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::record::parse_iccs;
+    use crate::rng::Rng;
+
+    /// A small network with weights from a fixed pseudo-random sequence.
+    pub(crate) fn random_network() -> Network {
+        let mut network = Network::new(32, 8).unwrap();
+        let mut rng = Rng::new(0x2545_f491_4f6c_dd1d);
+        let mut next = move |range: i64| rng.below(2 * range as usize + 1) as i64 - range;
+        for w in &mut network.feature_weights {
+            *w = next(20) as i16;
+        }
+        for b in &mut network.feature_biases {
+            *b = next(60) as i16 + 40;
+        }
+        for w in network
+            .hidden_weights
+            .iter_mut()
+            .chain(&mut network.output_weights)
+        {
+            *w = next(100) as i8;
+        }
+        for b in &mut network.hidden_biases {
+            *b = next(1000) as i32;
+        }
+        network.output_bias = 100;
+        network
+    }
+
+    #[test]
+    fn updates_match_refreshes() {
+        let network = random_network();
+        let mut board = Board::new();
+        let mut accumulator = network.accumulator(&board).unwrap();
+        // captures on both sides and general moves
+        for mv in "h2e2 h9g7 e2e6 e9e8 e0e1 g7e6 e3e4 e8e9 b2b9 a9a8"
+            .split_whitespace()
+            .map(|mv| parse_iccs(mv).unwrap())
+        {
+            assert!(board.legal_moves().contains(&mv));
+            let captured = board.make_move(mv.from(), mv.to());
+            network.update(&mut accumulator, &board, mv, captured);
+            assert_eq!(Some(&accumulator), network.accumulator(&board).as_ref());
+        }
+        assert_eq!(
+            Some(network.evaluate(&accumulator, board.turn)),
+            network.evaluate_board(&board)
+        );
+        assert_ne!(network.evaluate_board(&board), Some(0));
+    }
+
+    #[test]
+    fn needs_both_generals_at_home() {
+        let network = random_network();
+        let mut board = Board::empty();
+        board.put([9, 4], Piece::new(PieceType::Shuai, Player::Red));
+        assert_eq!(network.evaluate_board(&board), None);
+        board.put([0, 3], Piece::new(PieceType::Shuai, Player::Green));
+        assert!(network.evaluate_board(&board).is_some());
+        board.put([0, 3], Piece::new(PieceType::None, Player::NoneGreen));
+        board.put([4, 4], Piece::new(PieceType::Shuai, Player::Green));
+        assert_eq!(network.evaluate_board(&board), None);
+    }
+
+    #[test]
+    fn sides_are_symmetric() {
+        // the initial position looks the same to both sides
+        let network = random_network();
+        let mut board = Board::new();
+        let red = network.evaluate_board(&board);
+        board.pass();
+        assert_eq!(network.evaluate_board(&board), red);
+    }
+
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    #[test]
+    fn simd_matches_scalar() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+        let network = random_network();
+        let weights = network.row(1234);
+        let inputs = (0..32).map(|i| (i * 37 % 128) as i16).collect::<Vec<_>>();
+        let mut values = inputs.clone();
+        unsafe { simd::add(&mut values, weights) };
+        let added = values.clone();
+        unsafe { simd::sub(&mut values, network.row(99)) };
+        for i in 0..32 {
+            assert_eq!(added[i], inputs[i].wrapping_add(weights[i]));
+            assert_eq!(values[i], added[i].wrapping_sub(network.row(99)[i]));
+        }
+        let row = &network.hidden_weights[..32];
+        let scalar: i32 = inputs
+            .iter()
+            .zip(row)
+            .map(|(&x, &w)| i32::from(x) * i32::from(w))
+            .sum();
+        assert_eq!(unsafe { simd::dot(&inputs, row) }, scalar);
+    }
+
+    #[test]
+    fn round_trips() {
+        let network = random_network();
+        let bytes = network.to_bytes();
+        assert_eq!(Network::from_bytes(&bytes).unwrap(), network);
+        assert!(Network::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Network::from_bytes(b"QIBK").is_err());
+        assert!(Network::new(24, 8).is_err());
+        assert!(Network::new(MAX_SIZE + 16, 8).is_err());
+    }
+}
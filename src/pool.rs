@@ -0,0 +1,143 @@
+//! A pool of threads working through numbered jobs, such as the games of a
+//! match.
+
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// Runs jobs `0..jobs` on `threads` threads. Each thread makes its own state
+/// with `setup`, such as its engines, then takes job after job and runs
+/// `work` on it. `collect` gets each result on the calling thread as it
+/// arrives, with its job number, and returns whether to start more jobs.
+/// The first error, from `setup` or `work`, also stops the run and is
+/// returned once the jobs under way have finished.
+pub fn run<S, T: Send>(
+    jobs: usize,
+    threads: usize,
+    setup: impl Fn() -> Result<S> + Sync,
+    work: impl Fn(&mut S, usize) -> Result<T> + Sync,
+    mut collect: impl FnMut(usize, T) -> bool,
+) -> Result<()> {
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let mut error = None;
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..threads.max(1) {
+            let sender = sender.clone();
+            let (next, stop, setup, work) = (&next, &stop, &setup, &work);
+            scope.spawn(move || {
+                let mut state = match setup() {
+                    Ok(state) => state,
+                    Err(e) => {
+                        let _ = sender.send(Err(e));
+                        return;
+                    }
+                };
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= jobs || stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let result = work(&mut state, i).map(|value| (i, value));
+                    let failed = result.is_err();
+                    if sender.send(result).is_err() || failed {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+        for result in receiver {
+            match result {
+                Ok((i, value)) => {
+                    if !collect(i, value) {
+                        stop.store(true, Ordering::Relaxed);
+                    }
+                }
+                Err(e) => {
+                    stop.store(true, Ordering::Relaxed);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+
+    #[test]
+    fn runs_every_job() {
+        let mut done = vec![];
+        run(
+            20,
+            4,
+            || Ok(()),
+            |_, i| Ok(i * i),
+            |i, square| {
+                done.push((i, square));
+                true
+            },
+        )
+        .unwrap();
+        done.sort();
+        assert_eq!(done, (0..20).map(|i| (i, i * i)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn stops_early() {
+        // the worker holds back from the fourth job until collect has asked
+        // to stop, so only the jobs it had already started come back
+        let asked = AtomicBool::new(false);
+        let mut done = 0;
+        run(
+            1000,
+            1,
+            || Ok(()),
+            |_, i| {
+                while i >= 3 && !asked.load(Ordering::Relaxed) {
+                    thread::yield_now();
+                }
+                Ok(i)
+            },
+            |i, _| {
+                assert_eq!(i, done);
+                done += 1;
+                asked.store(done >= 3, Ordering::Relaxed);
+                done < 3
+            },
+        )
+        .unwrap();
+        assert!((3..1000).contains(&done));
+
+        let failed = run(
+            20,
+            2,
+            || Ok(()),
+            |_, i| {
+                if i == 5 {
+                    bail!("job {} failed", i);
+                }
+                Ok(i)
+            },
+            |_, _| true,
+        );
+        assert_eq!(failed.unwrap_err().to_string(), "job 5 failed");
+        let failed = run(
+            20,
+            2,
+            || -> Result<()> { bail!("no engine") },
+            |_, i| Ok(i),
+            |_, _| true,
+        );
+        assert_eq!(failed.unwrap_err().to_string(), "no engine");
+    }
+}
@@ -0,0 +1,301 @@
+use crate::board::{Board, Move};
+use crate::ecco;
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// How a recorded game ended.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    RedWin,
+    GreenWin,
+    Draw,
+    Unfinished,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::RedWin => "1-0",
+            Outcome::GreenWin => "0-1",
+            Outcome::Draw => "1/2-1/2",
+            Outcome::Unfinished => "*",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Outcome> {
+        match s {
+            "1-0" => Some(Outcome::RedWin),
+            "0-1" => Some(Outcome::GreenWin),
+            "1/2-1/2" => Some(Outcome::Draw),
+            "*" => Some(Outcome::Unfinished),
+            _ => None,
+        }
+    }
+}
+
+/// Formats a move in ICCS notation: files `a`-`i` from Red's left, ranks
+/// `0`-`9` from Red's side, e.g. `h2e2` for the central cannon.
+pub fn to_iccs(mv: Move) -> String {
+    let square = |[row, col]: [usize; 2]| format!("{}{}", (b'a' + col as u8) as char, 9 - row);
+    format!("{}{}", square(mv.from()), square(mv.to()))
+}
+
+/// Parses an ICCS square such as `h2`.
+pub fn parse_square(s: &str) -> Option<[usize; 2]> {
+    match *s.as_bytes() {
+        [file, rank] => {
+            let file = file.to_ascii_lowercase();
+            if (b'a'..=b'i').contains(&file) && rank.is_ascii_digit() {
+                Some([9 - (rank - b'0') as usize, (file - b'a') as usize])
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+pub fn parse_iccs(s: &str) -> Option<Move> {
+    if s.len() != 4 || !s.is_char_boundary(2) {
+        return None;
+    }
+    Some(Move::new(parse_square(&s[..2])?, parse_square(&s[2..])?))
+}
+
+/// A saved game: `[Key "Value"]` header lines followed by numbered ICCS
+/// moves and the result, in the style of PGN.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub headers: Vec<(String, String)>,
+    pub moves: Vec<Move>,
+    pub outcome: Outcome,
+}
+
+impl Default for Record {
+    fn default() -> Self {
+        Record::new()
+    }
+}
+
+impl Record {
+    pub fn new() -> Record {
+        Record {
+            headers: vec![],
+            moves: vec![],
+            outcome: Outcome::Unfinished,
+        }
+    }
+
+    /// The record of the game played so far on `board`, with its opening
+    /// classification in the `ECCO` and `Opening` headers.
+    pub fn from_board(board: &Board, outcome: Outcome) -> Record {
+        let mut record = Record {
+            headers: vec![],
+            moves: board.history.clone(),
+            outcome,
+        };
+        if let Some(opening) = ecco::classify(&record.moves) {
+            record.set_header("ECCO", opening.code);
+            record.set_header("Opening", opening.name);
+        }
+        record
+    }
+
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn set_header(&mut self, key: &str, value: &str) {
+        match self.headers.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.headers.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    /// Parses every game in `text`. A header line after some moves starts a
+    /// new game.
+    pub fn parse_all(text: &str) -> Result<Vec<Record>> {
+        let mut records = vec![];
+        let mut current = Record::new();
+        let mut in_moves = false;
+        for line in text.lines() {
+            let line = line.trim();
+            if line.starts_with('[') {
+                if in_moves {
+                    records.push(std::mem::take(&mut current).finished());
+                    in_moves = false;
+                }
+                let inner = line
+                    .strip_prefix('[')
+                    .and_then(|l| l.strip_suffix(']'))
+                    .ok_or_else(|| anyhow!("malformed header: {}", line))?;
+                let (key, value) = inner
+                    .split_once(' ')
+                    .ok_or_else(|| anyhow!("malformed header: {}", line))?;
+                current.set_header(key, value.trim().trim_matches('"'));
+                continue;
+            }
+            for token in line.split_whitespace() {
+                in_moves = true;
+                if token.ends_with('.') {
+                    continue;
+                }
+                if let Some(outcome) = Outcome::parse(token) {
+                    current.outcome = outcome;
+                } else if let Some(mv) = parse_iccs(token) {
+                    current.moves.push(mv);
+                } else {
+                    bail!("unrecognised move: {}", token);
+                }
+            }
+        }
+        if in_moves || !current.headers.is_empty() {
+            records.push(current.finished());
+        }
+        Ok(records)
+    }
+
+    /// Takes the outcome from the `Result` header if the moves did not end
+    /// with one.
+    fn finished(mut self) -> Record {
+        if let Some(outcome) = self.header("Result").and_then(Outcome::parse) {
+            if self.outcome == Outcome::Unfinished {
+                self.outcome = outcome;
+            }
+        }
+        self
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Record>> {
+        Record::parse_all(&fs::read_to_string(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    /// Plays the moves from the position in the `FEN` header, or else the
+    /// initial position, checking each is legal.
+    pub fn replay(&self) -> Result<Board> {
+        self.replay_with(|_, _| {})
+    }
+
+    /// Like `replay`, handing `visit` each position with the move played
+    /// from it.
+    pub fn replay_with(&self, mut visit: impl FnMut(&Board, Move)) -> Result<Board> {
+        let mut board = match self.header("FEN") {
+            Some(fen) => Board::from_fen(fen).ok_or_else(|| anyhow!("malformed FEN: {}", fen))?,
+            None => Board::new(),
+        };
+        for (i, &mv) in self.moves.iter().enumerate() {
+            let legal = board.legal_moves();
+            let mv = match legal.iter().find(|&&legal| legal == mv) {
+                Some(&mv) => mv,
+                None => bail!("illegal move {} at ply {}", to_iccs(mv), i + 1),
+            };
+            visit(&board, mv);
+            board.make_move(mv.from(), mv.to());
+            board.history.push(mv);
+        }
+        Ok(board)
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (key, value) in &self.headers {
+            if key != "Result" {
+                writeln!(f, "[{} \"{}\"]", key, value)?;
+            }
+        }
+        writeln!(f, "[Result \"{}\"]", self.outcome.as_str())?;
+        writeln!(f)?;
+        for (i, pair) in self.moves.chunks(2).enumerate() {
+            write!(f, "{}.", i + 1)?;
+            for &mv in pair {
+                write!(f, " {}", to_iccs(mv))?;
+            }
+            if i % 8 == 7 {
+                writeln!(f)?;
+            } else {
+                write!(f, " ")?;
+            }
+        }
+        writeln!(f, "{}", self.outcome.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iccs() {
+        let mv = Move::new([7, 7], [7, 4]);
+        assert_eq!(to_iccs(mv), "h2e2");
+        assert_eq!(parse_iccs("h2e2"), Some(mv));
+        assert_eq!(parse_iccs("H2E2"), Some(mv));
+        assert_eq!(parse_iccs("j2e2"), None);
+        assert_eq!(parse_iccs("h2e"), None);
+    }
+
+    #[test]
+    fn parses_every_game() {
+        let text = "\
+[Event \"first\"]
+[Result \"1-0\"]
+
+1. h2e2 h9g7 2. h0g2
+
+[Event \"second\"]
+[Result \"0-1\"]
+
+1. b2e2
+[Event \"third\"]
+
+1. h2e2 1/2-1/2
+";
+        let records = Record::parse_all(text).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].header("Event"), Some("first"));
+        assert_eq!(records[0].moves.len(), 3);
+        assert_eq!(records[0].outcome, Outcome::RedWin);
+        assert_eq!(records[1].moves, [parse_iccs("b2e2").unwrap()]);
+        assert_eq!(records[1].outcome, Outcome::GreenWin);
+        assert_eq!(records[2].outcome, Outcome::Draw);
+        assert!(Record::parse_all("1. h2e2 zz99").is_err());
+        assert!(Record::parse_all("[Event]").is_err());
+    }
+
+    #[test]
+    fn saves_and_replays() {
+        let mut board = Board::new();
+        for token in &["h2e2", "h9g7", "h0g2", "i9h9"] {
+            let mv = parse_iccs(token).unwrap();
+            board.move_piece(board.turn, mv.from(), mv.to()).unwrap();
+        }
+        let mut record = Record::from_board(&board, Outcome::Draw);
+        record.set_header("Event", "test");
+        let path = std::env::temp_dir().join(format!("qi-record-{}", std::process::id()));
+        record.save(&path).unwrap();
+        let loaded = Record::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].header("Event"), Some("test"));
+        assert_eq!(loaded[0].header("ECCO"), record.header("ECCO"));
+        assert_eq!(loaded[0].moves, record.moves);
+        assert_eq!(loaded[0].outcome, Outcome::Draw);
+        assert_eq!(loaded[0].replay().unwrap().fen(), board.fen());
+
+        // replay stops at the first illegal move
+        record.moves.push(parse_iccs("a0a5").unwrap());
+        let error = record.replay().err().unwrap();
+        assert_eq!(error.to_string(), "illegal move a0a5 at ply 5");
+    }
+}
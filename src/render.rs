@@ -0,0 +1,415 @@
+use crate::board::{Board, Piece, PieceType, Player};
+use crate::ecco;
+use std::io::{self, Write};
+#[cfg(feature = "term")]
+use termcolor::{Ansi, Color, ColorSpec, WriteColor};
+
+/// Draws a board, with the opening and clock underneath, to any writer.
+pub trait Renderer {
+    fn render(&self, board: &Board, out: &mut dyn Write) -> io::Result<()>;
+}
+
+/// The Chinese character for a piece, or `..` for an empty square. Every
+/// glyph is two columns wide.
+pub fn glyph(piece: Piece) -> &'static str {
+    match (piece.piecetype(), piece.player()) {
+        (PieceType::Shuai, Player::Red) => "帅",
+        (PieceType::Shi, Player::Red) => "仕",
+        (PieceType::Xiang, Player::Red) => "相",
+        (PieceType::Ma, Player::Red) => "傌",
+        (PieceType::Ju, Player::Red) => "俥",
+        (PieceType::Pao, Player::Red) => "炮",
+        (PieceType::Bing, Player::Red) => "兵",
+        (PieceType::Shuai, Player::Green) => "将",
+        (PieceType::Shi, Player::Green) => "士",
+        (PieceType::Xiang, Player::Green) => "象",
+        (PieceType::Ma, Player::Green) => "馬",
+        (PieceType::Ju, Player::Green) => "車",
+        (PieceType::Pao, Player::Green) => "砲",
+        (PieceType::Bing, Player::Green) => "卒",
+        _ => "..",
+    }
+}
+
+/// The piece's WXF letter, upper case for Red and lower case for Green.
+pub fn ascii(piece: Piece) -> char {
+    match piece.player() {
+        Player::Red => piece.piecetype().letter(),
+        Player::Green => piece.piecetype().letter().to_ascii_lowercase(),
+        _ => '.',
+    }
+}
+
+const FRAME: &str = "xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx";
+const NUMERALS: [&str; 9] = ["一", "二", "三", "四", "五", "六", "七", "八", "九"];
+
+/// Rows top to bottom and columns left to right as seen from Red's side, or
+/// from Green's side when `flipped`.
+fn rows(flipped: bool) -> Vec<usize> {
+    if flipped {
+        (0..10).rev().collect()
+    } else {
+        (0..10).collect()
+    }
+}
+
+fn cols(flipped: bool) -> Vec<usize> {
+    if flipped {
+        (0..9).rev().collect()
+    } else {
+        (0..9).collect()
+    }
+}
+
+/// Green's file numbers, 1 on Green's right. They are also the column
+/// numbers `move` takes.
+fn green_files(flipped: bool) -> String {
+    let labels = cols(flipped)
+        .iter()
+        .map(|col| format!(" {}", col + 1))
+        .collect::<Vec<_>>();
+    format!("xxxxx{}xx", labels.join(" "))
+}
+
+/// Red's file numbers, 一 on Red's right, in Chinese numerals or digits.
+fn red_files(flipped: bool, chinese: bool) -> String {
+    let labels = cols(flipped)
+        .iter()
+        .map(|col| {
+            if chinese {
+                NUMERALS[8 - col].to_string()
+            } else {
+                format!(" {}", 9 - col)
+            }
+        })
+        .collect::<Vec<_>>();
+    format!("xxxxx{}xx", labels.join(" "))
+}
+
+/// Writes the frame, file labels and row numbers, calling `cell` for each
+/// square. Each side's file labels are on its own side of the board.
+fn grid<W: Write + ?Sized>(
+    board: &Board,
+    out: &mut W,
+    flipped: bool,
+    chinese: bool,
+    mut cell: impl FnMut(&mut W, Piece) -> io::Result<()>,
+) -> io::Result<()> {
+    let (top, bottom) = if flipped {
+        (red_files(flipped, chinese), green_files(flipped))
+    } else {
+        (green_files(flipped), red_files(flipped, chinese))
+    };
+    writeln!(out, "{}", top)?;
+    writeln!(out, "{}", FRAME)?;
+    for row in rows(flipped) {
+        write!(out, "{:02} xx", row + 1)?;
+        for (i, col) in cols(flipped).into_iter().enumerate() {
+            cell(out, board.piece([row, col]))?;
+            if i != 8 {
+                write!(out, " ")?;
+            }
+        }
+        writeln!(out, "xx")?;
+    }
+    writeln!(out, "{}", FRAME)?;
+    writeln!(out, "{}", bottom)
+}
+
+fn status<W: Write + ?Sized>(board: &Board, out: &mut W) -> io::Result<()> {
+    if let Some(opening) = ecco::classify(&board.history) {
+        writeln!(out, "{} {}", opening.code, opening.name)?;
+    }
+    if let Some(clock) = &board.clock {
+        writeln!(out, "{}", clock)?;
+    }
+    Ok(())
+}
+
+/// Pieces as WXF letters, for logs and anything that is not UTF-8 safe.
+/// Red's files are numbered in digits.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AsciiRenderer {
+    /// Draw the board from Green's side.
+    pub flipped: bool,
+}
+
+impl Renderer for AsciiRenderer {
+    fn render(&self, board: &Board, out: &mut dyn Write) -> io::Result<()> {
+        grid(board, out, self.flipped, false, |out, piece| {
+            write!(out, " {}", ascii(piece))
+        })?;
+        status(board, out)
+    }
+}
+
+/// Pieces as Chinese characters, without color.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct UnicodeRenderer {
+    /// Draw the board from Green's side.
+    pub flipped: bool,
+}
+
+impl Renderer for UnicodeRenderer {
+    fn render(&self, board: &Board, out: &mut dyn Write) -> io::Result<()> {
+        grid(board, out, self.flipped, true, |out, piece| {
+            write!(out, "{}", glyph(piece))
+        })?;
+        status(board, out)
+    }
+}
+
+/// Chinese characters in each side's color. Through `Renderer` the colors
+/// are written as ANSI escapes; `render_color` leaves that to the target,
+/// such as a `termcolor::StandardStream`.
+#[cfg(feature = "term")]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ColorRenderer {
+    /// Draw the board from Green's side.
+    pub flipped: bool,
+}
+
+/// Writes the piece's glyph in its side's color, white for an empty
+/// square.
+#[cfg(feature = "term")]
+pub fn write_glyph(piece: Piece, out: &mut dyn WriteColor) -> io::Result<()> {
+    let color = match piece.player() {
+        Player::Red => Color::Red,
+        Player::Green => Color::Green,
+        _ => Color::White,
+    };
+    out.set_color(ColorSpec::new().set_fg(Some(color)))?;
+    write!(out, "{}", glyph(piece))?;
+    out.reset()
+}
+
+#[cfg(feature = "term")]
+impl ColorRenderer {
+    pub fn render_color(&self, board: &Board, out: &mut dyn WriteColor) -> io::Result<()> {
+        grid(board, out, self.flipped, true, |out, piece| {
+            write_glyph(piece, out)
+        })?;
+        status(board, out)
+    }
+}
+
+#[cfg(feature = "term")]
+impl Renderer for ColorRenderer {
+    fn render(&self, board: &Board, out: &mut dyn Write) -> io::Result<()> {
+        self.render_color(board, &mut Ansi::new(out))
+    }
+}
+
+/// The full terminal board: the river labelled 楚河 汉界, the palace
+/// diagonals drawn through empty points, and background colors marking the
+/// last move's squares and a general in check.
+#[cfg(feature = "term")]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RichRenderer {
+    /// Draw the board from Green's side.
+    pub flipped: bool,
+    /// Highlight this square and where its piece can legally move, with
+    /// captures in a different color.
+    pub selected: Option<[usize; 2]>,
+}
+
+/// How an empty point is drawn: palace points on a diagonal show its
+/// direction.
+#[cfg(feature = "term")]
+fn point(row: usize, col: usize) -> &'static str {
+    let palace_row = match row {
+        0..=2 => row,
+        7..=9 => row - 7,
+        _ => return "..",
+    };
+    match (palace_row, col) {
+        (1, 4) => "><",
+        (0, 3) | (2, 5) => "\\\\",
+        (0, 5) | (2, 3) => "//",
+        _ => "..",
+    }
+}
+
+#[cfg(feature = "term")]
+impl RichRenderer {
+    pub fn render_color(&self, board: &Board, out: &mut dyn WriteColor) -> io::Result<()> {
+        let last = board.history.last().map(|mv| [mv.from(), mv.to()]);
+        let checked = if board.in_check(board.turn) {
+            board.find_shuai(board.turn)
+        } else {
+            None
+        };
+        let destinations = self
+            .selected
+            .map_or(vec![], |square| board.destinations(square));
+        let (top, bottom) = if self.flipped {
+            (red_files(true, true), green_files(true))
+        } else {
+            (green_files(false), red_files(false, true))
+        };
+        writeln!(out, "{}", top)?;
+        writeln!(out, "{}", FRAME)?;
+        for (i, row) in rows(self.flipped).into_iter().enumerate() {
+            if i == 5 {
+                write!(out, "   xx")?;
+                out.set_color(
+                    ColorSpec::new()
+                        .set_fg(Some(Color::Blue))
+                        .set_bg(Some(Color::White)),
+                )?;
+                write!(out, "    楚 河        汉 界    ")?;
+                out.reset()?;
+                writeln!(out, "xx")?;
+            }
+            write!(out, "{:02} xx", row + 1)?;
+            for (j, col) in cols(self.flipped).into_iter().enumerate() {
+                let piece = board.piece([row, col]);
+                let bg = if self.selected == Some([row, col]) {
+                    Color::Yellow
+                } else if let Some(&(_, capture)) =
+                    destinations.iter().find(|&&(to, _)| to == [row, col])
+                {
+                    if capture {
+                        Color::Blue
+                    } else {
+                        Color::Yellow
+                    }
+                } else if checked == Some([row, col]) {
+                    Color::Magenta
+                } else if last.is_some_and(|squares| squares.contains(&[row, col])) {
+                    Color::Cyan
+                } else {
+                    Color::White
+                };
+                let (fg, text) = match piece.player() {
+                    Player::Red => (Color::Red, glyph(piece)),
+                    Player::Green => (Color::Green, glyph(piece)),
+                    _ => (Color::Black, point(row, col)),
+                };
+                let mut spec = ColorSpec::new();
+                spec.set_fg(Some(fg)).set_bg(Some(bg));
+                if piece.piecetype() != PieceType::None {
+                    spec.set_bold(true);
+                }
+                out.set_color(&spec)?;
+                write!(out, "{}", text)?;
+                if j != 8 {
+                    out.set_color(ColorSpec::new().set_bg(Some(Color::White)))?;
+                    write!(out, " ")?;
+                }
+                out.reset()?;
+            }
+            writeln!(out, "xx")?;
+        }
+        writeln!(out, "{}", FRAME)?;
+        writeln!(out, "{}", bottom)?;
+        status(board, out)
+    }
+}
+
+#[cfg(feature = "term")]
+impl Renderer for RichRenderer {
+    fn render(&self, board: &Board, out: &mut dyn Write) -> io::Result<()> {
+        self.render_color(board, &mut Ansi::new(out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_initial_position() {
+        let mut out = vec![];
+        AsciiRenderer::default()
+            .render(&Board::new(), &mut out)
+            .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "xxxxx 1  2  3  4  5  6  7  8  9xx");
+        assert_eq!(lines[2], "01 xx r  n  b  a  k  a  b  n  rxx");
+        assert_eq!(lines[4], "03 xx .  c  .  .  .  .  .  c  .xx");
+        assert_eq!(lines[11], "10 xx R  N  B  A  K  A  B  N  Rxx");
+        assert_eq!(lines[13], "xxxxx 9  8  7  6  5  4  3  2  1xx");
+        assert_eq!(lines.len(), 14);
+    }
+
+    #[test]
+    fn flipped_from_green() {
+        let mut board = Board::new();
+        board.make_move([7, 7], [7, 4]);
+        let mut out = vec![];
+        UnicodeRenderer { flipped: true }
+            .render(&board, &mut out)
+            .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "xxxxx一 二 三 四 五 六 七 八 九xx");
+        assert_eq!(lines[2], "10 xx俥 傌 相 仕 帅 仕 相 傌 俥xx");
+        assert_eq!(lines[3], "09 xx.. .. .. .. .. .. .. .. ..xx");
+        assert_eq!(lines[4], "08 xx.. .. .. .. 炮 .. .. 炮 ..xx");
+        assert_eq!(lines[13], "xxxxx 9  8  7  6  5  4  3  2  1xx");
+    }
+
+    #[test]
+    fn unicode_initial_position() {
+        let mut out = vec![];
+        UnicodeRenderer::default()
+            .render(&Board::new(), &mut out)
+            .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines[2], "01 xx車 馬 象 士 将 士 象 馬 車xx");
+        assert_eq!(lines[9], "08 xx.. 炮 .. .. .. .. .. 炮 ..xx");
+        assert_eq!(lines[13], "xxxxx九 八 七 六 五 四 三 二 一xx");
+        assert_eq!(lines.len(), 14);
+    }
+
+    #[cfg(feature = "term")]
+    #[test]
+    fn colored_pieces() {
+        let mut out = vec![];
+        ColorRenderer::default()
+            .render(&Board::new(), &mut out)
+            .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        let (red, green, reset) = ("\x1b[0m\x1b[31m", "\x1b[0m\x1b[32m", "\x1b[0m");
+        assert!(lines[2].starts_with(&format!("01 xx{}車{} {}馬", green, reset, green)));
+        assert!(lines[11].starts_with(&format!("10 xx{}俥{}", red, reset)));
+        assert_eq!(lines.len(), 14);
+
+        // without color the text matches the Unicode renderer's
+        let mut plain = vec![];
+        ColorRenderer::default()
+            .render_color(&Board::new(), &mut termcolor::NoColor::new(&mut plain))
+            .unwrap();
+        let mut unicode = vec![];
+        UnicodeRenderer::default()
+            .render(&Board::new(), &mut unicode)
+            .unwrap();
+        assert_eq!(plain, unicode);
+
+        let mut out = termcolor::Ansi::new(vec![]);
+        Piece::new(PieceType::Pao, Player::Red)
+            .show_piece(&mut out)
+            .unwrap();
+        let text = String::from_utf8(out.into_inner()).unwrap();
+        assert_eq!(text, format!("{}炮{}", red, reset));
+    }
+
+    #[cfg(feature = "term")]
+    #[test]
+    fn rich_river_and_palace() {
+        let mut out = vec![];
+        RichRenderer::default()
+            .render_color(&Board::new(), &mut termcolor::NoColor::new(&mut out))
+            .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines[14], "xxxxx九 八 七 六 五 四 三 二 一xx");
+        assert_eq!(lines[3], "02 xx.. .. .. .. >< .. .. .. ..xx");
+        assert_eq!(lines[7], "   xx    楚 河        汉 界    xx");
+        assert_eq!(lines[10], "08 xx.. 炮 .. \\\\ .. // .. 炮 ..xx");
+    }
+}
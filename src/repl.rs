@@ -0,0 +1,432 @@
+use crate::command::{Command, ParseError, COMMANDS};
+use crate::json::Value;
+use anyhow::Result;
+use qi::board::{Board, Move, MoveResult, Player};
+use qi::clock::Clock;
+use qi::engine::Engine;
+use qi::record::{to_iccs, Outcome, Record};
+use qi::render::ascii;
+use qi::selfplay::Opponent;
+use std::io::BufRead;
+
+/// What one command produced.
+#[derive(Default)]
+struct Reply {
+    /// Messages for the user, one per line.
+    output: Vec<String>,
+    error: Option<String>,
+    /// Whether to draw the board again.
+    redraw: bool,
+    /// Highlight the destinations of the piece on this square when drawing.
+    selected: Option<[usize; 2]>,
+    /// Set once the game has ended.
+    outcome: Option<Outcome>,
+}
+
+/// Reads commands from `input` and plays them on `board` until the game ends
+/// or the input does, drawing the board from `perspective`'s side. A line
+/// that cannot be parsed, even one that is not UTF-8, gets an error message
+/// and play goes on. `go` asks `opponent` for a
+/// move if there is one, and `engine` otherwise. With `json`, every command
+/// instead prints one JSON object on a line of its own; see `state`.
+pub fn run(
+    board: &mut Board,
+    engine: &mut Engine,
+    mut opponent: Option<&mut dyn Opponent>,
+    mut perspective: Player,
+    json: bool,
+    input: impl BufRead,
+) -> Result<()> {
+    if let Some(clock) = &mut board.clock {
+        clock.start(board.turn);
+    }
+    let start = Reply {
+        redraw: true,
+        ..Reply::default()
+    };
+    print(board, perspective, json, &start);
+    for line in input.split(b'\n') {
+        let reply = match Command::parse_bytes(&line?) {
+            Ok(command) => execute(
+                command,
+                board,
+                engine,
+                opponent.as_deref_mut(),
+                &mut perspective,
+            ),
+            Err(ParseError::Empty) => continue,
+            Err(e) => Reply {
+                error: Some(e.to_string()),
+                ..Reply::default()
+            },
+        };
+        print(board, perspective, json, &reply);
+        if reply.outcome.is_some() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn execute(
+    command: Command,
+    board: &mut Board,
+    engine: &mut Engine,
+    opponent: Option<&mut (dyn Opponent + '_)>,
+    perspective: &mut Player,
+) -> Reply {
+    let mut reply = Reply::default();
+    match command {
+        Command::Move { from, to } => play(board, from, to, &mut reply),
+        Command::Go => match opponent.map_or_else(
+            || Ok(engine.best_move(board)),
+            |opponent| {
+                opponent
+                    .choose(board)
+                    .map(|choice| choice.map(|(mv, _)| mv))
+            },
+        ) {
+            Err(e) => reply.error = Some(format!("The opponent failed: {}", e)),
+            Ok(Some(mv)) => {
+                reply.output.push(format!("computer plays {}", to_iccs(mv)));
+                play(board, mv.from(), mv.to(), &mut reply);
+            }
+            Ok(None) => {
+                let winner = board.turn.opponent();
+                reply.output.push(format!("{} has won", name(winner)));
+                reply.outcome = Some(win(winner));
+            }
+        },
+        Command::Analyze => {
+            if let Some(probe) = engine.tablebases.as_ref().and_then(|tbs| tbs.probe(board)) {
+                reply.output.push(format!("tablebase: {}", probe));
+            }
+            match engine.search(board) {
+                Some((mv, score)) => {
+                    reply
+                        .output
+                        .push(format!("best move {} score {}", to_iccs(mv), score))
+                }
+                None => reply.output.push("No legal moves".to_string()),
+            }
+        }
+        Command::Moves { square } => {
+            let destinations = board.destinations(square);
+            if destinations.is_empty() {
+                reply.output.push("No legal moves".to_string());
+            }
+            for (to, capture) in destinations {
+                let mark = if capture { " capture" } else { "" };
+                reply
+                    .output
+                    .push(format!("{}{}", to_iccs(Move::new(square, to)), mark));
+            }
+            reply.redraw = true;
+            reply.selected = Some(square);
+        }
+        Command::Flip => {
+            *perspective = match perspective {
+                Player::Green => Player::Red,
+                _ => Player::Green,
+            };
+            reply.redraw = true;
+        }
+        Command::Options => {
+            for (name, value) in engine.options() {
+                reply.output.push(format!("{} {}", name, value));
+            }
+        }
+        Command::Set { name, value } => {
+            if let Err(e) = engine.set_option(&name, &value) {
+                reply.error = Some(e.to_string());
+            }
+        }
+        Command::Book => match &engine.book {
+            Some(book) => {
+                let moves = book.probe(board);
+                if moves.is_empty() {
+                    reply.output.push("No book moves".to_string());
+                }
+                for (mv, weight) in moves {
+                    reply.output.push(format!("{} {}", to_iccs(mv), weight));
+                }
+            }
+            None => reply.error = Some("No opening book loaded".to_string()),
+        },
+        Command::Save { path } => {
+            match Record::from_board(board, Outcome::Unfinished).save(&path) {
+                Ok(..) => reply.output.push(format!("Saved to {}", path)),
+                Err(e) => reply.error = Some(format!("Could not save: {}", e)),
+            }
+        }
+        Command::Help => {
+            for (_, syntax, description) in COMMANDS {
+                reply.output.push(format!("{:<44} {}", syntax, description));
+            }
+        }
+    }
+    reply
+}
+
+/// Plays a move for the side to move, checking its clock before and after.
+fn play(board: &mut Board, from: [usize; 2], to: [usize; 2], reply: &mut Reply) {
+    if let Some(loser) = board.clock.as_ref().and_then(Clock::flag) {
+        timeout(loser, reply);
+        return;
+    }
+    match board.move_piece(board.turn, from, to) {
+        Ok(result) => {
+            reply.redraw = true;
+            if let Some(loser) = board.clock.as_mut().and_then(Clock::press) {
+                timeout(loser, reply);
+            } else if result != MoveResult::Valid {
+                let winner = board.turn.opponent();
+                reply.output.push(format!("{} has won", name(winner)));
+                reply.outcome = Some(win(winner));
+            }
+        }
+        Err(e) => reply.error = Some(format!("Invalid move: {}", e)),
+    }
+}
+
+fn timeout(loser: Player, reply: &mut Reply) {
+    let winner = loser.opponent();
+    reply.output.push(format!(
+        "{} has lost on time. {} has won",
+        name(loser),
+        name(winner)
+    ));
+    reply.outcome = Some(win(winner));
+}
+
+fn name(player: Player) -> &'static str {
+    match player {
+        Player::Red | Player::NoneRed => "Red",
+        _ => "Green",
+    }
+}
+
+fn win(player: Player) -> Outcome {
+    match player {
+        Player::Red | Player::NoneRed => Outcome::RedWin,
+        _ => Outcome::GreenWin,
+    }
+}
+
+fn print(board: &Board, perspective: Player, json: bool, reply: &Reply) {
+    if json {
+        println!("{}", state(board, reply));
+        return;
+    }
+    if reply.redraw {
+        match reply.selected {
+            Some(square) => board.show_destinations(perspective, square),
+            None => board.show_from(perspective),
+        }
+    }
+    for line in &reply.output {
+        println!("{}", line);
+    }
+    if let Some(error) = &reply.error {
+        println!("{}", error);
+    }
+}
+
+/// The game after a command as a JSON object: `board` as ten rows of WXF
+/// letters from Green's side (Red in upper case, `.` for empty), `fen`,
+/// `turn`, the `ply` count, `legal_moves` and `last_move` in ICCS, `result`
+/// as in records, and the command's `output` lines and `error`.
+fn state(board: &Board, reply: &Reply) -> Value {
+    let rows = board
+        .rows()
+        .iter()
+        .map(|row| {
+            Value::from(
+                row.iter()
+                    .map(|&piece| ascii(piece).to_string())
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let legal_moves = match reply.outcome {
+        Some(..) => vec![],
+        None => board.legal_moves().into_iter().map(to_iccs).collect(),
+    };
+    let turn = match board.turn {
+        Player::Green => "green",
+        _ => "red",
+    };
+    Value::Object(vec![
+        ("board", Value::Array(rows)),
+        ("fen", board.fen().into()),
+        ("turn", turn.into()),
+        ("ply", Value::Number(board.history.len() as i64)),
+        ("legal_moves", legal_moves.into()),
+        (
+            "last_move",
+            board.history.last().map(|&mv| to_iccs(mv)).into(),
+        ),
+        (
+            "result",
+            reply.outcome.unwrap_or(Outcome::Unfinished).as_str().into(),
+        ),
+        ("output", reply.output.clone().into()),
+        ("error", reply.error.clone().into()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qi::board::{Piece, PieceType};
+
+    #[test]
+    fn survives_malformed_lines() {
+        let mut board = Board::new();
+        let mut engine = Engine::new();
+        let input = &b"move h2\xffe2\ncastle\n\nmove h2e2\n"[..];
+        run(&mut board, &mut engine, None, Player::Red, true, input).unwrap();
+        assert_eq!(board.history.len(), 1);
+        assert_eq!(to_iccs(board.history[0]), "h2e2");
+    }
+
+    fn field<'a>(value: &'a Value, key: &str) -> &'a Value {
+        match value {
+            Value::Object(fields) => &fields.iter().find(|&&(k, _)| k == key).unwrap().1,
+            _ => panic!("not an object: {}", value),
+        }
+    }
+
+    #[test]
+    fn json_state() {
+        let mut board = Board::new();
+        let mut engine = Engine::new();
+        let mut perspective = Player::Red;
+        let mv = Command::Move {
+            from: [7, 7],
+            to: [7, 4],
+        };
+        let reply = execute(mv, &mut board, &mut engine, None, &mut perspective);
+        let json = state(&board, &reply);
+        let rows = match field(&json, "board") {
+            Value::Array(rows) => rows,
+            value => panic!("not an array: {}", value),
+        };
+        assert_eq!(rows.len(), 10);
+        assert_eq!(
+            rows[7],
+            vec![".", "C", ".", ".", "C", ".", ".", ".", "."].into()
+        );
+        assert_eq!(field(&json, "turn"), &"green".into());
+        assert_eq!(field(&json, "ply"), &Value::Number(1));
+        assert_eq!(field(&json, "last_move"), &"h2e2".into());
+        let legal = board
+            .legal_moves()
+            .into_iter()
+            .map(to_iccs)
+            .collect::<Vec<_>>();
+        assert!(legal.contains(&"h9g7".to_string()));
+        assert_eq!(field(&json, "legal_moves"), &legal.into());
+        assert_eq!(field(&json, "result"), &"*".into());
+        assert_eq!(field(&json, "error"), &Value::Null);
+
+        // a move out of turn leaves an error and the position as it was
+        let mv = Command::Move {
+            from: [7, 4],
+            to: [7, 3],
+        };
+        let reply = execute(mv, &mut board, &mut engine, None, &mut perspective);
+        let json = state(&board, &reply);
+        assert_eq!(field(&json, "ply"), &Value::Number(1));
+        match field(&json, "error") {
+            Value::String(error) => assert!(error.starts_with("Invalid move: ")),
+            value => panic!("not an error: {}", value),
+        }
+
+        // two rooks mate the lone Green general
+        let mut board = Board::empty();
+        board.put([9, 3], Piece::new(PieceType::Shuai, Player::Red));
+        board.put([0, 4], Piece::new(PieceType::Shuai, Player::Green));
+        board.put([1, 8], Piece::new(PieceType::Ju, Player::Red));
+        board.put([5, 0], Piece::new(PieceType::Ju, Player::Red));
+        let mv = Command::Move {
+            from: [5, 0],
+            to: [0, 0],
+        };
+        let reply = execute(mv, &mut board, &mut engine, None, &mut perspective);
+        let json = state(&board, &reply);
+        assert_eq!(field(&json, "result"), &"1-0".into());
+        assert_eq!(field(&json, "legal_moves"), &Value::Array(vec![]));
+        assert_eq!(field(&json, "output"), &vec!["Red has won"].into());
+        assert!(json.to_string().contains(r#""result":"1-0""#));
+    }
+
+    /// Always answers with the same move.
+    struct Scripted(Move);
+
+    impl Opponent for Scripted {
+        fn new_game(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn choose(&mut self, _: &Board) -> Result<Option<(Move, i32)>> {
+            Ok(Some((self.0, 0)))
+        }
+    }
+
+    #[test]
+    fn go_asks_the_opponent() {
+        let mut board = Board::new();
+        let mut engine = Engine::new();
+        let mut opponent = Scripted(Move::new([9, 1], [7, 2]));
+        let reply = execute(
+            Command::Go,
+            &mut board,
+            &mut engine,
+            Some(&mut opponent),
+            &mut Player::Red,
+        );
+        assert_eq!(reply.output, ["computer plays b0c2"]);
+        // the engine is not consulted at all
+        assert_eq!(engine.nodes(), 0);
+    }
+
+    #[test]
+    fn go_and_save() {
+        let mut board = Board::new();
+        let mut engine = Engine::new();
+        engine.set_option("Depth", "1").unwrap();
+        let mut perspective = Player::Red;
+        let reply = execute(Command::Go, &mut board, &mut engine, None, &mut perspective);
+        assert_eq!(reply.error, None);
+        let played = to_iccs(board.history[0]);
+        assert_eq!(reply.output, [format!("computer plays {}", played)]);
+        assert_eq!(board.turn, Player::Green);
+
+        let path = std::env::temp_dir().join(format!("qi-repl-{}", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let reply = execute(
+            Command::Save { path: path.clone() },
+            &mut board,
+            &mut engine,
+            None,
+            &mut perspective,
+        );
+        assert_eq!(reply.output, [format!("Saved to {}", path)]);
+        let records = Record::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records[0].moves, board.history);
+        assert_eq!(records[0].outcome, Outcome::Unfinished);
+
+        let reply = execute(
+            Command::Save {
+                path: "/nonexistent/game.txt".to_string(),
+            },
+            &mut board,
+            &mut engine,
+            None,
+            &mut perspective,
+        );
+        assert!(reply.error.unwrap().starts_with("Could not save: "));
+    }
+}
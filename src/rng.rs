@@ -0,0 +1,25 @@
+//! A small pseudo-random generator for openings, playouts and test data,
+//! where speed and repeatability matter more than quality.
+
+/// Marsaglia's xorshift64.
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    /// Any seed will do: the state is kept odd, as xorshift never leaves 0.
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number from 0 up to but not including `n`, which must not be 0.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
@@ -0,0 +1,208 @@
+//! Static exchange evaluation: what a capture wins once every piece that can
+//! recapture on its square has had the chance, cheapest first.
+
+use crate::bitboard::{bit, Bitboard};
+use crate::board::{Board, Move, PieceType, Player};
+use crate::engine::value;
+
+/// The order in which pieces join an exchange: the least valuable first, and
+/// the general last.
+const ATTACKERS: [PieceType; 7] = [
+    PieceType::Bing,
+    PieceType::Shi,
+    PieceType::Xiang,
+    PieceType::Ma,
+    PieceType::Pao,
+    PieceType::Ju,
+    PieceType::Shuai,
+];
+
+/// The material the side to move wins by playing `mv` and then exchanging
+/// on its destination, with either side free to stop recapturing when that
+/// would lose more. Attackers are found again after every capture, so Pao
+/// screens that appear or vanish, Ju and Pao lined up behind the pieces that
+/// go first, blocked Ma legs and the palace are all taken into account. The
+/// general recaptures only onto a square the other side no longer attacks.
+/// Pins are ignored. A quiet move scores 0 unless it can be captured.
+pub fn see(board: &Board, mv: Move) -> i32 {
+    let bits = board.bitboards();
+    let [row, col] = mv.to();
+    let target = row * 9 + col;
+    let from = mv.from();
+    let mut player = board.turn;
+    let mut piece = board.piece(from).piecetype();
+    let mut occupied = bits.occupied() & !bit(from[0] * 9 + from[1]);
+    let captured = board.piece([row, col]);
+    // gains[d]: what the side making capture d wins if the exchange stops
+    // there; there are at most 32 pieces to capture with
+    let mut gains = [0; 33];
+    gains[0] = value(captured.piecetype(), mv.to(), captured.player());
+    let mut depth = 0;
+    loop {
+        player = player.opponent();
+        let attackers = bits.attackers(player, target, occupied);
+        let (square, attacker) = match least_valuable(board, player, attackers) {
+            Some(found) => found,
+            None => break,
+        };
+        if attacker == PieceType::Shuai
+            && bits.attackers(player.opponent(), target, occupied & !bit(square)) != 0
+        {
+            break;
+        }
+        depth += 1;
+        gains[depth] = value(piece, mv.to(), player.opponent()) - gains[depth - 1];
+        occupied &= !bit(square);
+        piece = attacker;
+    }
+    while depth > 0 {
+        depth -= 1;
+        gains[depth] = -(-gains[depth]).max(gains[depth + 1]);
+    }
+    gains[0]
+}
+
+fn least_valuable(
+    board: &Board,
+    player: Player,
+    attackers: Bitboard,
+) -> Option<(usize, PieceType)> {
+    ATTACKERS.iter().find_map(|&piecetype| {
+        let bb = attackers & board.bitboards().pieces(player, piecetype);
+        match bb {
+            0 => None,
+            bb => Some((bb.trailing_zeros() as usize, piecetype)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Piece;
+    use crate::record::{parse_iccs, parse_square};
+
+    /// A board with Red to move and `pieces` given as ICCS squares and WXF
+    /// letters, upper case for Red.
+    fn position(pieces: &[(&str, char)]) -> Board {
+        let mut board = Board::empty();
+        for &(square, letter) in pieces {
+            let player = if letter.is_ascii_uppercase() {
+                Player::Red
+            } else {
+                Player::Green
+            };
+            let piecetype = PieceType::from_letter(letter).unwrap();
+            board.put(parse_square(square).unwrap(), Piece::new(piecetype, player));
+        }
+        board
+    }
+
+    /// A name, the pieces, the capture and what it wins.
+    type Case = (
+        &'static str,
+        &'static [(&'static str, char)],
+        &'static str,
+        i32,
+    );
+
+    #[test]
+    fn exchanges() {
+        let cases: &[Case] = &[
+            ("undefended pawn", &[("a4", 'R'), ("g4", 'p')], "a4g4", 60),
+            (
+                "pawn defended by a rook",
+                &[("a4", 'R'), ("g4", 'p'), ("g8", 'r')],
+                "a4g4",
+                -540,
+            ),
+            (
+                "the rook removes its own cannon's screen",
+                &[("e1", 'C'), ("e2", 'R'), ("e5", 'p'), ("e8", 'r')],
+                "e2e5",
+                -570,
+            ),
+            (
+                "the cannon jumps its own rook, which recaptures",
+                &[("e1", 'C'), ("e2", 'R'), ("e5", 'p'), ("e8", 'r')],
+                "e1e5",
+                30,
+            ),
+            (
+                "a cannon behind the rook gains the horse as screen",
+                &[
+                    ("e0", 'C'),
+                    ("e1", 'N'),
+                    ("e2", 'R'),
+                    ("e5", 'p'),
+                    ("e8", 'r'),
+                ],
+                "e2e5",
+                30,
+            ),
+            (
+                "the defending rook screens a cannon behind it",
+                &[("e2", 'R'), ("e5", 'p'), ("e8", 'r'), ("e9", 'c')],
+                "e2e5",
+                -570,
+            ),
+            (
+                "defended by a horse",
+                &[("a5", 'R'), ("e5", 'p'), ("f7", 'n')],
+                "a5e5",
+                -570,
+            ),
+            (
+                "the horse's leg is blocked",
+                &[("a5", 'R'), ("e5", 'p'), ("f7", 'n'), ("f6", 'P')],
+                "a5e5",
+                30,
+            ),
+            (
+                "an advisor recaptures in the palace",
+                &[("d5", 'R'), ("d9", 'n'), ("e8", 'a')],
+                "d5d9",
+                -330,
+            ),
+            (
+                "advisors stay in the palace",
+                &[("c2", 'R'), ("c6", 'p'), ("d7", 'a')],
+                "c2c6",
+                30,
+            ),
+            (
+                "the general stays in the palace",
+                &[("f0", 'K'), ("d2", 'R'), ("d6", 'p'), ("d7", 'k')],
+                "d2d6",
+                30,
+            ),
+            (
+                "the general recaptures an undefended rook",
+                &[("e3", 'R'), ("e8", 'n'), ("e9", 'k')],
+                "e3e8",
+                -330,
+            ),
+            (
+                "the general cannot recapture into a second rook",
+                &[("e3", 'R'), ("a8", 'R'), ("e8", 'n'), ("e9", 'k')],
+                "e3e8",
+                270,
+            ),
+        ];
+        for &(name, pieces, mv, expected) in cases {
+            let mut pieces = pieces.to_vec();
+            if !pieces.iter().any(|&(_, letter)| letter == 'K') {
+                pieces.push(("d0", 'K'));
+            }
+            if !pieces.iter().any(|&(_, letter)| letter == 'k') {
+                pieces.push(("f9", 'k'));
+            }
+            let board = position(&pieces);
+            assert_eq!(see(&board, parse_iccs(mv).unwrap()), expected, "{}", name);
+        }
+
+        let board = Board::new();
+        assert_eq!(see(&board, parse_iccs("h2h9").unwrap()), -15);
+        assert_eq!(see(&board, parse_iccs("h2e2").unwrap()), 0);
+    }
+}
@@ -0,0 +1,543 @@
+//! Engine-against-engine games and the statistics to judge a match: playing
+//! two configurations from a set of openings with colours swapped, and a
+//! sequential probability ratio test on the results.
+
+use crate::board::{Board, Move, Player};
+use crate::engine::Engine;
+use crate::external::{ExternalEngine, Protocol};
+use crate::pool;
+use crate::record::{Outcome, Record};
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+
+/// Anything that can play a game: our own `Engine` or an external one.
+pub trait Opponent {
+    /// Gets ready for a new game.
+    fn new_game(&mut self) -> Result<()>;
+
+    /// The move to play on `board` and its score for the side to move, or
+    /// `None` if there is no legal move.
+    fn choose(&mut self, board: &Board) -> Result<Option<(Move, i32)>>;
+}
+
+impl Opponent for Engine {
+    fn new_game(&mut self) -> Result<()> {
+        self.clear_hash();
+        Ok(())
+    }
+
+    fn choose(&mut self, board: &Board) -> Result<Option<(Move, i32)>> {
+        Ok(self.search(board))
+    }
+}
+
+impl Opponent for ExternalEngine {
+    fn new_game(&mut self) -> Result<()> {
+        ExternalEngine::new_game(self)
+    }
+
+    fn choose(&mut self, board: &Board) -> Result<Option<(Move, i32)>> {
+        self.go(board)
+    }
+}
+
+/// An engine setup: `Engine::new`, or the `external` engine started with
+/// its protocol, with `options` applied in order, as with `set`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    pub name: String,
+    pub external: Option<(Protocol, String)>,
+    pub options: Vec<(String, String)>,
+}
+
+impl Config {
+    /// Parses options written as `Depth=5,NullMove=0`, checking them, with
+    /// an optional `ucci:<command>` or `uci:<command>` first for an external
+    /// engine, as in `ucci:/usr/bin/eleeye,Depth=8`.
+    pub fn parse(name: &str, spec: &str) -> Result<Config> {
+        let mut options = vec![];
+        let mut external = None;
+        let mut parts = spec.split(',').peekable();
+        if let Some((protocol, command)) = parts.peek().and_then(|first| first.split_once(':')) {
+            let protocol = Protocol::parse(protocol)
+                .ok_or_else(|| anyhow!("unknown protocol {}, expected ucci or uci", protocol))?;
+            external = Some((protocol, command.to_string()));
+            parts.next();
+        }
+        for option in parts.filter(|option| !option.is_empty()) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected name=value, got {}", option))?;
+            options.push((key.to_string(), value.to_string()));
+        }
+        let config = Config {
+            name: name.to_string(),
+            external,
+            options,
+        };
+        if config.external.is_none() {
+            config.opponent()?;
+        }
+        Ok(config)
+    }
+
+    /// Sets up the engine, starting it if it is external.
+    pub fn opponent(&self) -> Result<Box<dyn Opponent + Send>> {
+        match &self.external {
+            Some((protocol, command)) => {
+                let mut engine = ExternalEngine::launch(command, &[], *protocol)?;
+                for (name, value) in &self.options {
+                    engine.set_option(name, value)?;
+                }
+                Ok(Box::new(engine))
+            }
+            None => {
+                let mut engine = Engine::new();
+                for (name, value) in &self.options {
+                    engine.set_option(name, value)?;
+                }
+                Ok(Box::new(engine))
+            }
+        }
+    }
+}
+
+/// When to end a game before mate. Scores are the searching side's, from
+/// Red's point of view.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Adjudication {
+    /// Declare a draw after this many plies.
+    pub max_plies: usize,
+    /// Award the game to a side once `resign_plies` scores in a row give it
+    /// at least `resign_score`.
+    pub resign_score: i32,
+    pub resign_plies: usize,
+    /// Declare a draw from ply `draw_after` once `draw_plies` scores in a
+    /// row are within `draw_score` of zero.
+    pub draw_after: usize,
+    pub draw_score: i32,
+    pub draw_plies: usize,
+}
+
+impl Default for Adjudication {
+    fn default() -> Self {
+        Adjudication {
+            max_plies: 400,
+            resign_score: 1000,
+            resign_plies: 6,
+            draw_after: 120,
+            draw_score: 20,
+            draw_plies: 12,
+        }
+    }
+}
+
+/// Why a game ended, as in the `Termination` header.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Termination {
+    /// The side to move had no legal moves.
+    Mate,
+    Resign,
+    /// Both sides' scores stayed near zero.
+    DrawScore,
+    MaxPlies,
+    /// The same position came up a third time. Perpetual check and chase
+    /// are not told apart from other repetitions.
+    Repetition,
+    /// An external engine played an illegal move and lost.
+    IllegalMove,
+}
+
+impl Termination {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Termination::Mate => "mate",
+            Termination::Resign => "adjudicated win",
+            Termination::DrawScore => "adjudicated draw",
+            Termination::MaxPlies => "move limit",
+            Termination::Repetition => "repetition",
+            Termination::IllegalMove => "illegal move",
+        }
+    }
+}
+
+/// A finished game.
+#[derive(Clone, Debug)]
+pub struct Game {
+    pub record: Record,
+    pub termination: Termination,
+}
+
+/// Plays `red` against `green` from `start` until mate or adjudication.
+pub fn play(
+    red: &mut dyn Opponent,
+    green: &mut dyn Opponent,
+    start: &Board,
+    adjudication: &Adjudication,
+) -> Result<Game> {
+    let mut board = start.clone();
+    board.history.clear();
+    let fen = board.fen();
+    let mut seen = HashMap::new();
+    let mut scores = vec![];
+    red.new_game()?;
+    green.new_game()?;
+    let (outcome, termination) = loop {
+        let repetitions = seen.entry(board.hash()).or_insert(0);
+        *repetitions += 1;
+        if *repetitions >= 3 {
+            break (Outcome::Draw, Termination::Repetition);
+        }
+        if board.history.len() >= adjudication.max_plies {
+            break (Outcome::Draw, Termination::MaxPlies);
+        }
+        let (choice, loss) = if board.turn == Player::Red {
+            (red.choose(&board)?, Outcome::GreenWin)
+        } else {
+            (green.choose(&board)?, Outcome::RedWin)
+        };
+        let (mv, score) = match choice {
+            Some(found) => found,
+            None => break (loss, Termination::Mate),
+        };
+        let mv = match board.legal_moves().iter().find(|&&legal| legal == mv) {
+            Some(&mv) => mv,
+            None => break (loss, Termination::IllegalMove),
+        };
+        scores.push(if board.turn == Player::Red {
+            score
+        } else {
+            -score
+        });
+        board.make_move(mv.from(), mv.to());
+        board.history.push(mv);
+        if let Some(outcome) = adjudicate(&scores, adjudication) {
+            break outcome;
+        }
+    };
+    let mut record = Record::new();
+    if fen != Board::new().fen() {
+        record.set_header("FEN", &fen);
+    }
+    record.moves = board.history;
+    record.outcome = outcome;
+    record.set_header("Termination", termination.as_str());
+    Ok(Game {
+        record,
+        termination,
+    })
+}
+
+fn adjudicate(scores: &[i32], adjudication: &Adjudication) -> Option<(Outcome, Termination)> {
+    let last = |n: usize| scores.len().checked_sub(n).map(|start| &scores[start..]);
+    if let Some(last) = last(adjudication.resign_plies).filter(|last| !last.is_empty()) {
+        if last.iter().all(|&score| score >= adjudication.resign_score) {
+            return Some((Outcome::RedWin, Termination::Resign));
+        }
+        if last
+            .iter()
+            .all(|&score| score <= -adjudication.resign_score)
+        {
+            return Some((Outcome::GreenWin, Termination::Resign));
+        }
+    }
+    if scores.len() >= adjudication.draw_after {
+        if let Some(last) = last(adjudication.draw_plies).filter(|last| !last.is_empty()) {
+            if last
+                .iter()
+                .all(|&score| score.abs() <= adjudication.draw_score)
+            {
+                return Some((Outcome::Draw, Termination::DrawScore));
+            }
+        }
+    }
+    None
+}
+
+/// A sequential probability ratio test of H0, that the Elo difference is
+/// `elo0`, against H1, that it is `elo1`, with error rates `alpha` and
+/// `beta`, using the normal approximation to the trinomial distribution of
+/// wins, draws and losses.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sprt {
+    pub elo0: f64,
+    pub elo1: f64,
+    pub alpha: f64,
+    pub beta: f64,
+}
+
+impl Default for Sprt {
+    fn default() -> Self {
+        Sprt {
+            elo0: 0.0,
+            elo1: 5.0,
+            alpha: 0.05,
+            beta: 0.05,
+        }
+    }
+}
+
+/// What the test says so far.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// H1 holds: the change is an improvement of about `elo1`.
+    AcceptH1,
+    /// H0 holds: it is no better than `elo0`.
+    AcceptH0,
+    Continue,
+}
+
+/// The expected score of a side `elo` points stronger.
+fn expected_score(elo: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf(-elo / 400.0))
+}
+
+impl Sprt {
+    /// The lower and upper bounds of the log-likelihood ratio.
+    pub fn bounds(&self) -> (f64, f64) {
+        (
+            (self.beta / (1.0 - self.alpha)).ln(),
+            ((1.0 - self.beta) / self.alpha).ln(),
+        )
+    }
+
+    /// The log-likelihood ratio of H1 to H0 after `results`.
+    pub fn llr(&self, results: &Results) -> f64 {
+        let (score, variance) = match results.score() {
+            Some(stats) => stats,
+            None => return 0.0,
+        };
+        if variance == 0.0 {
+            return 0.0;
+        }
+        let (s0, s1) = (expected_score(self.elo0), expected_score(self.elo1));
+        results.games() as f64 * (s1 - s0) * (2.0 * score - s0 - s1) / (2.0 * variance)
+    }
+
+    pub fn verdict(&self, results: &Results) -> Verdict {
+        let llr = self.llr(results);
+        let (lower, upper) = self.bounds();
+        if llr >= upper {
+            Verdict::AcceptH1
+        } else if llr <= lower {
+            Verdict::AcceptH0
+        } else {
+            Verdict::Continue
+        }
+    }
+}
+
+/// Wins, draws and losses of the first engine.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Results {
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+}
+
+impl Results {
+    pub fn games(&self) -> u32 {
+        self.wins + self.draws + self.losses
+    }
+
+    /// The mean score per game and the variance of a single game's score.
+    fn score(&self) -> Option<(f64, f64)> {
+        let n = self.games() as f64;
+        if n == 0.0 {
+            return None;
+        }
+        let (w, d, l) = (
+            self.wins as f64 / n,
+            self.draws as f64 / n,
+            self.losses as f64 / n,
+        );
+        let score = w + d / 2.0;
+        let variance = w * (1.0 - score).powi(2) + d * (0.5 - score).powi(2) + l * score.powi(2);
+        Some((score, variance))
+    }
+
+    /// The Elo difference the results suggest, with the half-width of its
+    /// 95% confidence interval.
+    pub fn elo(&self) -> Option<(f64, f64)> {
+        let (score, variance) = self.score()?;
+        let margin = 1.96 * (variance / self.games() as f64).sqrt();
+        let elo = |score: f64| {
+            let score = score.clamp(1e-6, 1.0 - 1e-6);
+            -400.0 * (1.0 / score - 1.0).log10()
+        };
+        Some((
+            elo(score),
+            (elo(score + margin) - elo(score - margin)) / 2.0,
+        ))
+    }
+}
+
+/// A match between two configurations.
+pub struct Match<'a> {
+    pub first: &'a Config,
+    pub second: &'a Config,
+    /// Each is played twice in turn, once with either configuration as Red.
+    pub openings: &'a [Board],
+    pub games: usize,
+    pub concurrency: usize,
+    pub adjudication: Adjudication,
+    pub sprt: Sprt,
+}
+
+impl Match<'_> {
+    /// Plays the games on `concurrency` threads, each with its own pair of
+    /// engines, handing each game to `report` in the order they finish with
+    /// its number, from 0, and the results so far. Stops early once the SPRT
+    /// reaches a verdict, or when an engine fails. There must be at least one
+    /// opening.
+    pub fn run(&self, mut report: impl FnMut(usize, &Game, &Results)) -> Result<Results> {
+        if self.openings.is_empty() {
+            bail!("a match needs at least one opening");
+        }
+        let mut results = Results::default();
+        pool::run(
+            self.games,
+            self.concurrency,
+            || Ok((self.first.opponent()?, self.second.opponent()?)),
+            |(first, second), i| {
+                let start = &self.openings[i / 2 % self.openings.len()];
+                let (red, green, names) = if i % 2 == 0 {
+                    (first, second, (&self.first.name, &self.second.name))
+                } else {
+                    (second, first, (&self.second.name, &self.first.name))
+                };
+                let mut game = play(&mut **red, &mut **green, start, &self.adjudication)?;
+                game.record.set_header("Round", &(i + 1).to_string());
+                game.record.set_header("Red", names.0);
+                game.record.set_header("Green", names.1);
+                Ok(game)
+            },
+            |i, game| {
+                let first_is_red = i % 2 == 0;
+                match (game.record.outcome, first_is_red) {
+                    (Outcome::RedWin, true) | (Outcome::GreenWin, false) => results.wins += 1,
+                    (Outcome::RedWin, false) | (Outcome::GreenWin, true) => results.losses += 1,
+                    _ => results.draws += 1,
+                }
+                report(i, &game, &results);
+                self.sprt.verdict(&results) == Verdict::Continue
+            },
+        )?;
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sprt() {
+        let sprt = Sprt::default();
+        let (lower, upper) = sprt.bounds();
+        assert!((lower + 2.944).abs() < 1e-3 && (upper - 2.944).abs() < 1e-3);
+
+        let even = Results {
+            wins: 100,
+            draws: 50,
+            losses: 100,
+        };
+        assert_eq!(even.elo().map(|(elo, _)| elo.round()), Some(0.0));
+        assert!(sprt.llr(&even) < 0.0);
+
+        // 60% from 1200 games is about 70 Elo, far more than the 5 tested for.
+        let strong = Results {
+            wins: 600,
+            draws: 240,
+            losses: 360,
+        };
+        let (elo, margin) = strong.elo().unwrap();
+        assert_eq!(elo.round(), 70.0);
+        assert!(margin > 10.0 && margin < 25.0);
+        assert_eq!(sprt.verdict(&strong), Verdict::AcceptH1);
+        let weak = Results {
+            wins: strong.losses,
+            draws: strong.draws,
+            losses: strong.wins,
+        };
+        assert_eq!(sprt.verdict(&weak), Verdict::AcceptH0);
+        assert_eq!(sprt.verdict(&Results::default()), Verdict::Continue);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn plays_an_external_engine() {
+        let stand_in = crate::external::tests::stand_in("selfplay", Protocol::Ucci);
+        let path = &stand_in.path;
+        let spec = format!("ucci:{},Depth=3", path.display());
+        let config = Config::parse("stand-in", &spec).unwrap();
+        assert_eq!(
+            config.external,
+            Some((Protocol::Ucci, path.display().to_string()))
+        );
+        assert!(Config::parse("bad", "xboard:/bin/true").is_err());
+
+        // the stand-in always answers h2e2, which is not a move for Green
+        let mut red = Config::parse("us", "Depth=1").unwrap().opponent().unwrap();
+        let mut green = config.opponent().unwrap();
+        let game = play(
+            &mut *red,
+            &mut *green,
+            &Board::new(),
+            &Adjudication::default(),
+        )
+        .unwrap();
+        assert_eq!(game.termination, Termination::IllegalMove);
+        assert_eq!(game.record.outcome, Outcome::RedWin);
+        assert_eq!(game.record.moves.len(), 1);
+    }
+
+    #[test]
+    fn plays_a_short_match() {
+        let first = Config::parse("deep", "Depth=2").unwrap();
+        let second = Config::parse("shallow", "Depth=1,NullMove=0").unwrap();
+        assert!(Config::parse("bad", "Depth").is_err());
+        assert!(Config::parse("bad", "Depth=100").is_err());
+        let openings = [
+            Board::new(),
+            Board::from_fen("3k5/9/9/9/9/9/9/9/4R4/5K3 w").unwrap(),
+        ];
+        let adjudication = Adjudication {
+            max_plies: 20,
+            ..Adjudication::default()
+        };
+        let run = Match {
+            first: &first,
+            second: &second,
+            openings: &openings,
+            games: 4,
+            concurrency: 2,
+            adjudication,
+            sprt: Sprt::default(),
+        };
+        let mut games = vec![];
+        let results = run
+            .run(|i, game, _| games.push((i, game.record.clone())))
+            .unwrap();
+        assert_eq!(results.games(), 4);
+        let no_openings = Match {
+            openings: &[],
+            ..run
+        };
+        assert!(no_openings.run(|_, _, _| {}).is_err());
+        games.sort_by_key(|&(i, _)| i);
+        for (i, record) in games {
+            assert_eq!(record.header("Round"), Some((i + 1).to_string().as_str()));
+            let red = if i % 2 == 0 { "deep" } else { "shallow" };
+            assert_eq!(record.header("Red"), Some(red));
+            assert!(record.moves.len() <= 20);
+            // every game replays from its opening
+            let reparsed = Record::parse_all(&record.to_string()).unwrap();
+            assert!(reparsed[0].replay().is_ok());
+            if i >= 2 {
+                assert!(record.header("FEN").is_some());
+                assert_ne!(record.outcome, Outcome::Unfinished);
+            }
+        }
+    }
+}
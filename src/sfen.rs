@@ -0,0 +1,423 @@
+//! Training data for evaluation networks: the quiet positions of fast
+//! self-play games, each with the search's score and the game's result,
+//! packed into 32 bytes.
+//!
+//! A file is the magic `QISF` followed by entries of:
+//!
+//! - 12 bytes, little-endian: bits 0-89 mark the occupied squares, numbered
+//!   `row * 9 + col`, bit 90 is set if Green is to move, and bits 91-92
+//!   hold the result plus one;
+//! - 16 bytes: a nibble per occupied square in order, low nibble first, the
+//!   piece's index in `KINDS`, plus 8 for Green;
+//! - the score as an `i16` and the ply as a `u16`, little-endian.
+
+use crate::bitboard::{squares, KINDS};
+use crate::board::{Board, Move, Piece, Player};
+use crate::engine::Engine;
+use crate::pool;
+use crate::record::Outcome;
+use crate::rng::Rng;
+use crate::selfplay::{play, Adjudication, Game, Opponent};
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"QISF";
+pub const ENTRY_SIZE: usize = 32;
+
+/// A position to train on.
+#[derive(Clone)]
+pub struct Entry {
+    pub board: Board,
+    /// The search's score for the side to move.
+    pub score: i16,
+    /// 1 if the side to move went on to win, 0 for a draw, -1 for a loss.
+    pub result: i8,
+    /// Plies from the initial position.
+    pub ply: u16,
+}
+
+impl Entry {
+    pub fn to_bytes(&self) -> [u8; ENTRY_SIZE] {
+        let mut bytes = [0; ENTRY_SIZE];
+        let bits = self.board.bitboards();
+        let mut header = bits.occupied();
+        if self.board.turn == Player::Green {
+            header |= 1 << 90;
+        }
+        header |= ((self.result + 1) as u128 & 3) << 91;
+        bytes[..12].copy_from_slice(&header.to_le_bytes()[..12]);
+        for (i, square) in squares(bits.occupied()).take(32).enumerate() {
+            let piece = self.board.piece([square / 9, square % 9]);
+            let kind = KINDS
+                .iter()
+                .position(|&k| k == piece.piecetype())
+                .unwrap_or(0);
+            let colour = if piece.player() == Player::Green {
+                8
+            } else {
+                0
+            };
+            bytes[12 + i / 2] |= (kind as u8 + colour) << (4 * (i % 2));
+        }
+        bytes[28..30].copy_from_slice(&self.score.to_le_bytes());
+        bytes[30..32].copy_from_slice(&self.ply.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; ENTRY_SIZE]) -> Result<Entry> {
+        let mut header = [0; 16];
+        header[..12].copy_from_slice(&bytes[..12]);
+        let header = u128::from_le_bytes(header);
+        let occupied = header & ((1 << 90) - 1);
+        if occupied.count_ones() > 32 {
+            bail!("more than 32 pieces");
+        }
+        let mut board = Board::empty();
+        for (i, square) in squares(occupied).enumerate() {
+            let code = (bytes[12 + i / 2] >> (4 * (i % 2))) & 15;
+            let piecetype = *KINDS
+                .get(code as usize & 7)
+                .ok_or_else(|| anyhow!("bad piece code {}", code))?;
+            let player = if code >= 8 {
+                Player::Green
+            } else {
+                Player::Red
+            };
+            board.put([square / 9, square % 9], Piece::new(piecetype, player));
+        }
+        if header >> 90 & 1 == 1 {
+            board.turn = Player::Green;
+        }
+        let result = (header >> 91 & 3) as i8 - 1;
+        if result > 1 {
+            bail!("bad result");
+        }
+        Ok(Entry {
+            board,
+            score: i16::from_le_bytes([bytes[28], bytes[29]]),
+            result,
+            ply: u16::from_le_bytes([bytes[30], bytes[31]]),
+        })
+    }
+}
+
+/// The text form: the FEN, score, result and ply separated by semicolons.
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{};{};{};{}",
+            self.board.fen(),
+            self.score,
+            self.result,
+            self.ply
+        )
+    }
+}
+
+/// Writes entries after the magic.
+pub struct Writer<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut inner: W) -> Result<Writer<W>> {
+        inner.write_all(MAGIC)?;
+        Ok(Writer { inner })
+    }
+
+    pub fn write(&mut self, entry: &Entry) -> Result<()> {
+        self.inner.write_all(&entry.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+/// Reads entries, checking the magic first.
+pub struct Reader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut inner: R) -> Result<Reader<R>> {
+        let mut magic = [0; 4];
+        inner
+            .read_exact(&mut magic)
+            .map_err(|_| anyhow!("not a training data file"))?;
+        if &magic != MAGIC {
+            bail!("not a training data file");
+        }
+        Ok(Reader { inner })
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        let mut bytes = [0; ENTRY_SIZE];
+        let mut filled = 0;
+        while filled < ENTRY_SIZE {
+            match self.inner.read(&mut bytes[filled..]) {
+                Ok(0) if filled == 0 => return None,
+                Ok(0) => return Some(Err(anyhow!("truncated entry"))),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+        Some(Entry::from_bytes(&bytes))
+    }
+}
+
+/// How the games are played and which positions are kept.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Settings {
+    /// Random moves played before the engine takes over.
+    pub random_plies: usize,
+    /// Positions scored further than this from zero are left out.
+    pub max_score: i32,
+    pub adjudication: Adjudication,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            random_plies: 8,
+            max_score: 3000,
+            adjudication: Adjudication::default(),
+        }
+    }
+}
+
+/// Plays for one side, remembering each position it searched with the
+/// score and move it found.
+struct Recorder<'a> {
+    engine: &'a Engine,
+    positions: Vec<(Board, i32, Move)>,
+}
+
+impl Opponent for Recorder<'_> {
+    fn new_game(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn choose(&mut self, board: &Board) -> Result<Option<(Move, i32)>> {
+        let choice = self.engine.search(board);
+        if let Some((mv, score)) = choice {
+            self.positions.push((board.clone(), score, mv));
+        }
+        Ok(choice)
+    }
+}
+
+/// Plays `engine` against itself after random moves drawn with `seed` and
+/// returns the game with its quiet positions: those not in check whose best
+/// move is not a capture, scored within `max_score`.
+pub fn play_game(engine: &Engine, settings: &Settings, seed: u64) -> Result<(Game, Vec<Entry>)> {
+    let mut rng = Rng::new(seed);
+    let mut start = Board::new();
+    for _ in 0..settings.random_plies {
+        let moves = start.legal_moves();
+        if moves.is_empty() {
+            break;
+        }
+        let mv = moves[rng.below(moves.len())];
+        start.make_move(mv.from(), mv.to());
+        start.history.push(mv);
+    }
+    let opening = start.history.len();
+    engine.clear_hash();
+    let mut red = Recorder {
+        engine,
+        positions: vec![],
+    };
+    let mut green = Recorder {
+        engine,
+        positions: vec![],
+    };
+    let game = play(&mut red, &mut green, &start, &settings.adjudication)?;
+    let mut positions = red.positions;
+    positions.append(&mut green.positions);
+    positions.sort_by_key(|(board, _, _)| board.history.len());
+    let entries = positions
+        .into_iter()
+        .filter(|(board, score, mv)| {
+            !mv.is_capture() && score.abs() <= settings.max_score && !board.in_check(board.turn)
+        })
+        .map(|(board, score, _)| {
+            let result = match (game.record.outcome, board.turn) {
+                (Outcome::RedWin, Player::Red) | (Outcome::GreenWin, Player::Green) => 1,
+                (Outcome::RedWin, _) | (Outcome::GreenWin, _) => -1,
+                _ => 0,
+            };
+            let ply = (opening + board.history.len()) as u16;
+            Entry {
+                board,
+                score: score as i16,
+                result,
+                ply,
+            }
+        })
+        .collect();
+    Ok((game, entries))
+}
+
+/// Self-play games spread over threads.
+pub struct Generator<'a> {
+    /// Engine options, as with `set`.
+    pub options: &'a [(String, String)],
+    pub games: usize,
+    pub concurrency: usize,
+    /// Game `i` draws its opening from `seed` and `i`, so a run can be
+    /// repeated.
+    pub seed: u64,
+    pub settings: Settings,
+}
+
+impl Generator<'_> {
+    /// Plays the games on `concurrency` threads, each with its own engine,
+    /// handing each game and its positions to `report` in the order they
+    /// finish with its number, from 0. Stops when an engine fails.
+    pub fn run(&self, mut report: impl FnMut(usize, &Game, &[Entry])) -> Result<()> {
+        pool::run(
+            self.games,
+            self.concurrency,
+            || {
+                let mut engine = Engine::new();
+                for (name, value) in self.options {
+                    engine.set_option(name, value)?;
+                }
+                Ok(engine)
+            },
+            |engine, i| {
+                let seed = self.seed ^ (i as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+                play_game(engine, &self.settings, seed)
+            },
+            |i, (game, entries)| {
+                report(i, &game, &entries);
+                true
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packs_entries() {
+        let board = Board::from_fen("3k5/4a4/9/9/2r6/9/9/9/9/4K1C2 b").unwrap();
+        let entries = [
+            Entry {
+                board: Board::new(),
+                score: -12,
+                result: 0,
+                ply: 0,
+            },
+            Entry {
+                board: board.clone(),
+                score: 1234,
+                result: -1,
+                ply: 57,
+            },
+        ];
+        let mut writer = Writer::new(vec![]).unwrap();
+        for entry in &entries {
+            writer.write(entry).unwrap();
+        }
+        let bytes = writer.into_inner();
+        assert_eq!(bytes.len(), 4 + 2 * ENTRY_SIZE);
+        let read = Reader::new(&bytes[..])
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(read.len(), 2);
+        for (read, entry) in read.iter().zip(&entries) {
+            assert_eq!(read.board.fen(), entry.board.fen());
+            assert_eq!(read.board.bitboards(), entry.board.bitboards());
+            assert_eq!(
+                (read.score, read.result, read.ply),
+                (entry.score, entry.result, entry.ply)
+            );
+        }
+        assert_eq!(read[1].board.turn, Player::Green);
+        assert_eq!(
+            read[1].to_string(),
+            "3k5/4a4/9/9/2r6/9/9/9/9/4K1C2 b - - 0 1;1234;-1;57"
+        );
+
+        let truncated = Reader::new(&bytes[..bytes.len() - 1])
+            .unwrap()
+            .last()
+            .unwrap();
+        assert!(truncated.is_err());
+        assert!(Reader::new(&b"QIBK"[..]).is_err());
+    }
+
+    #[test]
+    fn generates_quiet_positions() {
+        let options = [("Depth".to_string(), "1".to_string())];
+        let settings = Settings {
+            adjudication: Adjudication {
+                max_plies: 40,
+                ..Adjudication::default()
+            },
+            ..Settings::default()
+        };
+        let generator = Generator {
+            options: &options,
+            games: 2,
+            concurrency: 2,
+            seed: 7,
+            settings,
+        };
+        let mut games = vec![];
+        generator
+            .run(|i, game, entries| games.push((i, game.record.clone(), entries.to_vec())))
+            .unwrap();
+        games.sort_by_key(|(i, _, _)| *i);
+        assert_eq!(games.len(), 2);
+        // different openings from the same seed
+        assert_ne!(games[0].1.header("FEN"), games[1].1.header("FEN"));
+        for (_, record, entries) in &games {
+            assert!(!entries.is_empty());
+            record.replay().unwrap();
+            let red_result = match record.outcome {
+                Outcome::RedWin => 1,
+                Outcome::GreenWin => -1,
+                _ => 0,
+            };
+            for entry in entries {
+                assert!(entry.ply >= 8);
+                assert!(!entry.board.in_check(entry.board.turn));
+                assert!(i32::from(entry.score).abs() <= 3000);
+                let expected = match entry.board.turn {
+                    Player::Red => red_result,
+                    _ => -red_result,
+                };
+                assert_eq!(entry.result, expected);
+            }
+        }
+        // the same seed plays the same game
+        let engine = {
+            let mut engine = Engine::new();
+            engine.set_option("Depth", "1").unwrap();
+            engine
+        };
+        let (game, entries) = play_game(&engine, &settings, 7 ^ 0x9e37_79b9_7f4a_7c15).unwrap();
+        assert_eq!(game.record, games[0].1);
+        let summary = |entries: &[Entry]| {
+            entries
+                .iter()
+                .map(|e| (e.board.fen(), e.score, e.result, e.ply))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(summary(&entries), summary(&games[0].2));
+    }
+}
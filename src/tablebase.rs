@@ -0,0 +1,540 @@
+use crate::board::{Board, Piece, PieceType, Player};
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"QITB";
+pub const EXTENSION: &str = "qtb";
+
+// One byte per position, from the side to move's point of view: 0 for a
+// draw, n for a win in n plies, 128 + n for a loss in n plies, or a marker.
+const DRAW: u8 = 0;
+const UNKNOWN: u8 = 254;
+const ILLEGAL: u8 = 255;
+const MAX_PLIES: u8 = 125;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Wdl {
+    Win,
+    Draw,
+    Loss,
+}
+
+/// A tablebase result for the side to move: the outcome with best play and,
+/// unless drawn, the number of plies until mate.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Probe {
+    pub wdl: Wdl,
+    pub plies: u8,
+}
+
+/// Mates in `plies`, from 1 to 126.
+fn encode_win(plies: u8) -> u8 {
+    plies
+}
+
+/// Is mated in `plies`, from 0 to 125.
+fn encode_loss(plies: u8) -> u8 {
+    128 + plies
+}
+
+fn decode(v: u8) -> Option<Probe> {
+    let (wdl, plies) = match v {
+        DRAW => (Wdl::Draw, 0),
+        1..=126 => (Wdl::Win, v),
+        128..=253 => (Wdl::Loss, v - 128),
+        _ => return None,
+    };
+    Some(Probe { wdl, plies })
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.wdl {
+            Wdl::Win => write!(f, "win, mate in {} plies", self.plies),
+            Wdl::Draw => write!(f, "draw"),
+            Wdl::Loss => write!(f, "loss, mated in {} plies", self.plies),
+        }
+    }
+}
+
+fn order(kind: PieceType) -> u8 {
+    match kind {
+        PieceType::None => 0,
+        PieceType::Shuai => 1,
+        PieceType::Shi => 2,
+        PieceType::Xiang => 3,
+        PieceType::Ma => 4,
+        PieceType::Ju => 5,
+        PieceType::Pao => 6,
+        PieceType::Bing => 7,
+    }
+}
+
+/// The pieces besides the two generals, written in WXF letters with Red's
+/// side first, e.g. `KR-KAB` for Ju against Shi and Xiang.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Material {
+    pub red: Vec<PieceType>,
+    pub green: Vec<PieceType>,
+}
+
+impl Material {
+    fn new(mut red: Vec<PieceType>, mut green: Vec<PieceType>) -> Material {
+        red.sort_by_key(|&k| order(k));
+        green.sort_by_key(|&k| order(k));
+        Material { red, green }
+    }
+
+    pub fn parse(s: &str) -> Result<Material> {
+        let side = |part: &str| -> Result<Vec<PieceType>> {
+            let rest = part
+                .strip_prefix(|c| c == 'K' || c == 'k')
+                .ok_or_else(|| anyhow!("{}: each side starts with K", s))?;
+            rest.chars()
+                .map(|c| {
+                    PieceType::from_letter(c)
+                        .filter(|&k| k != PieceType::Shuai)
+                        .ok_or_else(|| anyhow!("{}: unknown piece {}", s, c))
+                })
+                .collect()
+        };
+        let (red, green) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("{}: expected e.g. KR-KAB", s))?;
+        Ok(Material::new(side(red)?, side(green)?))
+    }
+
+    /// The material on `board`, or `None` if a general is missing.
+    pub fn of(board: &Board) -> Option<Material> {
+        let (mut red, mut green) = (vec![], vec![]);
+        let mut generals = 0;
+        for row in board.rows().iter() {
+            for piece in row.iter() {
+                match (piece.piecetype(), piece.player()) {
+                    (PieceType::None, _) => {}
+                    (PieceType::Shuai, _) => generals += 1,
+                    (kind, Player::Red) => red.push(kind),
+                    (kind, _) => green.push(kind),
+                }
+            }
+        }
+        if generals == 2 {
+            Some(Material::new(red, green))
+        } else {
+            None
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        2 + self.red.len() + self.green.len()
+    }
+
+    pub fn flipped(&self) -> Material {
+        Material::new(self.green.clone(), self.red.clone())
+    }
+
+    /// Every material reachable by one capture.
+    fn captures(&self) -> Vec<Material> {
+        let mut subs = vec![];
+        for i in 0..self.red.len() {
+            let mut red = self.red.clone();
+            red.remove(i);
+            subs.push(Material::new(red, self.green.clone()));
+        }
+        for i in 0..self.green.len() {
+            let mut green = self.green.clone();
+            green.remove(i);
+            subs.push(Material::new(self.red.clone(), green));
+        }
+        subs.dedup();
+        subs
+    }
+}
+
+impl fmt::Display for Material {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let side = |pieces: &[PieceType]| pieces.iter().map(|k| k.letter()).collect::<String>();
+        write!(f, "K{}-K{}", side(&self.red), side(&self.green))
+    }
+}
+
+/// Squares a piece can ever stand on. Keeping the Shi, Xiang, Shuai and
+/// Bing to their own squares makes the tables much smaller.
+fn domain(kind: PieceType, player: Player) -> Vec<usize> {
+    let squares = (0..90).map(|sq| (sq / 9, sq % 9, sq));
+    let red = player == Player::Red;
+    let own = |row: usize| if red { row } else { 9 - row };
+    squares
+        .filter(|&(row, col, _)| match kind {
+            PieceType::Shuai => own(row) >= 7 && (3..=5).contains(&col),
+            PieceType::Shi => own(row) >= 7 && (3..=5).contains(&col) && (own(row) + col) % 2 == 0,
+            PieceType::Xiang => {
+                [(9, 2), (9, 6), (7, 0), (7, 4), (7, 8), (5, 2), (5, 6)].contains(&(own(row), col))
+            }
+            PieceType::Bing => own(row) <= 4 || (own(row) <= 6 && col % 2 == 0),
+            _ => true,
+        })
+        .map(|(_, _, sq)| sq)
+        .collect()
+}
+
+/// One material combination: a value byte for every placement of the
+/// pieces and side to move.
+pub struct Table {
+    material: Material,
+    slots: Vec<(PieceType, Player)>,
+    domains: Vec<Vec<usize>>,
+    /// `lookup[slot][square]` is the square's position in the slot's domain.
+    lookup: Vec<[u8; 90]>,
+    values: Vec<u8>,
+}
+
+impl Table {
+    fn layout(material: Material) -> Table {
+        let mut slots = vec![
+            (PieceType::Shuai, Player::Red),
+            (PieceType::Shuai, Player::Green),
+        ];
+        slots.extend(material.red.iter().map(|&k| (k, Player::Red)));
+        slots.extend(material.green.iter().map(|&k| (k, Player::Green)));
+        let domains = slots
+            .iter()
+            .map(|&(kind, player)| domain(kind, player))
+            .collect::<Vec<_>>();
+        let lookup = domains
+            .iter()
+            .map(|domain| {
+                let mut lookup = [u8::MAX; 90];
+                for (i, &sq) in domain.iter().enumerate() {
+                    lookup[sq] = i as u8;
+                }
+                lookup
+            })
+            .collect();
+        let size = domains.iter().map(Vec::len).product::<usize>() * 2;
+        Table {
+            material,
+            slots,
+            domains,
+            lookup,
+            values: vec![UNKNOWN; size],
+        }
+    }
+
+    pub fn material(&self) -> &Material {
+        &self.material
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The position at `index`, or `None` if two pieces would share a
+    /// square or identical pieces are not in square order (so each position
+    /// has exactly one index).
+    fn board(&self, mut index: usize) -> Option<Board> {
+        let mut board = Board::empty();
+        board.turn = if index.is_multiple_of(2) {
+            Player::Red
+        } else {
+            Player::Green
+        };
+        index /= 2;
+        let mut squares = vec![0; self.slots.len()];
+        for slot in (0..self.slots.len()).rev() {
+            let len = self.domains[slot].len();
+            squares[slot] = self.domains[slot][index % len];
+            index /= len;
+        }
+        for (slot, &(kind, player)) in self.slots.iter().enumerate() {
+            let sq = squares[slot];
+            if slot > 0 && self.slots[slot - 1] == (kind, player) && squares[slot - 1] >= sq {
+                return None;
+            }
+            if board.piece([sq / 9, sq % 9]).piecetype() != PieceType::None {
+                return None;
+            }
+            board.put([sq / 9, sq % 9], Piece::new(kind, player));
+        }
+        Some(board)
+    }
+
+    /// The index of `board`, which must have this table's material.
+    fn index(&self, board: &Board) -> Option<usize> {
+        let mut squares: Vec<Vec<usize>> = vec![vec![]; self.slots.len()];
+        for sq in 0..90 {
+            let piece = board.piece([sq / 9, sq % 9]);
+            if piece.piecetype() == PieceType::None {
+                continue;
+            }
+            let slot = self
+                .slots
+                .iter()
+                .position(|&s| s == (piece.piecetype(), piece.player()))?;
+            squares[slot].push(sq);
+        }
+        let mut index = 0;
+        let mut taken = vec![0; self.slots.len()];
+        for (slot, &kind) in self.slots.iter().enumerate() {
+            let first = self.slots.iter().position(|&s| s == kind)?;
+            let sq = *squares[first].get(taken[first])?;
+            taken[first] += 1;
+            let i = self.lookup[slot][sq];
+            if i == u8::MAX {
+                return None;
+            }
+            index = index * self.domains[slot].len() + i as usize;
+        }
+        let side = match board.turn {
+            Player::Green => 1,
+            _ => 0,
+        };
+        Some(index * 2 + side)
+    }
+
+    fn probe(&self, board: &Board) -> Option<u8> {
+        self.index(board).map(|i| self.values[i])
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let name = self.material.to_string();
+        let mut bytes = Vec::with_capacity(9 + name.len() + self.values.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(name.len() as u8);
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(&(self.values.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.values);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Table> {
+        if bytes.len() < 5 || &bytes[..4] != MAGIC {
+            bail!("not a tablebase file");
+        }
+        let len = bytes[4] as usize;
+        let name = bytes
+            .get(5..5 + len)
+            .and_then(|b| std::str::from_utf8(b).ok())
+            .ok_or_else(|| anyhow!("truncated tablebase file"))?;
+        let mut table = Table::layout(Material::parse(name)?);
+        let count = bytes
+            .get(5 + len..9 + len)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+            .ok_or_else(|| anyhow!("truncated tablebase file"))?;
+        let values = bytes.get(9 + len..).unwrap_or(&[]);
+        if count != table.values.len() || values.len() != count {
+            bail!("{}: wrong number of positions", name);
+        }
+        table.values.copy_from_slice(values);
+        Ok(table)
+    }
+}
+
+/// A set of endgame tables, probed for either colour.
+#[derive(Default)]
+pub struct Tablebases {
+    tables: HashMap<Material, Table>,
+    max_pieces: usize,
+}
+
+impl Tablebases {
+    pub fn new() -> Tablebases {
+        Tablebases::default()
+    }
+
+    /// Loads every `.qtb` file in `dir`.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Tablebases> {
+        let mut tbs = Tablebases::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some(EXTENSION) {
+                tbs.insert(Table::from_bytes(&fs::read(&path)?)?);
+            }
+        }
+        Ok(tbs)
+    }
+
+    pub fn insert(&mut self, table: Table) {
+        self.max_pieces = self.max_pieces.max(table.material.count());
+        self.tables.insert(table.material.clone(), table);
+    }
+
+    pub fn tables(&self) -> impl Iterator<Item = &Table> {
+        self.tables.values()
+    }
+
+    pub fn contains(&self, material: &Material) -> bool {
+        self.tables.contains_key(material) || self.tables.contains_key(&material.flipped())
+    }
+
+    /// The largest number of pieces, generals included, in any table.
+    pub fn max_pieces(&self) -> usize {
+        self.max_pieces
+    }
+
+    fn probe_byte(&self, board: &Board) -> Option<u8> {
+        let material = Material::of(board)?;
+        if let Some(table) = self.tables.get(&material) {
+            return table.probe(board);
+        }
+        let table = self.tables.get(&material.flipped())?;
+        table.probe(&board.flipped())
+    }
+
+    /// Looks `board` up, turning it round if only the table with the colours
+    /// swapped is loaded.
+    pub fn probe(&self, board: &Board) -> Option<Probe> {
+        self.probe_byte(board).and_then(decode)
+    }
+
+    /// Generates the table for `material` by retrograde analysis, first
+    /// generating any table a capture could lead into. Starting from the
+    /// positions where the side to move has no legal move, pass `n` marks
+    /// every position won or lost in exactly `n` plies, until nothing
+    /// changes. Positions never resolved are draws.
+    pub fn generate(&mut self, material: &Material) {
+        if self.contains(material) {
+            return;
+        }
+        for sub in material.captures() {
+            self.generate(&sub);
+        }
+        let mut table = Table::layout(material.clone());
+        let n = table.values.len();
+
+        // Successors of every position: indices into this table, or with
+        // the top bit set, the final value of a capture into another table.
+        const KNOWN: u32 = 1 << 31;
+        let mut offsets = Vec::with_capacity(n + 1);
+        let mut successors = vec![];
+        let mut furthest = 0;
+        for index in 0..n {
+            offsets.push(successors.len() as u32);
+            let mut board = match table.board(index) {
+                Some(board) if !board.in_check(board.turn.opponent()) => board,
+                _ => {
+                    table.values[index] = ILLEGAL;
+                    continue;
+                }
+            };
+            let moves = board.legal_moves();
+            if moves.is_empty() {
+                table.values[index] = encode_loss(0);
+                continue;
+            }
+            for mv in moves {
+                let captured = board.make_move(mv.from(), mv.to());
+                if captured.piecetype() == PieceType::None {
+                    successors.push(table.index(&board).unwrap() as u32);
+                } else {
+                    let v = self.probe_byte(&board).unwrap_or(DRAW);
+                    if let Some(p) = decode(v) {
+                        furthest = furthest.max(p.plies);
+                    }
+                    successors.push(KNOWN | v as u32);
+                }
+                board.unmake_move(mv.from(), mv.to(), captured);
+            }
+        }
+        offsets.push(successors.len() as u32);
+
+        for plies in 1..=MAX_PLIES {
+            let mut changed = false;
+            for index in 0..n {
+                if table.values[index] != UNKNOWN {
+                    continue;
+                }
+                let mut quickest_loss = None;
+                let mut slowest_win = Some(0);
+                for &s in &successors[offsets[index] as usize..offsets[index + 1] as usize] {
+                    let v = if s & KNOWN != 0 {
+                        s as u8
+                    } else {
+                        table.values[s as usize]
+                    };
+                    match decode(v) {
+                        Some(Probe {
+                            wdl: Wdl::Loss,
+                            plies: d,
+                        }) => {
+                            quickest_loss = Some(quickest_loss.map_or(d, |q: u8| q.min(d)));
+                            slowest_win = None;
+                        }
+                        Some(Probe {
+                            wdl: Wdl::Win,
+                            plies: d,
+                        }) => slowest_win = slowest_win.map(|w: u8| w.max(d)),
+                        _ => slowest_win = None,
+                    }
+                }
+                if let Some(d) = quickest_loss.filter(|&d| d < plies) {
+                    table.values[index] = encode_win(d + 1);
+                    changed = true;
+                } else if let Some(d) = slowest_win.filter(|&d| d < plies) {
+                    table.values[index] = encode_loss(d + 1);
+                    changed = true;
+                }
+            }
+            if !changed && plies > furthest + 1 {
+                break;
+            }
+        }
+        for v in table.values.iter_mut() {
+            if *v == UNKNOWN {
+                *v = DRAW;
+            }
+        }
+        self.insert(table);
+    }
+
+    /// Writes every table to `dir` as `<material>.qtb`.
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        fs::create_dir_all(&dir)?;
+        for table in self.tables.values() {
+            let path = dir
+                .as_ref()
+                .join(format!("{}.{}", table.material, EXTENSION));
+            fs::write(path, table.to_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ju_against_bare_general() {
+        let mut tbs = Tablebases::new();
+        tbs.generate(&Material::parse("KR-K").unwrap());
+
+        let mut board = Board::empty();
+        board.put([9, 4], Piece::new(PieceType::Shuai, Player::Red));
+        board.put([0, 3], Piece::new(PieceType::Shuai, Player::Green));
+        board.put([5, 8], Piece::new(PieceType::Ju, Player::Red));
+        let probe = tbs.probe(&board).unwrap();
+        assert_eq!(probe.wdl, Wdl::Win);
+        assert_eq!(probe.plies % 2, 1);
+        assert_eq!(tbs.probe(&board.flipped()), Some(probe));
+
+        // some move leads to a loss for Green one ply sooner, and none faster
+        let mut quickest = u8::MAX;
+        for mv in board.legal_moves() {
+            let mut next = board.clone();
+            next.make_move(mv.from(), mv.to());
+            let reply = tbs.probe(&next).unwrap();
+            if reply.wdl == Wdl::Loss {
+                quickest = quickest.min(reply.plies);
+            }
+        }
+        assert_eq!(quickest + 1, probe.plies);
+    }
+}
@@ -0,0 +1,156 @@
+use crate::board::Move;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Bound {
+    Exact,
+    Lower,
+    Upper,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub mv: Option<Move>,
+    pub score: i32,
+    pub depth: u32,
+    pub bound: Bound,
+}
+
+impl Entry {
+    // data layout: move (16 bits, 0xffff for none) | score (16) | depth (8) | bound (2)
+    fn pack(&self) -> u64 {
+        let mv = match self.mv {
+            Some(mv) => (mv.from_square() * 90 + mv.to_square()) as u64,
+            None => 0xffff,
+        };
+        let bound = match self.bound {
+            Bound::Exact => 0,
+            Bound::Lower => 1,
+            Bound::Upper => 2,
+        };
+        mv | (self.score as i16 as u16 as u64) << 16
+            | (self.depth.min(255) as u64) << 32
+            | bound << 40
+    }
+
+    fn unpack(data: u64) -> Entry {
+        let mv = (data & 0xffff) as usize;
+        Entry {
+            mv: if mv == 0xffff {
+                None
+            } else {
+                Some(Move::from_squares(mv / 90, mv % 90))
+            },
+            score: (data >> 16) as u16 as i16 as i32,
+            depth: ((data >> 32) & 0xff) as u32,
+            bound: match (data >> 40) & 3 {
+                0 => Bound::Exact,
+                1 => Bound::Lower,
+                _ => Bound::Upper,
+            },
+        }
+    }
+}
+
+/// A transposition table shared between search threads without locks.
+///
+/// Each slot holds the key XORed with the data next to the data itself, so a
+/// slot torn by two threads writing at once just fails to match on probe.
+pub struct TranspositionTable {
+    slots: Vec<[AtomicU64; 2]>,
+}
+
+impl TranspositionTable {
+    pub fn new(megabytes: usize) -> TranspositionTable {
+        let len = (megabytes.max(1) << 20) / 16;
+        TranspositionTable {
+            slots: (0..len)
+                .map(|_| [AtomicU64::new(0), AtomicU64::new(0)])
+                .collect(),
+        }
+    }
+
+    pub fn clear(&self) {
+        for slot in &self.slots {
+            slot[0].store(0, Ordering::Relaxed);
+            slot[1].store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn slot(&self, hash: u64) -> &[AtomicU64; 2] {
+        &self.slots[(hash % self.slots.len() as u64) as usize]
+    }
+
+    pub fn probe(&self, hash: u64) -> Option<Entry> {
+        let slot = self.slot(hash);
+        let check = slot[0].load(Ordering::Relaxed);
+        let data = slot[1].load(Ordering::Relaxed);
+        if data != 0 && check ^ data == hash {
+            Some(Entry::unpack(data))
+        } else {
+            None
+        }
+    }
+
+    /// Stores `entry`, always replacing what was there.
+    pub fn store(&self, hash: u64, entry: Entry) {
+        let slot = self.slot(hash);
+        let data = entry.pack();
+        slot[0].store(hash ^ data, Ordering::Relaxed);
+        slot[1].store(data, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(score: i32, depth: u32) -> Entry {
+        Entry {
+            mv: Some(Move::new([7, 7], [7, 4])),
+            score,
+            depth,
+            bound: Bound::Lower,
+        }
+    }
+
+    #[test]
+    fn stores_and_probes() {
+        let tt = TranspositionTable::new(1);
+        let hash = 0x1234_5678_9abc_def0;
+        assert_eq!(tt.probe(hash), None);
+        tt.store(hash, entry(-250, 7));
+        assert_eq!(tt.probe(hash), Some(entry(-250, 7)));
+        let none = Entry {
+            mv: None,
+            bound: Bound::Upper,
+            ..entry(0, 300)
+        };
+        tt.store(hash, none);
+        // the depth saturates at 255
+        assert_eq!(tt.probe(hash), Some(Entry { depth: 255, ..none }));
+        tt.clear();
+        assert_eq!(tt.probe(hash), None);
+    }
+
+    #[test]
+    fn replaces_and_rejects_torn_slots() {
+        let tt = TranspositionTable::new(1);
+        let len = tt.slots.len() as u64;
+        let (first, second) = (5, 5 + len);
+        tt.store(first, entry(10, 3));
+        // a different position in the same slot replaces it
+        tt.store(second, entry(20, 1));
+        assert_eq!(tt.probe(first), None);
+        assert_eq!(tt.probe(second), Some(entry(20, 1)));
+
+        // half of each write, as when two threads race on the slot
+        let slot = tt.slot(first);
+        let data = entry(10, 3).pack();
+        slot[1].store(data, Ordering::Relaxed);
+        assert_eq!(tt.probe(first), None);
+        assert_eq!(tt.probe(second), None);
+        slot[0].store(first ^ data, Ordering::Relaxed);
+        assert_eq!(tt.probe(first), Some(entry(10, 3)));
+    }
+}
@@ -0,0 +1,305 @@
+//! Texel tuning: fits the evaluation's weights to the results of saved games
+//! by gradient descent on the squared difference between each position's
+//! result and its quiescence-resolved score mapped through a sigmoid.
+
+use crate::bitboard::{squares, KINDS};
+use crate::board::{Board, Move, Player};
+use crate::engine::value;
+use crate::eval::Params;
+use crate::record::{Outcome, Record};
+use crate::see::see;
+use anyhow::Result;
+
+/// Plies of captures followed to resolve a position.
+const MAX_QPLY: u32 = 8;
+/// Weights: the seven piece values, then for each piece a table of ten rows
+/// by five files, the files mirrored left to right.
+const WEIGHTS: usize = 7 + 7 * 50;
+
+/// The weight index of the table entry for a piece of kind `k` at `index`
+/// in its `Params` table.
+fn pst_weight(k: usize, index: usize) -> usize {
+    let (row, col) = (index / 9, index % 9);
+    7 + k * 50 + row * 5 + col.min(8 - col)
+}
+
+/// A position reduced to what the evaluation sees, with its game's result.
+#[derive(Clone, Debug)]
+pub struct Sample {
+    /// Weight indices with +1 for each Red piece and -1 for each Green one.
+    features: Vec<(u16, i8)>,
+    /// 1 if Red won, 0.5 for a draw and 0 if Green won.
+    result: f64,
+}
+
+impl Sample {
+    /// The position on `board`, resolved with captures from the side to
+    /// move, or `None` if it is in check.
+    pub fn new(board: &Board, params: &Params, result: f64) -> Option<Sample> {
+        if board.in_check(board.turn) {
+            return None;
+        }
+        let leaf = resolve(board, params);
+        let bits = leaf.bitboards();
+        let mut features = vec![];
+        for &(player, sign) in &[(Player::Red, 1), (Player::Green, -1)] {
+            for (k, &piecetype) in KINDS.iter().enumerate() {
+                for square in squares(bits.pieces(player, piecetype)) {
+                    features.push((k as u16, sign));
+                    let weight = pst_weight(k, Params::square(player, square));
+                    features.push((weight as u16, sign));
+                }
+            }
+        }
+        Some(Sample { features, result })
+    }
+
+    /// The score for Red.
+    fn score(&self, weights: &[f64]) -> f64 {
+        self.features
+            .iter()
+            .map(|&(i, sign)| f64::from(sign) * weights[i as usize])
+            .sum()
+    }
+}
+
+/// Every position from `skip` plies on in the finished games in `records`.
+pub fn samples(records: &[Record], params: &Params, skip: usize) -> Result<Vec<Sample>> {
+    let mut samples = vec![];
+    for record in records {
+        let result = match record.outcome {
+            Outcome::RedWin => 1.0,
+            Outcome::Draw => 0.5,
+            Outcome::GreenWin => 0.0,
+            Outcome::Unfinished => continue,
+        };
+        let mut ply = 0;
+        record.replay_with(|board, _| {
+            if ply >= skip {
+                samples.extend(Sample::new(board, params, result));
+            }
+            ply += 1;
+        })?;
+    }
+    Ok(samples)
+}
+
+/// The position at the end of the principal variation of a captures-only
+/// search, so that no sample leaves a piece hanging.
+fn resolve(board: &Board, params: &Params) -> Board {
+    let mut board = board.clone();
+    let mut pv = vec![];
+    quiesce(&mut board, params, -i32::MAX, i32::MAX, 0, &mut pv);
+    for mv in pv {
+        board.make_move(mv.from(), mv.to());
+    }
+    board
+}
+
+fn quiesce(
+    board: &mut Board,
+    params: &Params,
+    mut alpha: i32,
+    beta: i32,
+    qply: u32,
+    pv: &mut Vec<Move>,
+) -> i32 {
+    let stand_pat = params.evaluate(board);
+    if stand_pat >= beta || qply == MAX_QPLY {
+        return stand_pat;
+    }
+    alpha = alpha.max(stand_pat);
+    let turn = board.turn;
+    let mut moves = board.legal_captures(false);
+    moves.sort_by_key(|&mv| -value(mv.captured(), mv.to(), turn.opponent()));
+    for mv in moves {
+        if see(board, mv) < 0 {
+            continue;
+        }
+        let mut line = vec![];
+        let captured = board.make_move(mv.from(), mv.to());
+        let score = -quiesce(board, params, -beta, -alpha, qply + 1, &mut line);
+        board.unmake_move(mv.from(), mv.to(), captured);
+        if score > alpha {
+            alpha = score;
+            pv.clear();
+            pv.push(mv);
+            pv.extend(line);
+            if score >= beta {
+                break;
+            }
+        }
+    }
+    alpha
+}
+
+/// Gradient descent with Adam over the weights of a `Params`.
+pub struct Tuner {
+    samples: Vec<Sample>,
+    weights: Vec<f64>,
+    /// Scales scores into the sigmoid: `1 / (1 + 10^(-k * score / 400))`.
+    pub k: f64,
+    /// How far each step may move a weight, roughly.
+    pub rate: f64,
+    moments: Vec<(f64, f64)>,
+    steps: i32,
+}
+
+impl Tuner {
+    /// Starts from `params`, with `k` fitted to the samples.
+    pub fn new(samples: Vec<Sample>, params: &Params) -> Tuner {
+        let mut weights = vec![0.0; WEIGHTS];
+        for (k, &value) in params.values.iter().enumerate() {
+            weights[k] = f64::from(value);
+        }
+        for (k, table) in params.pst.iter().enumerate() {
+            for (index, &bonus) in table.iter().enumerate() {
+                // each mirrored entry is met twice, the centre file once
+                let share = if index % 9 == 4 { 1.0 } else { 0.5 };
+                weights[pst_weight(k, index)] += share * f64::from(bonus);
+            }
+        }
+        let mut tuner = Tuner {
+            samples,
+            weights,
+            k: 1.0,
+            rate: 1.0,
+            moments: vec![(0.0, 0.0); WEIGHTS],
+            steps: 0,
+        };
+        tuner.fit_k();
+        tuner
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    fn sigmoid(&self, score: f64) -> f64 {
+        1.0 / (1.0 + 10f64.powf(-self.k * score / 400.0))
+    }
+
+    /// The mean squared difference between results and predictions.
+    pub fn error(&self) -> f64 {
+        let total: f64 = self
+            .samples
+            .iter()
+            .map(|sample| (sample.result - self.sigmoid(sample.score(&self.weights))).powi(2))
+            .sum();
+        total / self.samples.len().max(1) as f64
+    }
+
+    /// Picks the `k` between 0.01 and 10 that minimises the error, by
+    /// ternary search.
+    fn fit_k(&mut self) {
+        let (mut low, mut high) = (0.01, 10.0);
+        for _ in 0..60 {
+            let third = (high - low) / 3.0;
+            self.k = low + third;
+            let first = self.error();
+            self.k = high - third;
+            if first < self.error() {
+                high -= third;
+            } else {
+                low += third;
+            }
+        }
+        self.k = (low + high) / 2.0;
+    }
+
+    /// One step over every sample.
+    pub fn step(&mut self) {
+        let mut gradient = vec![0.0; WEIGHTS];
+        let scale = self.k * 10f64.ln() / 400.0;
+        for sample in &self.samples {
+            let p = self.sigmoid(sample.score(&self.weights));
+            let slope = 2.0 * (p - sample.result) * p * (1.0 - p) * scale;
+            for &(i, sign) in &sample.features {
+                gradient[i as usize] += slope * f64::from(sign);
+            }
+        }
+        let n = self.samples.len().max(1) as f64;
+        let (beta1, beta2) = (0.9, 0.999);
+        self.steps += 1;
+        for ((weight, (m, v)), g) in self
+            .weights
+            .iter_mut()
+            .zip(self.moments.iter_mut())
+            .zip(gradient)
+        {
+            let g = g / n;
+            *m = beta1 * *m + (1.0 - beta1) * g;
+            *v = beta2 * *v + (1.0 - beta2) * g * g;
+            let m_hat = *m / (1.0 - beta1.powi(self.steps));
+            let v_hat = *v / (1.0 - beta2.powi(self.steps));
+            *weight -= self.rate * m_hat / (v_hat.sqrt() + 1e-12);
+        }
+    }
+
+    /// The weights, rounded.
+    pub fn params(&self) -> Params {
+        let mut params = Params {
+            values: [0; 7],
+            pst: [[0; 90]; 7],
+        };
+        for (k, value) in params.values.iter_mut().enumerate() {
+            *value = self.weights[k].round() as i32;
+        }
+        for (k, table) in params.pst.iter_mut().enumerate() {
+            for (index, bonus) in table.iter_mut().enumerate() {
+                *bonus = self.weights[pst_weight(k, index)].round() as i32;
+            }
+        }
+        params
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_captures() {
+        let params = Params::default();
+        // Red's Ju takes the hanging one on a4
+        let board = Board::from_fen("3k5/9/9/9/9/r8/9/9/9/R4K3 w").unwrap();
+        let sample = Sample::new(&board, &params, 1.0).unwrap();
+        let tuner = Tuner::new(vec![sample], &params);
+        assert_eq!(tuner.samples[0].score(&tuner.weights), 600.0);
+        assert_eq!(tuner.params(), params);
+        // Red is in check
+        let board = Board::from_fen("3k5/9/9/9/9/9/9/9/9/3rK4 w").unwrap();
+        assert!(Sample::new(&board, &params, 1.0).is_none());
+    }
+
+    #[test]
+    fn fits_the_results() {
+        let params = Params::default();
+        let mut samples = vec![];
+        for &(fen, result) in &[
+            ("3k5/9/9/9/9/P8/9/9/9/5K3 w", 1.0),
+            ("3k5/9/9/9/9/2P6/9/9/9/5K3 w", 1.0),
+            ("3k5/9/9/9/9/6P2/9/9/9/5K3 b", 1.0),
+            ("3k5/9/9/9/p8/9/9/9/9/5K3 w", 0.0),
+            ("3k5/9/9/9/2p6/9/9/9/9/5K3 b", 0.0),
+            ("3k5/9/9/9/9/9/9/9/9/5K3 w", 0.5),
+            ("3k5/9/9/9/9/9/9/9/9/4K4 b", 0.5),
+        ] {
+            let board = Board::from_fen(fen).unwrap();
+            samples.push(Sample::new(&board, &params, result).unwrap());
+        }
+        let mut tuner = Tuner::new(samples, &params);
+        let before = tuner.error();
+        for _ in 0..200 {
+            tuner.step();
+        }
+        assert!(tuner.error() < before);
+        let tuned = tuner.params();
+        // a Bing on its own side wins these games
+        assert!(tuned.values[6] + tuned.pst[6][45] > 30);
+    }
+}
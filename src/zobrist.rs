@@ -0,0 +1,55 @@
+use crate::board::{Piece, PieceType, Player};
+
+/// Random keys for every (player, piece type, square), plus one for Green to
+/// move. They are generated at compile time from a fixed seed so that hashes
+/// stored on disk (opening books, tablebases) stay valid across builds.
+const KEYS: [[[u64; 90]; 7]; 2] = keys();
+pub const GREEN_TO_MOVE: u64 = splitmix(0x5158_4951_4e47_0001);
+
+const fn splitmix(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+const fn keys() -> [[[u64; 90]; 7]; 2] {
+    let mut keys = [[[0; 90]; 7]; 2];
+    let mut state = 0x7169_7869_616e_6771;
+    let mut side = 0;
+    while side < 2 {
+        let mut kind = 0;
+        while kind < 7 {
+            let mut square = 0;
+            while square < 90 {
+                state = splitmix(state);
+                keys[side][kind][square] = state;
+                square += 1;
+            }
+            kind += 1;
+        }
+        side += 1;
+    }
+    keys
+}
+
+/// The key for `piece` standing on `square` (`row * 9 + col`), or 0 for an
+/// empty square.
+pub fn key(piece: Piece, square: usize) -> u64 {
+    let side = match piece.player() {
+        Player::Red => 0,
+        Player::Green => 1,
+        _ => return 0,
+    };
+    let kind = match piece.piecetype() {
+        PieceType::None => return 0,
+        PieceType::Shuai => 0,
+        PieceType::Shi => 1,
+        PieceType::Xiang => 2,
+        PieceType::Ma => 3,
+        PieceType::Ju => 4,
+        PieceType::Pao => 5,
+        PieceType::Bing => 6,
+    };
+    KEYS[side][kind][square]
+}